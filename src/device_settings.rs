@@ -0,0 +1,308 @@
+//! Tuning of a device which outlives the update workers and restarts: colors, render mode,
+//! mounting and the like. Settings left out keep what the ccc and the environment configure.
+
+use crate::elli::{
+    Calibration, ElliConfig, FitMode, PausedBehavior, RenderMode, ResizeFilter, Rotation,
+};
+use crate::file_store::JsonFileMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Settings of one device. Every field is optional, so that a change only names what it
+/// changes, and the saved settings only what was changed at some point.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeviceSettings {
+    pub brightness: Option<u8>,
+    pub gamma: Option<f32>,
+    pub saturation: Option<f32>,
+    pub min_val: Option<u8>,
+    pub mode: Option<RenderMode>,
+    pub filter: Option<ResizeFilter>,
+    pub fit: Option<FitMode>,
+    pub fit_background: Option<[u8; 3]>,
+    pub paused_behavior: Option<PausedBehavior>,
+    pub progress_bar: Option<bool>,
+    pub progress_bar_color: Option<[u8; 3]>,
+    pub show_title: Option<bool>,
+    pub title_color: Option<[u8; 3]>,
+    pub auto_contrast: Option<bool>,
+    pub dither: Option<bool>,
+    pub dither_levels: Option<u8>,
+    // an empty palette keeps all colors
+    pub palette: Option<Vec<[u8; 3]>>,
+    pub crossfade_steps: Option<u8>,
+    pub max_fps: Option<u32>,
+    pub animate: Option<bool>,
+    pub attract_after_secs: Option<u64>,
+    pub poll_interval_ms: Option<u64>,
+    pub pixel_delay_ms: Option<u64>,
+    pub rotation: Option<Rotation>,
+    pub mirror: Option<bool>,
+    pub power_off_on_disconnect: Option<bool>,
+    pub fade_out: Option<bool>,
+}
+
+// polling spotify more often than this gets the app rate limited
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+impl DeviceSettings {
+    /// All settings as the config has them.
+    pub fn of(config: &ElliConfig) -> Self {
+        Self {
+            brightness: Some(config.brightness),
+            gamma: Some(config.gamma),
+            saturation: Some(config.saturation),
+            min_val: Some(config.min_val),
+            mode: Some(config.mode),
+            filter: Some(config.filter),
+            fit: Some(config.fit),
+            fit_background: Some(config.fit_background),
+            paused_behavior: Some(config.paused_behavior.clone()),
+            progress_bar: Some(config.progress_bar),
+            progress_bar_color: Some(config.progress_bar_color),
+            show_title: Some(config.show_title),
+            title_color: Some(config.title_color),
+            auto_contrast: Some(config.auto_contrast),
+            dither: Some(config.dither),
+            dither_levels: Some(config.dither_levels),
+            palette: Some(config.palette.clone().unwrap_or_default()),
+            crossfade_steps: Some(config.crossfade_steps),
+            max_fps: Some(config.max_fps),
+            animate: Some(config.animate),
+            attract_after_secs: Some(config.attract_after.as_secs()),
+            poll_interval_ms: Some(config.poll_interval.as_millis() as u64),
+            pixel_delay_ms: Some(config.pixel_delay.as_millis() as u64),
+            rotation: Some(config.rotation),
+            mirror: Some(config.mirror),
+            power_off_on_disconnect: Some(config.power_off_on_disconnect),
+            fade_out: Some(config.fade_out),
+        }
+    }
+
+    /// Takes over every setting the changes name.
+    pub fn merge(&mut self, changes: DeviceSettings) {
+        macro_rules! take {
+            ($($field:ident),*) => {
+                $(if changes.$field.is_some() {
+                    self.$field = changes.$field;
+                })*
+            };
+        }
+        take!(
+            brightness,
+            gamma,
+            saturation,
+            min_val,
+            mode,
+            filter,
+            fit,
+            fit_background,
+            paused_behavior,
+            progress_bar,
+            progress_bar_color,
+            show_title,
+            title_color,
+            auto_contrast,
+            dither,
+            dither_levels,
+            palette,
+            crossfade_steps,
+            max_fps,
+            animate,
+            attract_after_secs,
+            poll_interval_ms,
+            pixel_delay_ms,
+            rotation,
+            mirror,
+            power_off_on_disconnect,
+            fade_out
+        );
+    }
+
+    /// Sets the given settings on the config. Numbers are clamped to a usable range, as they
+    /// come straight from a request.
+    pub fn apply(&self, config: &mut ElliConfig) {
+        macro_rules! set {
+            ($($field:ident),*) => {
+                $(if let Some(value) = &self.$field {
+                    config.$field = value.clone();
+                })*
+            };
+        }
+        set!(
+            brightness,
+            min_val,
+            mode,
+            filter,
+            fit,
+            fit_background,
+            paused_behavior,
+            progress_bar,
+            progress_bar_color,
+            show_title,
+            title_color,
+            auto_contrast,
+            dither,
+            crossfade_steps,
+            max_fps,
+            animate,
+            rotation,
+            mirror,
+            power_off_on_disconnect,
+            fade_out
+        );
+        if let Some(gamma) = self.gamma {
+            config.gamma = gamma.clamp(0.5, 4.0);
+        }
+        if let Some(saturation) = self.saturation {
+            config.saturation = saturation.clamp(0.0, 3.0);
+        }
+        if let Some(levels) = self.dither_levels {
+            config.dither_levels = levels.max(2);
+        }
+        if let Some(palette) = &self.palette {
+            config.palette = Some(palette.clone()).filter(|palette| !palette.is_empty());
+        }
+        if let Some(secs) = self.attract_after_secs {
+            config.attract_after = Duration::from_secs(secs);
+        }
+        if let Some(millis) = self.poll_interval_ms {
+            config.poll_interval = Duration::from_millis(millis).max(MIN_POLL_INTERVAL);
+        }
+        if let Some(millis) = self.pixel_delay_ms {
+            config.pixel_delay = Duration::from_millis(millis);
+        }
+    }
+}
+
+impl DeviceSettings {
+    /// Whether the settings change how the album art is rendered, so that the frame has to be
+    /// rendered again. Brightness, rotation and mirroring are applied to the rendered frame.
+    pub fn change_rendering(&self) -> bool {
+        self.gamma.is_some()
+            || self.saturation.is_some()
+            || self.min_val.is_some()
+            || self.mode.is_some()
+            || self.filter.is_some()
+            || self.fit.is_some()
+            || self.fit_background.is_some()
+            || self.paused_behavior.is_some()
+            || self.progress_bar.is_some()
+            || self.progress_bar_color.is_some()
+            || self.auto_contrast.is_some()
+            || self.dither.is_some()
+            || self.dither_levels.is_some()
+            || self.palette.is_some()
+    }
+}
+
+impl From<Calibration> for DeviceSettings {
+    fn from(calibration: Calibration) -> Self {
+        Self {
+            gamma: Some(calibration.gamma),
+            brightness: Some(calibration.brightness),
+            saturation: Some(calibration.saturation),
+            ..Self::default()
+        }
+    }
+}
+
+/// Persists the settings keyed by ccc, so that a device looks the same after a restart.
+pub trait SettingsStore: Send + Sync {
+    fn load(&self) -> Result<HashMap<String, DeviceSettings>, Box<dyn Error>>;
+    fn save(&self, ccc: &str, settings: &DeviceSettings) -> Result<(), Box<dyn Error>>;
+}
+
+/// Keeps the settings of all devices in a single JSON file, which is rewritten on every change.
+pub struct FileSettingsStore {
+    file: JsonFileMap<DeviceSettings>,
+}
+
+impl FileSettingsStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            file: JsonFileMap::new(path),
+        }
+    }
+}
+
+impl SettingsStore for FileSettingsStore {
+    fn load(&self) -> Result<HashMap<String, DeviceSettings>, Box<dyn Error>> {
+        self.file.load()
+    }
+
+    fn save(&self, ccc: &str, settings: &DeviceSettings) -> Result<(), Box<dyn Error>> {
+        self.file.insert(ccc, settings.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_and_apply() {
+        let mut settings = DeviceSettings {
+            gamma: Some(1.8),
+            mode: Some(RenderMode::DominantColor),
+            ..DeviceSettings::default()
+        };
+        let changes: DeviceSettings = serde_json::from_str(
+            r#"{"mode": "ambient_glow", "rotation": "clockwise90", "paused_behavior": {"dim": 10},
+                "saturation": 9.0, "palette": []}"#,
+        )
+        .unwrap();
+        settings.merge(changes);
+        assert_eq!(settings.gamma, Some(1.8));
+        assert_eq!(settings.mode, Some(RenderMode::AmbientGlow));
+
+        let mut config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").unwrap();
+        config.palette = Some(vec![[0, 0, 0]]);
+        settings.apply(&mut config);
+        assert_eq!(config.gamma, 1.8);
+        assert_eq!(config.rotation, Rotation::Clockwise90);
+        assert_eq!(config.paused_behavior, PausedBehavior::Dim(10));
+        // clamped, and the empty palette keeps all colors
+        assert_eq!(config.saturation, 3.0);
+        assert_eq!(config.palette, None);
+        // untouched settings keep the defaults
+        assert_eq!(config.fit, FitMode::Stretch);
+    }
+
+    #[test]
+    fn test_change_rendering() {
+        let brightness = DeviceSettings {
+            brightness: Some(40),
+            rotation: Some(Rotation::Clockwise90),
+            ..DeviceSettings::default()
+        };
+        assert!(!brightness.change_rendering());
+        assert!(DeviceSettings::from(Calibration::of(
+            &ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").unwrap()
+        ))
+        .change_rendering());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let path = std::env::temp_dir().join(format!("elli-settings-{}.json", std::process::id()));
+        let store = FileSettingsStore::new(path.clone());
+        assert!(store.load().unwrap().is_empty());
+
+        let config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").unwrap();
+        let settings = DeviceSettings::of(&config);
+        store.save("ccc", &settings).unwrap();
+        store
+            .save("other", &DeviceSettings::from(Calibration::of(&config)))
+            .unwrap();
+
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded["ccc"], settings);
+        assert_eq!(loaded["other"].brightness, Some(config.brightness));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+//! Bearer tokens to control a device without a browser session, e.g. from a script or a home
+//! automation system. A token is the HMAC of the ccc and the session owning the device under a
+//! server secret, so it's valid for one device only and needs no storage. It stops working once
+//! another session takes the device over or it is disconnected. Routes across all devices take
+//! the operator token instead.
+
+use crate::elli::ElliConfig;
+use crate::spotify;
+use crate::state::AppState;
+use actix_session::SessionExt;
+use actix_web::dev::Payload;
+use actix_web::error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{web, FromRequest, HttpRequest};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use futures_util::future::{ready, Ready};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub struct DeviceTokens {
+    secret: Vec<u8>,
+}
+
+impl DeviceTokens {
+    pub fn new(secret: Vec<u8>) -> Self {
+        Self { secret }
+    }
+
+    /// Signs with a random secret, so that tokens are only valid until the server restarts.
+    pub fn random() -> Self {
+        let mut secret = vec![0; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        Self::new(secret)
+    }
+
+    /// A token for the device while the session owns it.
+    pub fn issue(&self, ccc: &str, owner: &str) -> String {
+        URL_SAFE_NO_PAD.encode(self.mac(ccc, owner).finalize().into_bytes())
+    }
+
+    /// Whether the token was issued for the ccc to its current owner. The comparison takes the
+    /// same time for every wrong token.
+    pub fn verify(&self, ccc: &str, owner: &str, token: &str) -> bool {
+        match URL_SAFE_NO_PAD.decode(token) {
+            Ok(signature) => self.mac(ccc, owner).verify_slice(&signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    fn mac(&self, ccc: &str, owner: &str) -> HmacSha256 {
+        // HMAC takes keys of any length
+        let mut mac = HmacSha256::new_from_slice(&self.secret).unwrap();
+        // the length keeps a ccc and owner from being split differently
+        mac.update(&(ccc.len() as u64).to_be_bytes());
+        mac.update(ccc.as_bytes());
+        mac.update(owner.as_bytes());
+        mac
+    }
+}
+
+/// Who calls a route of a device: a browser session or a script showing the device's token.
+/// Requests with an `Authorization` header must carry a valid token for the `{ccc}` of the path.
+pub enum DeviceCaller {
+    Session(String),
+    Token,
+}
+
+impl FromRequest for DeviceCaller {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(device_caller(req))
+    }
+}
+
+fn device_caller(req: &HttpRequest) -> Result<DeviceCaller, actix_web::Error> {
+    let Some(authorization) = req.headers().get(header::AUTHORIZATION) else {
+        return spotify::session_id(&req.get_session()).map(DeviceCaller::Session);
+    };
+    let token = bearer_token(authorization)?;
+    let ccc = req
+        .match_info()
+        .get("ccc")
+        .ok_or_else(|| ErrorUnauthorized("Tokens are only valid for device routes"))?;
+    let app_state = app_state(req)?;
+    // devices without an owner, e.g. disconnected ones, take no token
+    let valid = app_state
+        .owner(ccc)
+        .is_some_and(|owner| app_state.device_tokens().verify(ccc, &owner, token));
+    if valid {
+        Ok(DeviceCaller::Token)
+    } else {
+        Err(ErrorUnauthorized("Invalid token for this device"))
+    }
+}
+
+/// The valid `{ccc}` of a device route, for callers which control the device: the session
+/// owning it, a session taking over a device without an owner, or a script showing its token.
+pub struct ControlledDevice(pub String);
+
+impl FromRequest for ControlledDevice {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(controlled_device(req))
+    }
+}
+
+fn controlled_device(req: &HttpRequest) -> Result<ControlledDevice, actix_web::Error> {
+    let ccc = req
+        .match_info()
+        .get("ccc")
+        .ok_or_else(|| ErrorInternalServerError("Not a device route"))?;
+    ElliConfig::parse_ccc(ccc)?;
+    if let DeviceCaller::Session(session_id) = device_caller(req)? {
+        if app_state(req)?.is_owned_by_other(ccc, &session_id) {
+            return Err(ErrorForbidden("Another session controls this device"));
+        }
+    }
+    Ok(ControlledDevice(ccc.to_string()))
+}
+
+/// Whoever runs the server, e.g. a dashboard or a script, showing the operator token as bearer
+/// token. Routes which see or paint every device require it. Without an operator token
+/// configured, these routes are disabled.
+pub struct Operator;
+
+impl FromRequest for Operator {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(operator(req))
+    }
+}
+
+fn operator(req: &HttpRequest) -> Result<Operator, actix_web::Error> {
+    let expected = app_state(req)?
+        .operator_token()
+        .ok_or_else(|| ErrorForbidden("No operator token is configured"))?;
+    let authorization = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .ok_or_else(|| ErrorUnauthorized("Expected the operator token"))?;
+    // comparing the digests takes the same time however much of the token is right
+    let token = bearer_token(authorization)?;
+    if Sha256::digest(token) == Sha256::digest(expected) {
+        Ok(Operator)
+    } else {
+        Err(ErrorUnauthorized("Invalid operator token"))
+    }
+}
+
+fn bearer_token(authorization: &HeaderValue) -> Result<&str, actix_web::Error> {
+    authorization
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| ErrorUnauthorized("Expected a bearer token"))
+}
+
+fn app_state(req: &HttpRequest) -> Result<&web::Data<AppState>, actix_web::Error> {
+    req.app_data::<web::Data<AppState>>()
+        .ok_or_else(|| ErrorInternalServerError("App state is missing"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_state;
+
+    #[test]
+    fn test_verify() {
+        let tokens = DeviceTokens::new(b"secret".to_vec());
+        let token = tokens.issue("0FBL3E2B3UPU4R9Z", "owner");
+        assert!(tokens.verify("0FBL3E2B3UPU4R9Z", "owner", &token));
+        // tokens are bound to their device, owner and secret
+        assert!(!tokens.verify("0FBL3E2B3UPU4R9Y", "owner", &token));
+        assert!(!tokens.verify("0FBL3E2B3UPU4R9Z", "next owner", &token));
+        let other_secret = DeviceTokens::new(b"other".to_vec());
+        assert!(!other_secret.verify("0FBL3E2B3UPU4R9Z", "owner", &token));
+        assert!(!tokens.verify("0FBL3E2B3UPU4R9Z", "owner", "not base64!"));
+    }
+
+    #[test]
+    fn test_operator() {
+        let state = |token: Option<&str>| {
+            let state = test_state();
+            web::Data::new(match token {
+                Some(token) => state.with_operator_token(token.to_string()),
+                None => state,
+            })
+        };
+        let request = |state: web::Data<AppState>, authorization: Option<&str>| {
+            let request = actix_web::test::TestRequest::default().app_data(state);
+            match authorization {
+                Some(authorization) => {
+                    request.insert_header((header::AUTHORIZATION, authorization))
+                }
+                None => request,
+            }
+            .to_http_request()
+        };
+
+        assert!(operator(&request(state(Some("op")), Some("Bearer op"))).is_ok());
+        assert!(operator(&request(state(Some("op")), Some("Bearer other"))).is_err());
+        assert!(operator(&request(state(Some("op")), None)).is_err());
+        // without a configured token, nobody is an operator
+        assert!(operator(&request(state(None), Some("Bearer "))).is_err());
+    }
+
+    #[test]
+    fn test_controlled_device() {
+        let state = web::Data::new(test_state());
+        state.set_owner("0FBL3E2B3UPU4R9Z", String::from("owner"));
+        let token = state.device_tokens().issue("0FBL3E2B3UPU4R9Z", "owner");
+        let request = |ccc: &str| {
+            actix_web::test::TestRequest::default()
+                .app_data(state.clone())
+                .param("ccc", ccc.to_string())
+                .insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+                .to_http_request()
+        };
+
+        let device = controlled_device(&request("0FBL3E2B3UPU4R9Z")).unwrap();
+        assert_eq!(device.0, "0FBL3E2B3UPU4R9Z");
+        // the token is only valid for its device
+        assert!(controlled_device(&request("0FBL3E2B3UPU4R9Y")).is_err());
+        let invalid = controlled_device(&request("0FBL3E2B")).err().unwrap();
+        assert_eq!(
+            invalid.as_response_error().status_code(),
+            actix_web::http::StatusCode::BAD_REQUEST
+        );
+    }
+}
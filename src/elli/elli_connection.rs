@@ -1,24 +1,36 @@
+use crate::elli::frame_log::{Direction, FrameLog};
 use crate::elli::messages::websocket::{
-    AuthMessage, AuthenticationMessage, PixelData, PixelMessage, RequestMessage, SocketMessage,
+    AuthMessage, AuthenticationMessage, NameMessage, PixelData, PixelMessage, PowerMessage,
+    RequestMessage, SocketMessage, WriteMessage,
 };
-use crate::elli::{ConnectionStatus, ElliConfig};
-use futures_util::{SinkExt, StreamExt};
-use log::{info, warn};
+use crate::elli::{ConnectionStatus, ElliConfig, MAX_SIZE};
+use futures_util::future::BoxFuture;
+use futures_util::{Sink, SinkExt, StreamExt};
 use serde_json::{from_str, to_string};
+use std::collections::VecDeque;
 use std::error::Error;
+use std::time::Duration;
 use tokio::sync::mpsc::error::SendError;
 use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, watch};
 use tokio::task::JoinHandle;
+use tokio::time::{interval_at, sleep, sleep_until, Instant};
 use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite;
 use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
+use tracing::{info, warn, Instrument};
+
+// pause between two attempts of sending the same pixel
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(10);
+// how long we wait for the device to tell its size
+const SIZE_READ_TIMEOUT: Duration = Duration::from_secs(1);
+// how long we collect the pixels the device reports, unless it has reported all of them
+const MATRIX_READ_TIMEOUT: Duration = Duration::from_secs(2);
 
 pub struct ElliConnection {
     cmd_tx: mpsc::Sender<Command>,
     close_manager_tx: oneshot::Sender<()>,
-    close_receiver_tx: oneshot::Sender<()>,
-    connection_status: ConnectionStatus,
-    recv_join_handle: JoinHandle<()>,
+    status_rx: watch::Receiver<ConnectionStatus>,
     cmd_join_handle: JoinHandle<()>,
 }
 
@@ -30,6 +42,24 @@ pub enum Command {
         data: PixelData,
         resp: oneshot::Sender<Result<(), CommandError>>,
     },
+    WritePixels {
+        data: Vec<PixelData>,
+        resp: oneshot::Sender<Result<(), CommandError>>,
+    },
+    SetName {
+        name: String,
+        resp: oneshot::Sender<Result<(), CommandError>>,
+    },
+    ReadSize {
+        resp: oneshot::Sender<Result<Option<u32>, CommandError>>,
+    },
+    Power {
+        on: bool,
+        resp: oneshot::Sender<Result<(), CommandError>>,
+    },
+    ReadMatrix {
+        resp: oneshot::Sender<Result<Vec<PixelData>, CommandError>>,
+    },
 }
 
 #[derive(Debug)]
@@ -45,37 +75,104 @@ impl std::fmt::Display for CommandError {
 
 impl Error for CommandError {}
 
+/// The socket to the host couldn't be opened, e.g. because the host name didn't resolve, the
+/// connection was refused or the TLS handshake failed.
+#[derive(Debug)]
+pub struct ConnectError {
+    pub host: String,
+    pub source: tungstenite::Error,
+}
+
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to connect to {}: {}", self.host, self.source)
+    }
+}
+
+impl Error for ConnectError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Describes how often and how fast the connection manager tries to re-establish a dropped
+/// socket. The n-th attempt waits `base_delay * factor^n`.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub factor: f32,
+}
+
+impl ReconnectPolicy {
+    fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay.mul_f32(self.factor.powi(attempt as i32))
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            factor: 2.0,
+        }
+    }
+}
+
 impl ElliConnection {
-    pub async fn new(config: ElliConfig) -> Result<Self, Box<dyn Error>> {
-        info!("Connecting socket to: {}", config.host);
-        let (ws_stream, _res) = connect_async(&config.host).await?;
-        let (write, read) = ws_stream.split();
-        let (tx_cmd, rx_cmd) = mpsc::channel(32);
-        let (tx_recv, rx_recv) = mpsc::channel(32);
+    pub async fn new(
+        config: ElliConfig,
+        reconnect_policy: ReconnectPolicy,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::with_frame_log(config, reconnect_policy, FrameLog::default()).await
+    }
+
+    /// Like `new`, but logs the frames into the given log, e.g. one kept across connections.
+    pub async fn with_frame_log(
+        config: ElliConfig,
+        reconnect_policy: ReconnectPolicy,
+        frame_log: FrameLog,
+    ) -> Result<Self, Box<dyn Error>> {
+        let (tx_cmd, rx_cmd) = mpsc::channel(config.command_queue.max(1));
         let (tx_close_manager, rx_close_manager) = oneshot::channel();
-        let (tx_close_recv, rx_close_recv) = oneshot::channel();
-        let cmd_join_handle =
-            ConnectionManager::new(write, config, rx_recv, rx_cmd, rx_close_manager).await;
-        let recv_join_handle = ConnectionReceiver::new(read, tx_recv, rx_close_recv).await;
+        let (tx_status, rx_status) = watch::channel(ConnectionStatus::Connected);
+        let receiver_log = frame_log.clone();
+        let connector: Connector<SocketWriter> =
+            Box::new(move |host, tx_recv| Box::pin(connect(host, tx_recv, receiver_log.clone())));
+        let host = config.host.clone();
+        let manager = ConnectionManager::connect(
+            connector,
+            config,
+            reconnect_policy,
+            rx_cmd,
+            rx_close_manager,
+            tx_status,
+            frame_log,
+        )
+        .await
+        .map_err(|source| ConnectError { host, source })?;
+        let cmd_join_handle = manager.start_task().await;
 
         let result = Self {
             cmd_tx: tx_cmd,
             cmd_join_handle,
-            recv_join_handle,
             close_manager_tx: tx_close_manager,
-            close_receiver_tx: tx_close_recv,
-            connection_status: ConnectionStatus::Connected,
+            status_rx: rx_status,
         };
         Ok(result)
     }
 
+    pub fn status(&self) -> ConnectionStatus {
+        self.status_rx.borrow().clone()
+    }
+
     pub async fn authenticate(&mut self) -> Result<(), Box<dyn Error>> {
         let (res_tx, res_rx) = oneshot::channel();
         let cmd = Command::Authenticate { resp: res_tx };
         self.cmd_tx.send(cmd).await?;
         let result = res_rx.await??;
-        self.connection_status = result;
-        info!("Authenticated Socket. Status: {:?}", self.connection_status);
+        info!("Authenticated Socket. Status: {:?}", result);
         Ok(())
     }
 
@@ -86,17 +183,98 @@ impl ElliConnection {
             data: pixel,
         };
         self.cmd_tx.send(cmd).await?;
-        let _ = res_rx.await??;
+        res_rx.await??;
+        Ok(())
+    }
+
+    /// Sends all pixels in as few socket frames as the config's `pixel_batch_size` allows.
+    /// Returns once the last frame was sent.
+    pub async fn write_pixels(&mut self, pixels: Vec<PixelData>) -> Result<(), Box<dyn Error>> {
+        let (res_tx, res_rx) = oneshot::channel();
+        let cmd = Command::WritePixels {
+            resp: res_tx,
+            data: pixels,
+        };
+        self.cmd_tx.send(cmd).await?;
+        res_rx.await??;
+        Ok(())
+    }
+
+    /// Sends the pixels one message each like `write_pixel`, but doesn't wait for a write to
+    /// be confirmed before sending the next one. At most `limit` writes are unconfirmed at a
+    /// time, and `delay` passes between two sends. Returns once every write is confirmed.
+    /// Without `wait_for_ack` writes are confirmed once sent, so this is no faster than
+    /// sending them one after the other.
+    pub async fn write_pixels_concurrently(
+        &mut self,
+        pixels: Vec<PixelData>,
+        limit: usize,
+        delay: Duration,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut in_flight = VecDeque::new();
+        for data in pixels {
+            if in_flight.len() >= limit.max(1) {
+                let res_rx: oneshot::Receiver<Result<(), CommandError>> =
+                    in_flight.pop_front().unwrap();
+                res_rx.await??;
+            }
+            let (res_tx, res_rx) = oneshot::channel();
+            let cmd = Command::WritePixel { resp: res_tx, data };
+            self.cmd_tx.send(cmd).await?;
+            in_flight.push_back(res_rx);
+            if !delay.is_zero() {
+                sleep(delay).await;
+            }
+        }
+        for res_rx in in_flight {
+            res_rx.await??;
+        }
+        Ok(())
+    }
+
+    /// Renames the device. The device keeps the name across restarts.
+    pub async fn set_name(&mut self, name: String) -> Result<(), Box<dyn Error>> {
+        let (res_tx, res_rx) = oneshot::channel();
+        let cmd = Command::SetName { resp: res_tx, name };
+        self.cmd_tx.send(cmd).await?;
+        res_rx.await??;
+        Ok(())
+    }
+
+    /// Switches the matrix on or off. Devices which don't know the command ignore it.
+    pub async fn set_power(&mut self, on: bool) -> Result<(), Box<dyn Error>> {
+        let (res_tx, res_rx) = oneshot::channel();
+        let cmd = Command::Power { on, resp: res_tx };
+        self.cmd_tx.send(cmd).await?;
+        res_rx.await??;
         Ok(())
     }
 
+    /// Asks the device for the size of its matrix. Returns None, if the device doesn't answer
+    /// in time or with an unusable size.
+    pub async fn read_size(&mut self) -> Result<Option<u32>, Box<dyn Error>> {
+        let (res_tx, res_rx) = oneshot::channel();
+        let cmd = Command::ReadSize { resp: res_tx };
+        self.cmd_tx.send(cmd).await?;
+        let size = res_rx.await??;
+        Ok(size)
+    }
+
+    /// Asks the device for the pixels it is showing. Returns what the device reported within
+    /// `MATRIX_READ_TIMEOUT`, which may be less than the full matrix.
+    pub async fn read_matrix(&mut self) -> Result<Vec<PixelData>, Box<dyn Error>> {
+        let (res_tx, res_rx) = oneshot::channel();
+        let cmd = Command::ReadMatrix { resp: res_tx };
+        self.cmd_tx.send(cmd).await?;
+        let pixels = res_rx.await??;
+        Ok(pixels)
+    }
+
     pub async fn close(self) -> Result<(), Box<dyn Error>> {
-        // send close signals
-        let _ = self.close_receiver_tx.send(());
+        // send close signal. The manager closes the receiver before it finishes.
         let _ = self.close_manager_tx.send(());
 
-        // wait for tasks to finish
-        self.recv_join_handle.await?;
+        // wait for the manager to finish
         self.cmd_join_handle.await?;
 
         info!("Socket finished closing");
@@ -112,55 +290,341 @@ type SocketWriter = futures_util::stream::SplitSink<
     Message,
 >;
 
+// opens a socket to the given host and spawns a receiver for its read half, which forwards
+// incoming messages into the passed sender. Returns the write half.
+type Connector<W> = Box<
+    dyn Fn(
+            String,
+            Sender<RecvSocketMsg>,
+        ) -> BoxFuture<'static, Result<(W, ReceiverHandle), tungstenite::Error>>
+        + Send,
+>;
+
+async fn connect(
+    host: String,
+    tx_recv: Sender<RecvSocketMsg>,
+    frame_log: FrameLog,
+) -> Result<(SocketWriter, ReceiverHandle), tungstenite::Error> {
+    info!("Connecting socket to: {}", host);
+    let (ws_stream, _res) = connect_async(&host).await?;
+    let (write, read) = ws_stream.split();
+    let receiver = ConnectionReceiver::new(read, tx_recv, frame_log).await;
+    Ok((write, receiver))
+}
+
 enum RecvSocketMsg {
-    Authentication { status: String },
+    Authentication { ok: bool, status: String },
+    Pong,
+    // the device echoed a written pixel or reported one it is showing
+    Pixel { pixel: PixelData },
+    // the device answered a size read
+    Size { size: u32 },
+    // the read half of the socket has ended, either by an error or a close from the other side
+    Disconnected,
 }
 
-struct ConnectionManager {
-    writer: SocketWriter,
+struct ConnectionManager<W = SocketWriter> {
+    writer: W,
+    receiver: Option<ReceiverHandle>,
+    connector: Connector<W>,
     config: ElliConfig,
+    reconnect_policy: ReconnectPolicy,
     // possibly, we need a list inside the map in case we have multiple auth requests for the
     // same device
     pending_auth_request: Option<oneshot::Sender<Result<ConnectionStatus, CommandError>>>,
+    pending_size_request: Option<PendingSizeRead>,
+    pending_matrix_read: Option<PendingMatrixRead>,
+    // commands which could not be sent because the socket died. They are re-sent once the
+    // socket is re-established.
+    pending_cmds: VecDeque<Command>,
+    // written pixels waiting for their echo from the device, oldest first
+    pending_acks: VecDeque<PendingAck>,
+    // set as soon as the socket is found dead
+    needs_reconnect: bool,
+    // when the socket last answered a ping, or when it was established
+    last_pong: Instant,
+    // whether an authentication was requested on this connection, so that we re-authenticate
+    // after a reconnect
+    authenticated: bool,
+    // sender handed to every new receiver
+    tx_socket: Sender<RecvSocketMsg>,
     // receiver to the socket reader
     rx_socket: mpsc::Receiver<RecvSocketMsg>,
     // receiver to receive commands from the main task
     rx_cmd: Receiver<Command>,
     // use oneshot channel for closing the manager
     rx_close: oneshot::Receiver<()>,
+    tx_status: watch::Sender<ConnectionStatus>,
+    // every text frame sent
+    frame_log: FrameLog,
 }
 
-impl ConnectionManager {
-    async fn new(
-        writer: SocketWriter,
+impl<W> ConnectionManager<W>
+where
+    W: Sink<Message, Error = tungstenite::Error> + Unpin + Send + 'static,
+{
+    async fn connect(
+        connector: Connector<W>,
         config: ElliConfig,
-        rx_socket: Receiver<RecvSocketMsg>,
+        reconnect_policy: ReconnectPolicy,
         rx_cmd: Receiver<Command>,
         rx_close: oneshot::Receiver<()>,
-    ) -> JoinHandle<()> {
+        tx_status: watch::Sender<ConnectionStatus>,
+        frame_log: FrameLog,
+    ) -> Result<Self, tungstenite::Error> {
+        let (tx_socket, rx_socket) = mpsc::channel(32);
+        let (writer, receiver) = connector(config.host.clone(), tx_socket.clone()).await?;
         let result = Self {
             writer,
+            receiver: Some(receiver),
+            connector,
             config,
+            reconnect_policy,
             pending_auth_request: None,
+            pending_size_request: None,
+            pending_matrix_read: None,
+            pending_cmds: VecDeque::new(),
+            pending_acks: VecDeque::new(),
+            needs_reconnect: false,
+            last_pong: Instant::now(),
+            authenticated: false,
+            tx_socket,
             rx_socket,
             rx_cmd,
             rx_close,
+            tx_status,
+            frame_log,
         };
-        result.start_task().await
+        Ok(result)
     }
+
     async fn start_task(mut self) -> JoinHandle<()> {
+        // logs in the span of whoever opened the connection, e.g. of the device's worker
         tokio::spawn(async move {
+            let ping_interval = self.config.ping_interval;
+            let mut ping = interval_at(Instant::now() + ping_interval, ping_interval);
             loop {
+                let ack_deadline = self.next_ack_deadline();
+                let awaiting_acks = !self.pending_acks.is_empty();
+                let read_deadline = self.pending_matrix_read.as_ref().map(|read| read.deadline);
+                let size_deadline = self.pending_size_request.as_ref().map(|read| read.deadline);
                 tokio::select! {
                     Some(cmd) = self.rx_cmd.recv() => { self.handle_recv_cmd(cmd).await }
                     Some(recv) = self.rx_socket.recv() => { self.handle_recv_socket_msg(recv).await }
+                    _ = ping.tick() => { self.keep_alive().await }
+                    _ = sleep_until(ack_deadline), if awaiting_acks => {
+                        self.expire_acks()
+                    }
+                    _ = sleep_until(read_deadline.unwrap_or_else(Instant::now)),
+                        if read_deadline.is_some() => {
+                        self.finish_matrix_read()
+                    }
+                    _ = sleep_until(size_deadline.unwrap_or_else(Instant::now)),
+                        if size_deadline.is_some() => {
+                        self.expire_size_read()
+                    }
                     _ = &mut self.rx_close => {
-                        _ = self.writer.close().await; // we ignore the result and kill the task
                         break;
                     }
                 }
+                if self.needs_reconnect && !self.reconnect().await {
+                    // the close signal arrived while reconnecting
+                    break;
+                }
+            }
+            _ = self.writer.close().await; // we ignore the result and kill the task
+            if let Some(receiver) = self.receiver.take() {
+                receiver.close().await;
+            }
+        }.in_current_span())
+    }
+
+    /// Re-establishes the socket according to the reconnect policy and re-sends pending
+    /// commands. Returns false if the manager was told to close in the meantime.
+    async fn reconnect(&mut self) -> bool {
+        self.needs_reconnect = false;
+        let _ = self.tx_status.send(ConnectionStatus::Reconnecting);
+        if let Some(receiver) = self.receiver.take() {
+            receiver.close().await;
+        }
+        // drop everything the old receiver has sent, so that its disconnect message doesn't
+        // trigger another reconnect
+        while self.rx_socket.try_recv().is_ok() {}
+
+        for attempt in 0..self.reconnect_policy.max_retries {
+            let delay = self.reconnect_policy.delay(attempt);
+            info!(
+                "Reconnecting to {} in {:?} (attempt {})",
+                self.config.host,
+                delay,
+                attempt + 1
+            );
+            tokio::select! {
+                _ = sleep(delay) => {}
+                _ = &mut self.rx_close => { return false; }
+            }
+
+            match (self.connector)(self.config.host.clone(), self.tx_socket.clone()).await {
+                Ok((writer, receiver)) => {
+                    self.writer = writer;
+                    self.receiver = Some(receiver);
+                    self.last_pong = Instant::now();
+                    let _ = self.tx_status.send(ConnectionStatus::Connected);
+                    if self.authenticated {
+                        if let Err(e) = self.send_auth_message().await {
+                            warn!("Failed to re-authenticate after reconnect: {:?}", e);
+                            continue;
+                        }
+                    }
+                    info!("Reconnected to {}", self.config.host);
+                    self.resume_pending_cmds().await;
+                    return true;
+                }
+                Err(e) => warn!("Reconnect attempt {} failed: {:?}", attempt + 1, e),
             }
-        })
+        }
+
+        warn!(
+            "Giving up reconnecting to {} after {} attempts",
+            self.config.host, self.reconnect_policy.max_retries
+        );
+        let _ = self.tx_status.send(ConnectionStatus::Error);
+        self.fail_pending_cmds();
+        true
+    }
+
+    async fn resume_pending_cmds(&mut self) {
+        while let Some(cmd) = self.pending_cmds.pop_front() {
+            self.handle_recv_cmd(cmd).await;
+            if self.needs_reconnect {
+                // the socket died again. The main loop takes care of the remaining commands.
+                return;
+            }
+        }
+    }
+
+    fn next_ack_deadline(&self) -> Instant {
+        self.pending_acks
+            .front()
+            .map(|ack| ack.deadline)
+            .unwrap_or_else(Instant::now)
+    }
+
+    fn expire_acks(&mut self) {
+        let now = Instant::now();
+        while self
+            .pending_acks
+            .front()
+            .is_some_and(|ack| ack.deadline <= now)
+        {
+            let ack = self.pending_acks.pop_front().unwrap();
+            warn!("No acknowledgement for {} pixels", ack.positions.len());
+            let _ = ack.resp.send(Err(CommandError {
+                msg: String::from("Device did not acknowledge the write"),
+            }));
+        }
+    }
+
+    // resolves the write once all its pixels have been echoed
+    fn handle_ack(&mut self, row: usize, col: usize) {
+        let Some(index) = self
+            .pending_acks
+            .iter()
+            .position(|ack| ack.positions.contains(&(row, col)))
+        else {
+            return;
+        };
+        let ack = &mut self.pending_acks[index];
+        ack.positions.retain(|pos| *pos != (row, col));
+        if ack.positions.is_empty() {
+            let ack = self.pending_acks.remove(index).unwrap();
+            let _ = ack.resp.send(Ok(()));
+        }
+    }
+
+    // keeps a pixel reported during a matrix read. A later report of the same pixel wins.
+    fn collect_pixel(&mut self, pixel: PixelData) {
+        let Some(read) = self.pending_matrix_read.as_mut() else {
+            return;
+        };
+        read.pixels
+            .retain(|known| (known.row, known.col) != (pixel.row, pixel.col));
+        read.pixels.push(pixel);
+        if read.pixels.len() >= read.expected {
+            self.finish_matrix_read();
+        }
+    }
+
+    // the device doesn't tell its size, so the size of the ccc stays. A late answer still
+    // updates the config of the connection.
+    fn expire_size_read(&mut self) {
+        if let Some(read) = self.pending_size_request.take() {
+            info!("Device didn't tell its size in {:?}", SIZE_READ_TIMEOUT);
+            let _ = read.resp.send(Ok(None));
+        }
+    }
+
+    fn finish_matrix_read(&mut self) {
+        if let Some(read) = self.pending_matrix_read.take() {
+            info!("Device reported {} pixels", read.pixels.len());
+            let _ = read.resp.send(Ok(read.pixels));
+        }
+    }
+
+    // the device might mishandle pixels outside of its matrix, so they are never sent
+    fn check_bounds(&self, pixels: &[PixelData]) -> Result<(), CommandError> {
+        let size = self.config.size as usize;
+        match pixels.iter().find(|p| p.row >= size || p.col >= size) {
+            Some(pixel) => Err(CommandError {
+                msg: format!(
+                    "Pixel at row {}, col {} is outside of the {}x{} matrix",
+                    pixel.row, pixel.col, size, size
+                ),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // resolves a sent write right away, or once the device has echoed the pixels
+    fn confirm_write(
+        &mut self,
+        pixels: &[PixelData],
+        resp: oneshot::Sender<Result<(), CommandError>>,
+    ) {
+        if self.config.wait_for_ack {
+            self.pending_acks.push_back(PendingAck {
+                positions: pixels.iter().map(|p| (p.row, p.col)).collect(),
+                deadline: Instant::now() + self.config.ack_timeout,
+                resp,
+            });
+        } else {
+            let _ = resp.send(Ok(()));
+        }
+    }
+
+    fn fail_pending_cmds(&mut self) {
+        for cmd in self.pending_cmds.drain(..) {
+            let command_error = CommandError {
+                msg: String::from("Connection lost"),
+            };
+            match cmd {
+                Command::Authenticate { resp } => {
+                    let _ = resp.send(Err(command_error));
+                }
+                Command::WritePixel { resp, .. }
+                | Command::WritePixels { resp, .. }
+                | Command::SetName { resp, .. }
+                | Command::Power { resp, .. } => {
+                    let _ = resp.send(Err(command_error));
+                }
+                Command::ReadSize { resp } => {
+                    let _ = resp.send(Err(command_error));
+                }
+                Command::ReadMatrix { resp } => {
+                    let _ = resp.send(Err(command_error));
+                }
+            }
+        }
     }
 
     async fn handle_recv_cmd(&mut self, cmd: Command) {
@@ -171,24 +635,92 @@ impl ConnectionManager {
             Command::WritePixel { data, resp } => {
                 self.write_pixel(data, resp).await;
             }
+            Command::WritePixels { data, resp } => {
+                self.write_pixels(data, resp).await;
+            }
+            Command::SetName { name, resp } => {
+                self.set_name(name, resp).await;
+            }
+            Command::ReadSize { resp } => {
+                self.read_size(resp).await;
+            }
+            Command::Power { on, resp } => {
+                self.set_power(on, resp).await;
+            }
+            Command::ReadMatrix { resp } => {
+                self.read_matrix(resp).await;
+            }
         }
     }
 
     async fn handle_recv_socket_msg(&mut self, msg: RecvSocketMsg) {
         match msg {
-            RecvSocketMsg::Authentication { status } => {
-                let connection_status = if status == "ok" {
+            RecvSocketMsg::Authentication { ok, status } => {
+                let connection_status = if ok {
                     ConnectionStatus::Authenticated
                 } else {
+                    warn!("Device refused the authentication: {}", status);
                     ConnectionStatus::Error
                 };
+                let _ = self.tx_status.send(connection_status.clone());
 
                 if let Some(tx) = self.pending_auth_request.take() {
-                    tx.send(Ok(connection_status)).unwrap();
+                    let _ = tx.send(Ok(connection_status));
+                } else {
+                    info!(
+                        "Received auth message from socket without pending request. Status: {:?}",
+                        connection_status
+                    );
+                }
+            }
+            RecvSocketMsg::Pong => {
+                self.last_pong = Instant::now();
+            }
+            RecvSocketMsg::Pixel { pixel } => {
+                self.handle_ack(pixel.row, pixel.col);
+                self.collect_pixel(pixel);
+            }
+            RecvSocketMsg::Size { size } => {
+                let size = if (1..=MAX_SIZE).contains(&size) {
+                    // pixels are checked against the size the device reports, not the ccc's
+                    self.config.set_size(size);
+                    Some(self.config.size)
                 } else {
-                    warn!("Received auth message from socket, but no pending async request in connection manager");
+                    warn!("Device reported unusable size {}", size);
+                    None
+                };
+                if let Some(read) = self.pending_size_request.take() {
+                    let _ = read.resp.send(Ok(size));
                 }
             }
+            RecvSocketMsg::Disconnected => {
+                warn!("Socket to {} disconnected", self.config.host);
+                self.mark_dead();
+            }
+        }
+    }
+
+    // pings the socket, unless it hasn't answered the previous pings in time
+    async fn keep_alive(&mut self) {
+        if self.last_pong.elapsed() > self.config.pong_timeout {
+            warn!(
+                "No pong from {} for {:?}",
+                self.config.host,
+                self.last_pong.elapsed()
+            );
+            self.mark_dead();
+            return;
+        }
+        if let Err(e) = self.writer.send(Message::Ping(Default::default())).await {
+            warn!("Failed to ping {}: {:?}", self.config.host, e);
+            self.mark_dead();
+        }
+    }
+
+    fn mark_dead(&mut self) {
+        self.needs_reconnect = self.reconnect_policy.max_retries > 0;
+        if !self.needs_reconnect {
+            let _ = self.tx_status.send(ConnectionStatus::Error);
         }
     }
 
@@ -196,106 +728,387 @@ impl ConnectionManager {
         &mut self,
         resp: oneshot::Sender<Result<ConnectionStatus, CommandError>>,
     ) {
+        match self.send_auth_message().await {
+            Ok(_) => {
+                self.authenticated = true;
+                self.pending_auth_request = Some(resp);
+            }
+            Err(e) => {
+                let command_error = CommandError {
+                    msg: format!("{:?}", e),
+                };
+                let _ = resp.send(Err(command_error));
+            }
+        }
+    }
+
+    async fn send_auth_message(&mut self) -> Result<(), tungstenite::Error> {
         let auth_msg = AuthMessage {
             request: "authenticate".to_string(),
-            param: "ReqL1".to_string(),
-            device_type: "TetrisController".to_string(),
+            param: self.config.auth_param.clone(),
+            device_type: self.config.device_type.clone(),
             address: self.config.d_code.clone(),
             from: self.config.b_code.clone(),
         };
         let msg = Utf8Bytes::from(to_string(&auth_msg).expect("Writing to json should work"));
-        match self.writer.send(Message::Text(msg)).await {
-            Ok(_) => {
-                self.pending_auth_request = Some(resp);
+        self.writer.send(Message::Text(msg.clone())).await?;
+        self.frame_log.record(Direction::Sent, &msg);
+        Ok(())
+    }
+
+    async fn write_pixel(
+        &mut self,
+        data: PixelData,
+        resp: oneshot::Sender<Result<(), CommandError>>,
+    ) {
+        if let Err(e) = self.check_bounds(std::slice::from_ref(&data)) {
+            let _ = resp.send(Err(e));
+            return;
+        }
+        let msg = self.pixel_frame(std::slice::from_ref(&data));
+        match self.send_with_retries("pixel", msg).await {
+            Ok(_) => self.confirm_write(std::slice::from_ref(&data), resp),
+            Err(e) if self.reconnect_policy.max_retries > 0 => {
+                // the socket seems dead. Keep the command until we have reconnected.
+                warn!("Failed to write pixel: {:?}. Queuing it for reconnect.", e);
+                self.pending_cmds
+                    .push_front(Command::WritePixel { data, resp });
+                self.needs_reconnect = true;
             }
             Err(e) => {
                 let command_error = CommandError {
                     msg: format!("{:?}", e),
                 };
-                resp.send(Err(command_error)).unwrap();
+                let _ = resp.send(Err(command_error));
             }
         }
     }
 
-    async fn write_pixel(
+    async fn write_pixels(
         &mut self,
-        data: PixelData,
+        mut data: Vec<PixelData>,
         resp: oneshot::Sender<Result<(), CommandError>>,
     ) {
-        let req_msg = RequestMessage {
-            request: String::from("write"),
-            param: String::from("pixel"),
+        if let Err(e) = self.check_bounds(&data) {
+            let _ = resp.send(Err(e));
+            return;
+        }
+        let batch_size = self.config.pixel_batch_size.max(1);
+        let mut sent = 0;
+        while sent < data.len() {
+            let end = (sent + batch_size).min(data.len());
+            let msg = self.pixel_frame(&data[sent..end]);
+            match self.send_with_retries("pixels", msg).await {
+                Ok(_) => sent = end,
+                Err(e) if self.reconnect_policy.max_retries > 0 => {
+                    // only re-send the pixels which haven't made it to the socket yet
+                    warn!(
+                        "Failed to write pixels: {:?}. Queuing them for reconnect.",
+                        e
+                    );
+                    data.drain(..sent);
+                    self.pending_cmds
+                        .push_front(Command::WritePixels { data, resp });
+                    self.needs_reconnect = true;
+                    return;
+                }
+                Err(e) => {
+                    let command_error = CommandError {
+                        msg: format!("{:?}", e),
+                    };
+                    let _ = resp.send(Err(command_error));
+                    return;
+                }
+            }
+        }
+        self.confirm_write(&data, resp);
+    }
+
+    async fn set_name(&mut self, name: String, resp: oneshot::Sender<Result<(), CommandError>>) {
+        let message = NameMessage {
+            name: name.clone(),
+            request: RequestMessage {
+                request: String::from("write"),
+                param: String::from("name"),
+                from: self.config.b_code.clone(),
+                to: self.config.d_code.clone(),
+            },
+        };
+        let msg = Utf8Bytes::from(to_string(&message).expect("Writing to json should work"));
+        match self.send_with_retries("name", msg).await {
+            Ok(_) => {
+                let _ = resp.send(Ok(()));
+            }
+            Err(e) if self.reconnect_policy.max_retries > 0 => {
+                warn!("Failed to write name: {:?}. Queuing it for reconnect.", e);
+                self.pending_cmds
+                    .push_front(Command::SetName { name, resp });
+                self.needs_reconnect = true;
+            }
+            Err(e) => {
+                let command_error = CommandError {
+                    msg: format!("{:?}", e),
+                };
+                let _ = resp.send(Err(command_error));
+            }
+        }
+    }
+
+    async fn set_power(&mut self, on: bool, resp: oneshot::Sender<Result<(), CommandError>>) {
+        let message = PowerMessage {
+            power: on,
+            request: RequestMessage {
+                request: String::from("write"),
+                param: String::from("power"),
+                from: self.config.b_code.clone(),
+                to: self.config.d_code.clone(),
+            },
+        };
+        let msg = Utf8Bytes::from(to_string(&message).expect("Writing to json should work"));
+        match self.send_with_retries("power", msg).await {
+            Ok(_) => {
+                let _ = resp.send(Ok(()));
+            }
+            Err(e) if self.reconnect_policy.max_retries > 0 => {
+                warn!("Failed to write power: {:?}. Queuing it for reconnect.", e);
+                self.pending_cmds.push_front(Command::Power { on, resp });
+                self.needs_reconnect = true;
+            }
+            Err(e) => {
+                let command_error = CommandError {
+                    msg: format!("{:?}", e),
+                };
+                let _ = resp.send(Err(command_error));
+            }
+        }
+    }
+
+    async fn read_size(&mut self, resp: oneshot::Sender<Result<Option<u32>, CommandError>>) {
+        let message = RequestMessage {
+            request: String::from("read"),
+            param: String::from("size"),
             from: self.config.b_code.clone(),
             to: self.config.d_code.clone(),
         };
-        let pixel_msg = PixelMessage {
-            pixel: data,
-            request: req_msg,
-        };
+        let msg = Utf8Bytes::from(to_string(&message).expect("Writing to json should work"));
+        // the size is only nice to know, so a failed read isn't kept for a reconnect
+        match self.send_with_retries("size read", msg).await {
+            Ok(_) => {
+                self.pending_size_request = Some(PendingSizeRead {
+                    deadline: Instant::now() + SIZE_READ_TIMEOUT,
+                    resp,
+                })
+            }
+            Err(e) => {
+                let command_error = CommandError {
+                    msg: format!("{:?}", e),
+                };
+                let _ = resp.send(Err(command_error));
+            }
+        }
+    }
 
-        let msg = Utf8Bytes::from(to_string(&pixel_msg).expect("Writing to json should work"));
-        match self.writer.send(Message::Text(msg)).await {
+    async fn read_matrix(&mut self, resp: oneshot::Sender<Result<Vec<PixelData>, CommandError>>) {
+        let message = RequestMessage {
+            request: String::from("read"),
+            param: String::from("pixel"),
+            from: self.config.b_code.clone(),
+            to: self.config.d_code.clone(),
+        };
+        let msg = Utf8Bytes::from(to_string(&message).expect("Writing to json should work"));
+        match self.send_with_retries("matrix read", msg).await {
             Ok(_) => {
-                resp.send(Ok(())).unwrap();
+                // a read still running is answered with what it has got so far
+                self.finish_matrix_read();
+                let size = self.config.size as usize;
+                self.pending_matrix_read = Some(PendingMatrixRead {
+                    pixels: Vec::new(),
+                    expected: size * size,
+                    deadline: Instant::now() + MATRIX_READ_TIMEOUT,
+                    resp,
+                });
             }
             Err(e) => {
                 let command_error = CommandError {
                     msg: format!("{:?}", e),
                 };
-                resp.send(Err(command_error)).unwrap();
+                let _ = resp.send(Err(command_error));
             }
         }
     }
+
+    // a single pixel is sent as plain object, multiple pixels as an array of objects
+    fn pixel_frame(&self, pixels: &[PixelData]) -> Utf8Bytes {
+        let messages: Vec<PixelMessage> = pixels
+            .iter()
+            .map(|pixel| PixelMessage {
+                pixel: pixel.clone(),
+                request: RequestMessage {
+                    request: String::from("write"),
+                    param: String::from("pixel"),
+                    from: self.config.b_code.clone(),
+                    to: self.config.d_code.clone(),
+                },
+            })
+            .collect();
+        let json = match messages.as_slice() {
+            [single] => to_string(single),
+            _ => to_string(&messages),
+        };
+        Utf8Bytes::from(json.expect("Writing to json should work"))
+    }
+
+    /// Sends `msg` and tries again as long as the socket is only busy. `what` names the
+    /// message in the logs.
+    async fn send_with_retries(
+        &mut self,
+        what: &str,
+        msg: Utf8Bytes,
+    ) -> Result<(), tungstenite::Error> {
+        let mut attempt = 0;
+        loop {
+            match self.writer.send(Message::Text(msg.clone())).await {
+                Ok(_) => {
+                    // only frames which made it to the socket
+                    self.frame_log.record(Direction::Sent, &msg);
+                    return Ok(());
+                }
+                Err(e) if is_retryable(&e) && attempt < self.config.write_retries => {
+                    attempt += 1;
+                    warn!(
+                        "Failed to write {} (attempt {}): {:?}. Retrying.",
+                        what, attempt, e
+                    );
+                    sleep(WRITE_RETRY_DELAY).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// a closed socket or a failing io stays broken, only a full write buffer is worth a retry
+fn is_retryable(e: &tungstenite::Error) -> bool {
+    matches!(e, tungstenite::Error::WriteBufferFull(_))
+}
+
+/// A write waiting for the device to echo its pixels.
+struct PendingAck {
+    positions: Vec<(usize, usize)>,
+    deadline: Instant,
+    resp: oneshot::Sender<Result<(), CommandError>>,
+}
+
+/// A read of the matrix size, answered with None once the deadline passes.
+struct PendingSizeRead {
+    deadline: Instant,
+    resp: oneshot::Sender<Result<Option<u32>, CommandError>>,
+}
+
+/// A read of the pixels on the device, collecting them as the device reports them.
+struct PendingMatrixRead {
+    pixels: Vec<PixelData>,
+    // pixels of the full matrix, after which the read is done
+    expected: usize,
+    deadline: Instant,
+    resp: oneshot::Sender<Result<Vec<PixelData>, CommandError>>,
+}
+
+/// Handle to a running receiver task, used to stop it.
+struct ReceiverHandle {
+    close_tx: oneshot::Sender<()>,
+    join_handle: JoinHandle<()>,
+}
+
+impl ReceiverHandle {
+    async fn close(self) {
+        let _ = self.close_tx.send(());
+        if let Err(e) = self.join_handle.await {
+            warn!("Receiver task failed: {:?}", e);
+        }
+    }
 }
 
 pub struct ConnectionReceiver {
     reader: SocketReader,
     tx_recv: Sender<RecvSocketMsg>,
+    // every text frame received
+    frame_log: FrameLog,
 }
 
 impl ConnectionReceiver {
+    #[expect(clippy::new_ret_no_self, reason = "the receiver lives on in its task")]
     async fn new(
         reader: SocketReader,
         tx_recv: Sender<RecvSocketMsg>,
-        rx_close: oneshot::Receiver<()>,
-    ) -> JoinHandle<()> {
-        let result = Self { reader, tx_recv };
-        result.start_task(rx_close).await
+        frame_log: FrameLog,
+    ) -> ReceiverHandle {
+        let (close_tx, rx_close) = oneshot::channel();
+        let result = Self {
+            reader,
+            tx_recv,
+            frame_log,
+        };
+        let join_handle = result.start_task(rx_close).await;
+        ReceiverHandle {
+            close_tx,
+            join_handle,
+        }
     }
 
     async fn start_task(mut self, mut rx_close: oneshot::Receiver<()>) -> JoinHandle<()> {
-        tokio::spawn(async move {
-            loop {
-                tokio::select! {
-                    res = self.read_next() => {
-                        if let Err(e) = res {
-                            warn!("Error reading from socket: {:?}", e);
+        tokio::spawn(
+            async move {
+                loop {
+                    tokio::select! {
+                        res = self.reader.next() => {
+                            match res {
+                                Some(Ok(msg)) => {
+                                    if let Err(e) = self.handle_message(msg).await {
+                                        warn!("Error handling socket message: {:?}", e);
+                                    }
+                                }
+                                Some(Err(e)) => {
+                                    warn!("Error reading from socket: {:?}", e);
+                                    let _ = self.tx_recv.send(RecvSocketMsg::Disconnected).await;
+                                    break;
+                                }
+                                None => {
+                                    info!("Socket stream ended");
+                                    let _ = self.tx_recv.send(RecvSocketMsg::Disconnected).await;
+                                    break;
+                                }
+                            }
+                        }
+                        _ = &mut rx_close => {
+                            break;
                         }
-                    }
-                    _ = &mut rx_close => {
-                        break;
                     }
                 }
             }
-        })
+            .in_current_span(),
+        )
     }
 
-    pub async fn read_next(&mut self) -> Result<(), Box<dyn Error>> {
-        if let Some(res) = self.reader.next().await {
-            match res? {
-                Message::Text(text) => self.handle_text(text.to_string()).await,
-                Message::Ping(_) => {
-                    info!("Received Ping");
-                    Ok(())
-                }
-                Message::Close(c) => {
-                    info!("Socket closed from other side: {:?}", c);
-                    Ok(())
-                }
-                _ => Ok(()),
+    async fn handle_message(&mut self, msg: Message) -> Result<(), Box<dyn Error>> {
+        match msg {
+            Message::Text(text) => {
+                self.frame_log.record(Direction::Received, &text);
+                self.handle_text(text.to_string()).await
             }
-        } else {
-            Ok(())
+            Message::Ping(_) => {
+                info!("Received Ping");
+                Ok(())
+            }
+            Message::Pong(_) => {
+                self.tx_recv.send(RecvSocketMsg::Pong).await?;
+                Ok(())
+            }
+            Message::Close(c) => {
+                info!("Socket closed from other side: {:?}", c);
+                Ok(())
+            }
+            _ => Ok(()),
         }
     }
 
@@ -303,8 +1116,24 @@ impl ConnectionReceiver {
         let msg = from_str::<SocketMessage>(&text)?;
         match msg {
             SocketMessage::Authentication(a) => self.handle_authenticated(a).await?,
-            SocketMessage::Write(_) => {
-                warn!("Receiving write messages from socket server not implemented. Ignoring message.")
+            SocketMessage::Write(WriteMessage::Pixel(p)) => {
+                self.tx_recv
+                    .send(RecvSocketMsg::Pixel { pixel: p.pixel })
+                    .await?;
+            }
+            SocketMessage::Write(WriteMessage::Size(s)) => {
+                self.tx_recv
+                    .send(RecvSocketMsg::Size { size: s.size })
+                    .await?;
+            }
+            SocketMessage::Write(WriteMessage::DeviceName(name)) => {
+                info!("Device is named {}", name.name)
+            }
+            SocketMessage::Write(WriteMessage::Power(p)) => {
+                info!("Device is switched {}", if p.power { "on" } else { "off" })
+            }
+            SocketMessage::Unknown(raw) => {
+                warn!("Received unknown message from socket: {}", raw)
             }
         }
         Ok(())
@@ -315,7 +1144,8 @@ impl ConnectionReceiver {
         msg: AuthenticationMessage,
     ) -> Result<(), SendError<RecvSocketMsg>> {
         let recv_msg = RecvSocketMsg::Authentication {
-            status: msg.connection,
+            ok: msg.is_ok(),
+            status: msg.status().to_string(),
         };
         self.tx_recv.send(recv_msg).await
     }
@@ -324,74 +1154,596 @@ impl ConnectionReceiver {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::elli::mock_server::{MockBehavior, MockServer};
+    use crate::render::DEFAULT_PALETTE;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+
+    /// Sink standing in for the socket writer. Rejects the first `failures` messages as
+    /// if its buffer was full and records every message it accepts. With `usize::MAX`
+    /// failures the socket is dead and rejects every message as closed.
+    struct FlakySink {
+        failures: usize,
+        sent: Arc<Mutex<Vec<Message>>>,
+    }
+
+    impl Sink<Message> for FlakySink {
+        type Error = tungstenite::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+            if self.failures == usize::MAX {
+                return Err(tungstenite::Error::ConnectionClosed);
+            }
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(tungstenite::Error::WriteBufferFull(item));
+            }
+            self.sent.lock().unwrap().push(item);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    // receiver handle for connections without a socket to read from
+    fn idle_receiver() -> ReceiverHandle {
+        let (close_tx, close_rx) = oneshot::channel::<()>();
+        let join_handle = tokio::spawn(async move {
+            let _ = close_rx.await;
+        });
+        ReceiverHandle {
+            close_tx,
+            join_handle,
+        }
+    }
+
+    /// Connector handing out one flaky sink per connection attempt. The n-th sink rejects
+    /// `failures[n]` messages. All sinks record into the same list.
+    fn flaky_connector(
+        failures: Vec<usize>,
+        sent: Arc<Mutex<Vec<Message>>>,
+    ) -> Connector<FlakySink> {
+        let attempts = Arc::new(Mutex::new(failures.into_iter()));
+        Box::new(move |_host, _tx_recv| {
+            let failures = attempts.lock().unwrap().next();
+            let sent = sent.clone();
+            Box::pin(async move {
+                let failures = failures.ok_or(tungstenite::Error::ConnectionClosed)?;
+                Ok((FlakySink { failures, sent }, idle_receiver()))
+            })
+        })
+    }
+
+    /// Runs a connection manager on flaky sinks, sends it the command built by `build_cmd`
+    /// and returns the command's response together with all messages that reached a sink.
+    async fn send_command<T>(
+        failures: Vec<usize>,
+        config: ElliConfig,
+        reconnect_policy: ReconnectPolicy,
+        build_cmd: impl FnOnce(oneshot::Sender<Result<T, CommandError>>) -> Command,
+    ) -> (Result<T, CommandError>, Vec<Message>) {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let connector = flaky_connector(failures, sent.clone());
+
+        let (tx_cmd, rx_cmd) = mpsc::channel(1);
+        let (tx_close, rx_close) = oneshot::channel();
+        let (tx_status, _rx_status) = watch::channel(ConnectionStatus::Connected);
+        let manager = ConnectionManager::connect(
+            connector,
+            config,
+            reconnect_policy,
+            rx_cmd,
+            rx_close,
+            tx_status,
+            FrameLog::default(),
+        )
+        .await
+        .expect("Failed to connect");
+        let handle = manager.start_task().await;
+
+        let (res_tx, res_rx) = oneshot::channel();
+        tx_cmd
+            .send(build_cmd(res_tx))
+            .await
+            .expect("Failed to send command");
+        let result = res_rx.await.expect("No response from connection manager");
+
+        tx_close.send(()).unwrap();
+        handle.await.unwrap();
+        let sent = sent.lock().unwrap().drain(..).collect();
+        (result, sent)
+    }
+
+    async fn write_pixel_with_failures(
+        failures: Vec<usize>,
+        write_retries: u32,
+        reconnect_policy: ReconnectPolicy,
+    ) -> (Result<(), CommandError>, usize) {
+        let mut config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").expect("Failed to parse ccc");
+        config.write_retries = write_retries;
+        let (result, sent) = send_command(failures, config, reconnect_policy, |resp| {
+            Command::WritePixel {
+                data: PixelData::from_rgb(255, 0, 0, 0, 0),
+                resp,
+            }
+        })
+        .await;
+        (result, sent.len())
+    }
+
+    fn no_reconnect() -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_retries: 0,
+            ..ReconnectPolicy::default()
+        }
+    }
 
     #[tokio::test]
-    async fn test_connection_setup() {
-        // Initialize logger to see info! messages
-        env_logger::builder()
-            .filter_level(log::LevelFilter::Info)
-            .is_test(true)
-            .init();
+    async fn test_write_pixel_retries_after_failure() {
+        let (result, sent) = write_pixel_with_failures(vec![1], 2, no_reconnect()).await;
+        assert!(result.is_ok());
+        assert_eq!(sent, 1);
+    }
+
+    #[tokio::test]
+    async fn test_write_pixel_fails_when_retries_exhausted() {
+        let (result, sent) = write_pixel_with_failures(vec![3], 2, no_reconnect()).await;
+        assert!(result.is_err());
+        assert_eq!(sent, 0);
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        let busy = tungstenite::Error::WriteBufferFull(Message::text("pixel"));
+        assert!(is_retryable(&busy));
+        assert!(!is_retryable(&tungstenite::Error::ConnectionClosed));
+        assert!(!is_retryable(&tungstenite::Error::AlreadyClosed));
+        let io = std::io::Error::from(std::io::ErrorKind::BrokenPipe);
+        assert!(!is_retryable(&tungstenite::Error::Io(io)));
+    }
+
+    #[tokio::test]
+    async fn test_write_pixel_is_resent_after_reconnect() {
+        let policy = ReconnectPolicy {
+            max_retries: 2,
+            base_delay: Duration::from_millis(1),
+            factor: 2.0,
+        };
+        // the first socket is dead, the second one works
+        let (result, sent) = write_pixel_with_failures(vec![usize::MAX, 0], 0, policy).await;
+        assert!(result.is_ok());
+        assert_eq!(sent, 1);
+    }
+
+    #[tokio::test]
+    async fn test_write_pixel_fails_when_reconnect_fails() {
+        let policy = ReconnectPolicy {
+            max_retries: 2,
+            base_delay: Duration::from_millis(1),
+            factor: 2.0,
+        };
+        // no further connection can be established after the first socket died
+        let (result, sent) = write_pixel_with_failures(vec![usize::MAX], 0, policy).await;
+        assert!(result.is_err());
+        assert_eq!(sent, 0);
+    }
+
+    #[tokio::test]
+    async fn test_write_pixels_in_batches() {
+        let mut config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").expect("Failed to parse ccc");
+        config.pixel_batch_size = 2;
+        let pixels = (0..5)
+            .map(|col| PixelData::from_rgb(255, 0, 0, 0, col))
+            .collect();
+        let (result, sent) = send_command(vec![0], config, no_reconnect(), |resp| {
+            Command::WritePixels { data: pixels, resp }
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(sent.len(), 3);
+
+        let frames: Vec<serde_json::Value> = sent
+            .iter()
+            .map(|msg| from_str(msg.to_text().unwrap()).unwrap())
+            .collect();
+        assert_eq!(frames[0].as_array().unwrap().len(), 2);
+        assert_eq!(frames[0][1]["col"], 1);
+        assert_eq!(frames[0][1]["param"], "pixel");
+        // a single remaining pixel is sent as plain object, like a single pixel write
+        assert_eq!(frames[2]["col"], 4);
+    }
+
+    #[tokio::test]
+    async fn test_write_pixel_on_the_edge() {
+        // a 5x5 matrix, so 4 is the last row and col
+        let config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").expect("Failed to parse ccc");
+        let pixel = PixelData::from_rgb(255, 0, 0, 4, 4);
+        let (result, sent) = send_command(vec![0], config, no_reconnect(), |resp| {
+            Command::WritePixel { data: pixel, resp }
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(sent.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_write_pixel_out_of_range() {
+        for (row, col) in [(5, 0), (0, 5), (usize::MAX, 2)] {
+            let config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").expect("Failed to parse ccc");
+            let pixel = PixelData::from_rgb(255, 0, 0, row, col);
+            let (result, sent) = send_command(vec![0], config, no_reconnect(), |resp| {
+                Command::WritePixel { data: pixel, resp }
+            })
+            .await;
+            assert!(result.is_err());
+            assert!(sent.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_pixels_out_of_range() {
+        // a single pixel outside of the matrix rejects the whole write
+        let config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").expect("Failed to parse ccc");
+        let pixels = (0..6)
+            .map(|col| PixelData::from_rgb(255, 0, 0, 0, col))
+            .collect();
+        let (result, sent) = send_command(vec![0], config, no_reconnect(), |resp| {
+            Command::WritePixels { data: pixels, resp }
+        })
+        .await;
+        let e = result.unwrap_err();
+        assert!(e.to_string().contains("row 0, col 5"));
+        assert!(sent.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_write_pixel_fails_without_ack() {
+        let mut config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").expect("Failed to parse ccc");
+        config.wait_for_ack = true;
+        config.ack_timeout = Duration::from_millis(20);
+        let (result, sent) = send_command(vec![0], config, no_reconnect(), |resp| {
+            Command::WritePixel {
+                data: PixelData::from_rgb(255, 0, 0, 0, 0),
+                resp,
+            }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(sent.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_write_pixels_resolve_on_ack() {
+        let mut config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").expect("Failed to parse ccc");
+        config.wait_for_ack = true;
+        config.pixel_batch_size = 2;
+        // hands out a working sink and keeps the sender of the receiver, to fake the echoes
+        let tx_recv_slot = Arc::new(Mutex::new(None));
+        let slot = tx_recv_slot.clone();
+        let connector: Connector<FlakySink> = Box::new(move |_host, tx_recv| {
+            *slot.lock().unwrap() = Some(tx_recv);
+            let sink = FlakySink {
+                failures: 0,
+                sent: Arc::new(Mutex::new(Vec::new())),
+            };
+            Box::pin(async move { Ok((sink, idle_receiver())) })
+        });
+
+        let (tx_cmd, rx_cmd) = mpsc::channel(1);
+        let (tx_close, rx_close) = oneshot::channel();
+        let (tx_status, _rx_status) = watch::channel(ConnectionStatus::Connected);
+        let manager = ConnectionManager::connect(
+            connector,
+            config,
+            no_reconnect(),
+            rx_cmd,
+            rx_close,
+            tx_status,
+            FrameLog::default(),
+        )
+        .await
+        .expect("Failed to connect");
+        let handle = manager.start_task().await;
+
+        let (res_tx, mut res_rx) = oneshot::channel();
+        let data = (0..2)
+            .map(|col| PixelData::from_rgb(255, 0, 0, 0, col))
+            .collect();
+        tx_cmd
+            .send(Command::WritePixels { data, resp: res_tx })
+            .await
+            .unwrap();
+        // the echoes must not overtake the write
+        sleep(Duration::from_millis(10)).await;
+        let tx_recv = tx_recv_slot.lock().unwrap().take().unwrap();
+        tx_recv
+            .send(RecvSocketMsg::Pixel {
+                pixel: PixelData::from_rgb(255, 0, 0, 0, 0),
+            })
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(10)).await;
+        // only one of the two pixels is confirmed yet
+        assert!(res_rx.try_recv().is_err());
+        tx_recv
+            .send(RecvSocketMsg::Pixel {
+                pixel: PixelData::from_rgb(255, 0, 0, 0, 1),
+            })
+            .await
+            .unwrap();
+        assert!(res_rx.await.unwrap().is_ok());
+
+        tx_close.send(()).unwrap();
+        handle.await.unwrap();
+    }
 
+    #[tokio::test]
+    async fn test_set_name() {
         let config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").expect("Failed to parse ccc");
-        let mut connection = ElliConnection::new(config)
+        let (result, sent) =
+            send_command(vec![0], config, no_reconnect(), |resp| Command::SetName {
+                name: String::from("Kitchen"),
+                resp,
+            })
+            .await;
+        assert!(result.is_ok());
+        let msg: serde_json::Value = from_str(sent[0].to_text().unwrap()).unwrap();
+        assert_eq!(msg["request"], "write");
+        assert_eq!(msg["param"], "name");
+        assert_eq!(msg["name"], "Kitchen");
+        assert_eq!(msg["to"], "3UPU4R9Z");
+    }
+
+    #[tokio::test]
+    async fn test_socket_without_pong_is_dead() {
+        let mut config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").expect("Failed to parse ccc");
+        config.ping_interval = Duration::from_millis(10);
+        config.pong_timeout = Duration::from_millis(25);
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let connector = flaky_connector(vec![0], sent.clone());
+
+        let (_tx_cmd, rx_cmd) = mpsc::channel(1);
+        let (tx_close, rx_close) = oneshot::channel();
+        let (tx_status, mut rx_status) = watch::channel(ConnectionStatus::Connected);
+        let manager = ConnectionManager::connect(
+            connector,
+            config,
+            no_reconnect(),
+            rx_cmd,
+            rx_close,
+            tx_status,
+            FrameLog::default(),
+        )
+        .await
+        .expect("Failed to connect");
+        let handle = manager.start_task().await;
+
+        // the idle receiver never answers the pings
+        rx_status
+            .wait_for(|status| *status == ConnectionStatus::Error)
             .await
-            .expect("Failed to create new socket connection");
+            .unwrap();
+        tx_close.send(()).unwrap();
+        handle.await.unwrap();
+        let sent = sent.lock().unwrap();
+        assert!(!sent.is_empty());
+        assert!(sent.iter().all(|msg| matches!(msg, Message::Ping(_))));
+    }
 
+    // connection to a mock server, authenticated unless the server rejects it
+    async fn mock_connection(server: &MockServer) -> ElliConnection {
+        let config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z")
+            .expect("Failed to parse ccc")
+            .with_host(server.host.clone());
+        let mut connection = ElliConnection::new(config, no_reconnect())
+            .await
+            .expect("Failed to create new socket connection");
         connection
             .authenticate()
             .await
             .map_err(|e| panic!("failed to authenticate with error: {}", e))
             .unwrap();
+        connection
+    }
+
+    #[tokio::test]
+    async fn test_connection_setup() {
+        let server = MockServer::start(MockBehavior::Accept).await;
+        let mut connection = mock_connection(&server).await;
+        assert_eq!(connection.status(), ConnectionStatus::Authenticated);
 
         let width = 5;
         let height = 5;
-        let data = in_colors_data();
         for col in 0..height {
             for row in 0..width {
                 let index = row * width + col;
-                let rgb = data[index];
-                let pixel = PixelData::from_rgb(rgb.0, rgb.1, rgb.2, row, col);
+                let [r, g, b] = DEFAULT_PALETTE[index % DEFAULT_PALETTE.len()];
+                let pixel = PixelData::from_rgb(r, g, b, row, col);
                 connection
                     .write_pixel(pixel)
                     .await
                     .expect("Failed to send pixel");
             }
         }
+        connection
+            .set_name(String::from("Kitchen"))
+            .await
+            .expect("Failed to set name");
 
         connection
             .close()
             .await
             .expect("Error while closing the connection");
+
+        let received = server.received();
+        assert_eq!(received[0]["request"], "authenticate");
+        assert_eq!(received[0]["address"], "3UPU4R9Z");
+        let pixels = received.iter().filter(|m| m["param"] == "pixel").count();
+        assert_eq!(pixels, width * height);
+        assert_eq!(server.name(), "Kitchen");
+    }
+
+    #[tokio::test]
+    async fn test_set_power() {
+        let config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").expect("Failed to parse ccc");
+        let (result, sent) = send_command(vec![0], config, no_reconnect(), |resp| Command::Power {
+            on: false,
+            resp,
+        })
+        .await;
+        assert!(result.is_ok());
+        let msg: serde_json::Value = from_str(sent[0].to_text().unwrap()).unwrap();
+        assert_eq!(msg["request"], "write");
+        assert_eq!(msg["param"], "power");
+        assert_eq!(msg["power"], false);
+    }
+
+    #[tokio::test]
+    async fn test_write_pixels_concurrently() {
+        let server = MockServer::start(MockBehavior::Accept).await;
+        let mut config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z10")
+            .expect("Failed to parse ccc")
+            .with_host(server.host.clone());
+        // every write waits for its echo, which is where overlapping writes pay off
+        config.wait_for_ack = true;
+        let mut connection = ElliConnection::new(config, no_reconnect()).await.unwrap();
+        connection.authenticate().await.unwrap();
+
+        let pixels: Vec<_> = (0..100)
+            .map(|i| PixelData::from_rgb(255, 0, 0, i / 10, i % 10))
+            .collect();
+        connection
+            .write_pixels_concurrently(pixels, 4, Duration::ZERO)
+            .await
+            .unwrap();
+        connection.close().await.unwrap();
+
+        let written = server
+            .received()
+            .iter()
+            .filter(|m| m["param"] == "pixel")
+            .count();
+        assert_eq!(written, 100);
+    }
+
+    #[tokio::test]
+    async fn test_connect_error() {
+        // nothing listens on the port once the listener is gone
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = format!("ws://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z")
+            .expect("Failed to parse ccc")
+            .with_host(host.clone());
+        let Err(e) = ElliConnection::new(config, no_reconnect()).await else {
+            panic!("Connected without a server");
+        };
+        let e = e
+            .downcast_ref::<ConnectError>()
+            .expect("Not a connect error");
+        assert_eq!(e.host, host);
+        assert!(
+            matches!(&e.source, tungstenite::Error::Io(io) if io.kind() == std::io::ErrorKind::ConnectionRefused)
+        );
     }
 
-    fn in_colors_data() -> Vec<(u8, u8, u8)> {
-        vec![
-            (241, 142, 23),  // #f18e17
-            (230, 71, 29),   // #e6471d
-            (223, 10, 56),   // #df0a38
-            (223, 6, 87),    // #df0657
-            (228, 22, 122),  // #e4167a
-            (251, 214, 20),  // #fbd614
-            (241, 142, 23),  // #f18e17
-            (223, 10, 56),   // #df0a38
-            (228, 22, 122),  // #e4167a
-            (216, 6, 129),   // #d80681
-            (174, 202, 32),  // #aeca20
-            (174, 202, 32),  // #aeca20
-            (63, 69, 145),   // #3f4591
-            (136, 31, 126),  // #881f7e
-            (136, 31, 126),  // #881f7e
-            (96, 178, 54),   // #60b236
-            (255, 255, 255), // #ffffff
-            (39, 132, 199),  // #2784c7
-            (49, 54, 135),   // #313687
-            (91, 37, 121),   // #5b2579
-            (32, 155, 108),  // #209b6c
-            (32, 161, 157),  // #20a19d
-            (39, 132, 199),  // #2784c7
-            (29, 97, 172),   // #1d61ac
-            (49, 54, 135),   // #313687
-        ]
+    #[tokio::test]
+    async fn test_frame_log() {
+        let server = MockServer::start(MockBehavior::Accept).await;
+        let config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z")
+            .expect("Failed to parse ccc")
+            .with_host(server.host.clone());
+        let frame_log = FrameLog::default();
+        let mut connection =
+            ElliConnection::with_frame_log(config, no_reconnect(), frame_log.clone())
+                .await
+                .unwrap();
+        connection.authenticate().await.unwrap();
+        connection.close().await.unwrap();
+
+        let frames = frame_log.frames();
+        assert_eq!(frames[0].direction, Direction::Sent);
+        assert!(frames[0].text.contains("authenticate"));
+        assert_eq!(frames[1].direction, Direction::Received);
+        assert_eq!(frames[1].text, r#"{"connection":"ok"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_read_size() {
+        let server = MockServer::start_with_size(MockBehavior::Accept, Some(8)).await;
+        let mut connection = mock_connection(&server).await;
+        assert_eq!(connection.read_size().await.unwrap(), Some(8));
+        connection.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_size_without_answer() {
+        let server = MockServer::start(MockBehavior::Accept).await;
+        let mut connection = mock_connection(&server).await;
+        assert_eq!(connection.read_size().await.unwrap(), None);
+        connection.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_matrix() {
+        let server = MockServer::start(MockBehavior::Accept).await;
+        let mut connection = mock_connection(&server).await;
+        connection
+            .write_pixels(vec![
+                PixelData::from_rgb(255, 0, 0, 0, 0),
+                PixelData::from_rgb(0, 0, 255, 1, 2),
+            ])
+            .await
+            .unwrap();
+        connection
+            .write_pixel(PixelData::from_rgb(0, 255, 0, 0, 0))
+            .await
+            .unwrap();
+
+        // the device reports only the pixels written so far, each with its latest color
+        let mut pixels = connection.read_matrix().await.unwrap();
+        pixels.sort_by_key(|pixel| (pixel.row, pixel.col));
+        let positions: Vec<_> = pixels.iter().map(|pixel| (pixel.row, pixel.col)).collect();
+        assert_eq!(positions, [(0, 0), (1, 2)]);
+        assert_eq!(pixels[0].hue, 85);
+        connection.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connection_auth_failure() {
+        let server = MockServer::start(MockBehavior::RejectAuth).await;
+        let connection = mock_connection(&server).await;
+        assert_eq!(connection.status(), ConnectionStatus::Error);
+        connection.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connection_closed_mid_session() {
+        let server = MockServer::start(MockBehavior::CloseAfterAuth).await;
+        let mut connection = mock_connection(&server).await;
+
+        // without reconnects the socket is given up once the server has closed it
+        let mut status = connection.status_rx.clone();
+        status
+            .wait_for(|status| *status == ConnectionStatus::Error)
+            .await
+            .unwrap();
+        let result = connection
+            .write_pixel(PixelData::from_rgb(255, 0, 0, 0, 0))
+            .await;
+        assert!(result.is_err());
+        connection.close().await.unwrap();
     }
 }
@@ -0,0 +1,73 @@
+//! The last text frames on the socket of a device, to see what a misbehaving device was sent
+//! and answered.
+
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// frames kept per device. Older ones are dropped.
+const FRAME_LOG_SIZE: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LoggedFrame {
+    pub direction: Direction,
+    // milliseconds since the unix epoch
+    pub at: u64,
+    pub text: String,
+}
+
+/// Handle to a bounded log of frames. Clones share the same log, so that the manager and the
+/// receiver of a connection, and the connections of a device across reconnects, write into one.
+#[derive(Clone, Default)]
+pub struct FrameLog {
+    frames: Arc<Mutex<VecDeque<LoggedFrame>>>,
+}
+
+impl FrameLog {
+    pub fn record(&self, direction: Direction, text: &str) {
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        let mut frames = self.frames.lock().unwrap();
+        if frames.len() == FRAME_LOG_SIZE {
+            frames.pop_front();
+        }
+        frames.push_back(LoggedFrame {
+            direction,
+            at,
+            text: text.to_string(),
+        });
+    }
+
+    /// The logged frames, oldest first.
+    pub fn frames(&self) -> Vec<LoggedFrame> {
+        self.frames.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drops_oldest_frames() {
+        let log = FrameLog::default();
+        for i in 0..FRAME_LOG_SIZE + 2 {
+            log.record(Direction::Sent, &i.to_string());
+        }
+        log.clone().record(Direction::Received, "answer");
+
+        let frames = log.frames();
+        assert_eq!(frames.len(), FRAME_LOG_SIZE);
+        assert_eq!(frames[0].text, "3");
+        assert_eq!(frames[FRAME_LOG_SIZE - 1].direction, Direction::Received);
+    }
+}
@@ -14,12 +14,17 @@ pub mod websocket {
     #[derive(Debug, Serialize, Deserialize)]
     pub struct RequestMessage {
         pub(crate) request: String,
+        // consumed as tag when parsing a WriteMessage, so it may be missing
+        #[serde(default)]
         pub(crate) param: String,
         pub(crate) from: String,
         pub(crate) to: String,
     }
 
-    #[derive(Debug, Deserialize, Serialize)]
+    /// A single pixel in the color space of the device. All three channels span the full byte:
+    /// `hue` goes once around the color wheel (0 red, 85 green, 170 blue, back to red at 255),
+    /// `sat` and `val` go from 0 (gray/black) to 255 (full saturation/brightness).
+    #[derive(Debug, Clone, Deserialize, Serialize)]
     pub struct PixelData {
         pub(crate) hue: u8,
         pub(crate) sat: u8,
@@ -40,6 +45,42 @@ pub mod websocket {
             }
         }
 
+        /// Takes the color in the device's own color space, without converting it.
+        pub fn from_hsv(hue: u8, sat: u8, val: u8, row: usize, col: usize) -> Self {
+            Self {
+                hue,
+                sat,
+                val,
+                row,
+                col,
+            }
+        }
+
+        /// Raises `val` to at least `min_val`, so that dark pixels still glow faintly. Pixels
+        /// which are meant to be switched off should be built without calling this.
+        pub fn with_min_value(self, min_val: u8) -> Self {
+            let val = self.val.max(min_val);
+            self.with_value(val)
+        }
+
+        /// Scales `val` down, so that a fully bright pixel ends up at `max_val`.
+        pub fn dimmed(self, max_val: u8) -> Self {
+            self.scale_value(max_val as f32 / 255.0)
+        }
+
+        /// Replaces the brightness of the pixel, keeping its hue and saturation.
+        pub fn with_value(mut self, val: u8) -> Self {
+            self.val = val;
+            self
+        }
+
+        /// Multiplies the brightness of the pixel. Factors above 1 brighten it up to the full
+        /// byte, hue and saturation are kept.
+        pub fn scale_value(self, factor: f32) -> Self {
+            let val = (self.val as f32 * factor.max(0.0)).round().min(255.0) as u8;
+            self.with_value(val)
+        }
+
         fn diff_c(c: f32, v: f32, diff: f32) -> f32 {
             (v - c) / 6.0 / diff + 0.5
         }
@@ -94,16 +135,56 @@ pub mod websocket {
         pub request: RequestMessage,
     }
 
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct NameMessage {
+        pub name: String,
+        #[serde(flatten)]
+        pub request: RequestMessage,
+    }
+
+    /// Switches the matrix on or off. A switched off matrix keeps the pixels written to it.
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct PowerMessage {
+        pub power: bool,
+        #[serde(flatten)]
+        pub request: RequestMessage,
+    }
+
     #[derive(Debug, Deserialize, Serialize)]
     #[serde(untagged, rename_all = "lowercase")]
     pub enum SocketMessage {
         Authentication(AuthenticationMessage),
         Write(WriteMessage),
+        // catches everything the variants above don't match, so that unknown messages can be
+        // logged instead of failing deserialization. Must stay the last variant.
+        Unknown(serde_json::Value),
     }
 
+    /// The answer of the device to an authentication. The status is "ok" on current firmware.
+    /// Richer answers, with the status in an object or more fields next to it, are accepted too.
     #[derive(Debug, Deserialize, Serialize)]
     pub struct AuthenticationMessage {
-        pub(crate) connection: String,
+        pub(crate) connection: serde_json::Value,
+        // e.g. a protocol version. Unused so far.
+        #[serde(flatten)]
+        pub(crate) details: serde_json::Map<String, serde_json::Value>,
+    }
+
+    impl AuthenticationMessage {
+        /// The status, either given as is or as the `status` of an object.
+        pub fn status(&self) -> &str {
+            match &self.connection {
+                serde_json::Value::Object(object) => {
+                    object.get("status").and_then(|status| status.as_str())
+                }
+                connection => connection.as_str(),
+            }
+            .unwrap_or_default()
+        }
+
+        pub fn is_ok(&self) -> bool {
+            self.status().trim().eq_ignore_ascii_case("ok")
+        }
     }
     #[derive(Debug, Deserialize, Serialize)]
     #[serde(tag = "param")]
@@ -112,6 +193,22 @@ pub mod websocket {
         DeviceName(DeviceNameMessage),
         #[serde(rename = "pixel")]
         Pixel(PixelMessage),
+        #[serde(rename = "size")]
+        Size(SizeMessage),
+        #[serde(rename = "power")]
+        Power(PowerStateMessage),
+    }
+
+    /// Echo of a power write.
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct PowerStateMessage {
+        pub power: bool,
+    }
+
+    /// Answer to a size read. The matrix is `size` pixels wide and high.
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct SizeMessage {
+        pub size: u32,
     }
 
     #[derive(Debug, Deserialize, Serialize)]
@@ -121,6 +218,7 @@ pub mod websocket {
         pub to: String,
     }
 
+    #[expect(dead_code, reason = "WriteMessage parses the writes")]
     #[derive(Debug, Deserialize, Serialize)]
     #[serde(untagged)]
     pub enum WriteParams {
@@ -138,3 +236,256 @@ pub mod websocket {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::websocket::*;
+    use serde_json::from_str;
+
+    #[test]
+    fn test_authentication_message() {
+        let msg = from_str::<SocketMessage>(r#"{"connection":"ok"}"#).unwrap();
+        assert!(matches!(msg, SocketMessage::Authentication(a) if a.connection == "ok"));
+    }
+
+    #[test]
+    fn test_richer_authentication_message() {
+        let raw = r#"{"connection":{"status":"OK","protocol":2},"version":"1.2.3"}"#;
+        match from_str::<SocketMessage>(raw).unwrap() {
+            SocketMessage::Authentication(a) => {
+                assert!(a.is_ok());
+                assert_eq!(a.details["version"], "1.2.3");
+            }
+            other => panic!("Expected authentication message, got: {:?}", other),
+        }
+        let failed = from_str::<AuthenticationMessage>(r#"{"connection":"denied"}"#).unwrap();
+        assert!(!failed.is_ok());
+        assert_eq!(failed.status(), "denied");
+    }
+
+    #[test]
+    fn test_min_value_lifts_black_pixel() {
+        let pixel = PixelData::from_rgb(0, 0, 0, 0, 0).with_min_value(20);
+        assert_eq!(pixel.val, 20);
+    }
+
+    #[test]
+    fn test_min_value_keeps_brighter_pixel() {
+        let pixel = PixelData::from_rgb(255, 0, 0, 0, 0).with_min_value(20);
+        assert_eq!(pixel.val, 255);
+    }
+
+    fn hsv(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+        let pixel = PixelData::from_rgb(r, g, b, 0, 0);
+        (pixel.hue, pixel.sat, pixel.val)
+    }
+
+    #[test]
+    fn test_rgb_to_hsv_primary_colors() {
+        assert_eq!(hsv(255, 0, 0), (0, 255, 255));
+        assert_eq!(hsv(0, 255, 0), (85, 255, 255));
+        assert_eq!(hsv(0, 0, 255), (170, 255, 255));
+    }
+
+    #[test]
+    fn test_rgb_to_hsv_secondary_colors() {
+        assert_eq!(hsv(255, 255, 0), (43, 255, 255));
+        assert_eq!(hsv(0, 255, 255), (128, 255, 255));
+        assert_eq!(hsv(255, 0, 255), (213, 255, 255));
+    }
+
+    #[test]
+    fn test_rgb_to_hsv_white_and_black() {
+        assert_eq!(hsv(255, 255, 255), (0, 0, 255));
+        assert_eq!(hsv(0, 0, 0), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_from_hsv_keeps_the_bytes() {
+        let pixel = PixelData::from_hsv(86, 17, 3, 2, 1);
+        assert_eq!(
+            (pixel.hue, pixel.sat, pixel.val, pixel.row, pixel.col),
+            (86, 17, 3, 2, 1)
+        );
+    }
+
+    #[test]
+    fn test_dimmed_scales_value() {
+        let pixel = PixelData::from_rgb(255, 0, 0, 0, 0).dimmed(32);
+        assert_eq!((pixel.hue, pixel.sat, pixel.val), (0, 255, 32));
+        let pixel = PixelData::from_rgb(128, 0, 0, 0, 0).dimmed(32);
+        assert_eq!(pixel.val, 16);
+    }
+
+    #[test]
+    fn test_dimmed_bounds() {
+        let pixel = PixelData::from_rgb(200, 100, 0, 0, 0);
+        assert_eq!(pixel.clone().dimmed(0).val, 0);
+        assert_eq!(pixel.clone().dimmed(255).val, pixel.val);
+    }
+
+    #[test]
+    fn test_scale_value() {
+        let pixel = PixelData::from_rgb(200, 100, 0, 0, 0);
+        let halved = pixel.clone().scale_value(0.5);
+        assert_eq!(halved.val, 100);
+        assert_eq!((halved.hue, halved.sat), (pixel.hue, pixel.sat));
+        // boosting stops at full brightness
+        assert_eq!(pixel.clone().scale_value(2.0).val, 255);
+        assert_eq!(pixel.with_value(7).val, 7);
+    }
+
+    #[test]
+    fn test_pixel_echo_message() {
+        let raw = r#"{"request":"write","param":"pixel","from":"0FBL3E2B","to":"3UPU4R9Z","hue":0,"sat":255,"val":255,"row":1,"col":2}"#;
+        let msg = from_str::<SocketMessage>(raw).unwrap();
+        match msg {
+            SocketMessage::Write(WriteMessage::Pixel(p)) => {
+                assert_eq!((p.pixel.row, p.pixel.col), (1, 2))
+            }
+            other => panic!("Expected pixel message, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_size_message() {
+        let raw =
+            r#"{"request":"write","param":"size","from":"3UPU4R9Z","to":"0FBL3E2B","size":8}"#;
+        let msg = from_str::<SocketMessage>(raw).unwrap();
+        assert!(matches!(msg, SocketMessage::Write(WriteMessage::Size(s)) if s.size == 8));
+    }
+
+    #[test]
+    fn test_power_message() {
+        let raw = r#"{"request":"write","param":"power","from":"0FBL3E2B","to":"3UPU4R9Z","power":false}"#;
+        let msg = from_str::<SocketMessage>(raw).unwrap();
+        assert!(matches!(msg, SocketMessage::Write(WriteMessage::Power(p)) if !p.power));
+    }
+
+    // the keys of a serialized message, sorted. The device wants them all on the top level.
+    fn keys(value: &serde_json::Value) -> Vec<&str> {
+        let mut keys: Vec<_> = value
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(|k| k.as_str())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    fn request(param: &str) -> RequestMessage {
+        RequestMessage {
+            request: String::from("write"),
+            param: param.to_string(),
+            from: String::from("0FBL3E2B"),
+            to: String::from("3UPU4R9Z"),
+        }
+    }
+
+    #[test]
+    fn test_pixel_message_wire_format() {
+        let msg = PixelMessage {
+            pixel: PixelData::from_rgb(255, 0, 0, 3, 4),
+            request: request("pixel"),
+        };
+        let value = serde_json::to_value(&msg).unwrap();
+        assert_eq!(
+            keys(&value),
+            ["col", "from", "hue", "param", "request", "row", "sat", "to", "val"]
+        );
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "request": "write", "param": "pixel", "from": "0FBL3E2B", "to": "3UPU4R9Z",
+                "hue": 0, "sat": 255, "val": 255, "row": 3, "col": 4,
+            })
+        );
+
+        let parsed: PixelMessage = serde_json::from_value(value).unwrap();
+        assert_eq!((parsed.pixel.row, parsed.pixel.col), (3, 4));
+        assert_eq!(
+            (parsed.pixel.hue, parsed.pixel.sat, parsed.pixel.val),
+            (0, 255, 255)
+        );
+        assert_eq!(parsed.request.request, "write");
+        assert_eq!(parsed.request.param, "pixel");
+        assert_eq!(parsed.request.to, "3UPU4R9Z");
+    }
+
+    #[test]
+    fn test_name_message_wire_format() {
+        let msg = NameMessage {
+            name: String::from("Kitchen"),
+            request: request("name"),
+        };
+        let value = serde_json::to_value(&msg).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "request": "write", "param": "name", "from": "0FBL3E2B", "to": "3UPU4R9Z",
+                "name": "Kitchen",
+            })
+        );
+        let parsed: NameMessage = serde_json::from_value(value).unwrap();
+        assert_eq!(parsed.name, "Kitchen");
+        assert_eq!(parsed.request.from, "0FBL3E2B");
+    }
+
+    #[test]
+    fn test_device_name_message_wire_format() {
+        let msg = DeviceNameMessage {
+            request: String::from("write"),
+            name: String::from("Kitchen"),
+            to: String::from("0FBL3E2B"),
+        };
+        let value = serde_json::to_value(&msg).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({"request": "write", "name": "Kitchen", "to": "0FBL3E2B"})
+        );
+        let parsed: DeviceNameMessage = serde_json::from_value(value).unwrap();
+        assert_eq!(
+            (
+                parsed.request.as_str(),
+                parsed.name.as_str(),
+                parsed.to.as_str()
+            ),
+            ("write", "Kitchen", "0FBL3E2B")
+        );
+    }
+
+    #[test]
+    fn test_auth_message_wire_format() {
+        let msg = AuthMessage {
+            request: String::from("authenticate"),
+            param: String::from("ReqL1"),
+            device_type: String::from("TetrisController"),
+            address: String::from("3UPU4R9Z"),
+            from: String::from("0FBL3E2B"),
+        };
+        let value = serde_json::to_value(&msg).unwrap();
+        assert_eq!(
+            keys(&value),
+            ["address", "deviceType", "from", "param", "request"]
+        );
+        assert_eq!(value["deviceType"], "TetrisController");
+
+        let parsed: AuthMessage = serde_json::from_value(value).unwrap();
+        assert_eq!(parsed.request, "authenticate");
+        assert_eq!(parsed.param, "ReqL1");
+        assert_eq!(parsed.device_type, "TetrisController");
+        assert_eq!(parsed.address, "3UPU4R9Z");
+        assert_eq!(parsed.from, "0FBL3E2B");
+    }
+
+    #[test]
+    fn test_unknown_message() {
+        let raw = r#"{"request":"notify","param":"firmware","version":"1.2.3"}"#;
+        let msg = from_str::<SocketMessage>(raw).unwrap();
+        match msg {
+            SocketMessage::Unknown(value) => assert_eq!(value["version"], "1.2.3"),
+            other => panic!("Expected unknown message, got: {:?}", other),
+        }
+    }
+}
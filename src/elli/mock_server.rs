@@ -0,0 +1,167 @@
+//! WebSocket server standing in for the elemon backend, so that the connection can be tested
+//! without a device online.
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::accept_async;
+use tokio_tungstenite::tungstenite::Message;
+
+/// How the server answers an authentication request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MockBehavior {
+    // authenticates every socket and answers writes like a device would
+    Accept,
+    // answers every authentication with a failure
+    RejectAuth,
+    // authenticates the socket and closes it right after
+    CloseAfterAuth,
+}
+
+#[derive(Default)]
+struct MockDevice {
+    name: String,
+    // answered to size reads. Devices with None don't answer.
+    size: Option<u32>,
+    // last written pixel per row and column, reported on pixel reads
+    pixels: HashMap<(u64, u64), Value>,
+    // every message received, batches split into single messages
+    received: Vec<Value>,
+}
+
+pub struct MockServer {
+    pub host: String,
+    device: Arc<Mutex<MockDevice>>,
+    handle: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Listens on a free local port. Every accepted socket is handled independently, so that
+    /// reconnects reach the same device.
+    pub async fn start(behavior: MockBehavior) -> Self {
+        Self::start_with_size(behavior, None).await
+    }
+
+    /// Like `start`, but the device tells its size when asked.
+    pub async fn start_with_size(behavior: MockBehavior, size: Option<u32>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind mock server");
+        let host = format!("ws://{}", listener.local_addr().unwrap());
+        let device = Arc::new(Mutex::new(MockDevice {
+            size,
+            ..MockDevice::default()
+        }));
+
+        let server_device = device.clone();
+        let handle = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, behavior, server_device.clone()));
+            }
+        });
+        Self {
+            host,
+            device,
+            handle,
+        }
+    }
+
+    pub fn received(&self) -> Vec<Value> {
+        self.device.lock().unwrap().received.clone()
+    }
+
+    pub fn name(&self) -> String {
+        self.device.lock().unwrap().name.clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn serve(stream: TcpStream, behavior: MockBehavior, device: Arc<Mutex<MockDevice>>) {
+    let Ok(mut socket) = accept_async(stream).await else {
+        return;
+    };
+    while let Some(Ok(msg)) = socket.next().await {
+        let Message::Text(text) = msg else {
+            continue;
+        };
+        let Ok(value) = serde_json::from_str::<Value>(&text) else {
+            continue;
+        };
+        let messages = match value {
+            Value::Array(messages) => messages,
+            message => vec![message],
+        };
+
+        for message in messages {
+            let answers = answers(&message, behavior, &device);
+            device.lock().unwrap().received.push(message.clone());
+            for answer in answers {
+                if socket
+                    .send(Message::text(answer.to_string()))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            if behavior == MockBehavior::CloseAfterAuth && message["request"] == "authenticate" {
+                let _ = socket.close(None).await;
+                return;
+            }
+        }
+    }
+}
+
+// a pixel read is answered with one message per written pixel, everything else with at most one
+fn answers(message: &Value, behavior: MockBehavior, device: &Mutex<MockDevice>) -> Vec<Value> {
+    if message["request"] == "read" && message["param"] == "pixel" {
+        return device.lock().unwrap().pixels.values().cloned().collect();
+    }
+    answer(message, behavior, device).into_iter().collect()
+}
+
+fn answer(message: &Value, behavior: MockBehavior, device: &Mutex<MockDevice>) -> Option<Value> {
+    match (message["request"].as_str()?, message["param"].as_str()?) {
+        ("authenticate", _) => {
+            let status = match behavior {
+                MockBehavior::RejectAuth => "failed",
+                _ => "ok",
+            };
+            Some(json!({ "connection": status }))
+        }
+        ("write", "name") => {
+            device.lock().unwrap().name = message["name"].as_str()?.to_string();
+            Some(message.clone())
+        }
+        ("read", "name") => Some(json!({
+            "request": "write",
+            "param": "name",
+            "name": device.lock().unwrap().name,
+            "to": message["from"],
+        })),
+        ("read", "size") => Some(json!({
+            "request": "write",
+            "param": "size",
+            "size": device.lock().unwrap().size?,
+            "from": message["to"],
+            "to": message["from"],
+        })),
+        ("write", "power") => Some(message.clone()),
+        // the device echoes every written pixel
+        ("write", "pixel") => {
+            let position = (message["row"].as_u64()?, message["col"].as_u64()?);
+            let mut device = device.lock().unwrap();
+            device.pixels.insert(position, message.clone());
+            Some(message.clone())
+        }
+        _ => None,
+    }
+}
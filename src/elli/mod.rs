@@ -1,9 +1,27 @@
 pub mod elli_connection;
+pub mod frame_log;
 pub mod messages;
+#[cfg(test)]
+pub mod mock_server;
 
-use actix_web::error::ContentTypeError;
-use actix_web::error::ContentTypeError::ParseError;
-use log::info;
+use crate::render::DEFAULT_PALETTE;
+use actix_web::http::StatusCode;
+use actix_web::ResponseError;
+use image::imageops::FilterType;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{info, warn};
+
+// how often a failed pixel write is repeated before the command fails. Kept small, as every
+// retry delays the remaining pixels of the frame.
+const DEFAULT_WRITE_RETRIES: u32 = 2;
+const DEFAULT_HOST: &str = "wss://ws.elemon.de:443";
+const DEFAULT_DEVICE_TYPE: &str = "TetrisController";
+const DEFAULT_AUTH_PARAM: &str = "ReqL1";
+// largest matrix size accepted from a ccc
+pub(crate) const MAX_SIZE: u32 = 32;
 
 #[derive(Clone)]
 pub struct ElliConfig {
@@ -11,6 +29,180 @@ pub struct ElliConfig {
     pub(crate) b_code: String,
     pub(crate) d_code: String,
     pub(crate) size: u32,
+    // largest matrix painted, whatever the ccc or the device tell. Caps the pixels rendered and
+    // sent for every frame.
+    pub(crate) max_size: u32,
+    // what the server authenticates as. The defaults are what the elemon app sends for the
+    // tetris lamps. Other hardware or protocol revisions may expect something else.
+    pub(crate) device_type: String,
+    pub(crate) auth_param: String,
+    pub(crate) write_retries: u32,
+    // lower bound for the brightness of painted pixels. 0 leaves dark pixels black.
+    pub(crate) min_val: u8,
+    // number of pixels sent in one socket frame. Frames of more than one pixel are sent as
+    // JSON array, which not every device may understand, so this defaults to 1.
+    pub(crate) pixel_batch_size: usize,
+    // pause between two pixels when they are sent one by one. Shorter pauses paint faster, but
+    // slow devices drop pixels they can't keep up with. Zero sends as fast as the socket takes
+    // them. Batched frames aren't throttled.
+    pub(crate) pixel_delay: Duration,
+    // single pixel writes which may wait for their confirmation at the same time. This only
+    // pays off with wait_for_ack: sending the next pixel before the previous one is echoed saves
+    // a round trip per pixel, so a frame gets up to this many times faster. Without
+    // wait_for_ack a write is done once it is on the socket, and the pixel delay sets the pace
+    // either way, so more than 1 gains nothing. Devices that can't take pixels that fast drop
+    // some, so this defaults to 1, one write after the other.
+    pub(crate) pixel_concurrency: usize,
+    // commands queued for the socket of a connection. Callers hold the socket while they
+    // paint and wait for every write before the next one, so only up to pixel_concurrency
+    // writes are queued at a time, and the default leaves plenty of room. Once it is full,
+    // commands wait for room.
+    pub(crate) command_queue: usize,
+    // gamma of the album art. Images are converted to linear light with it before downscaling.
+    pub(crate) gamma: f32,
+    // what of the album art the matrix shows
+    pub(crate) mode: RenderMode,
+    // how the album art is resized to the matrix, for the device and the preview alike
+    pub(crate) filter: ResizeFilter,
+    // how images which don't match the matrix' aspect ratio are fitted onto it
+    pub(crate) fit: FitMode,
+    // color of the bars around images fitted with FitMode::Contain
+    pub(crate) fit_background: [u8; 3],
+    // what the matrix shows while playback is paused
+    pub(crate) paused_behavior: PausedBehavior,
+    // reserve the bottom row for a bar showing the progress of the track
+    pub(crate) progress_bar: bool,
+    pub(crate) progress_bar_color: [u8; 3],
+    // scroll the name of a new track across the matrix before showing its album art
+    pub(crate) show_title: bool,
+    pub(crate) title_color: [u8; 3],
+    // shortest time between two polls of spotify. Polls come every few seconds, or shortly
+    // after the end of a track, but never sooner than this.
+    pub(crate) poll_interval: Duration,
+    // multiplies the saturation of the downscaled image, as downscaling washes out the colors.
+    // 1.0 keeps them, around 1.3 to 1.5 makes album art look vivid on the leds.
+    pub(crate) saturation: f32,
+    // stretch the brightness of the downscaled image to the full range, so that flat covers
+    // don't end up as a uniform blob
+    pub(crate) auto_contrast: bool,
+    // dither the downscaled image to the number of levels per color channel the LEDs can
+    // actually distinguish. This is way less than the 256 values sent to the device.
+    pub(crate) dither: bool,
+    pub(crate) dither_levels: u8,
+    // colors the album art is reduced to, after dithering. None keeps all colors.
+    pub(crate) palette: Option<Vec<[u8; 3]>>,
+    // number of frames to blend from one album art into the next. 0 and 1 cut hard.
+    pub(crate) crossfade_steps: u8,
+    // frames per second at most on the matrix and on the preview stream. Frames which come
+    // faster, e.g. of a crossfade or a gif, are coalesced into the latest one. 0 doesn't cap.
+    pub(crate) max_fps: u32,
+    // painted while nothing plays. Without one, the matrix keeps the last album art.
+    pub(crate) idle_image: Option<PathBuf>,
+    // time without playback after which RenderMode::Attract starts its animation
+    pub(crate) attract_after: Duration,
+    // loop the frames of animated album art while the track plays
+    pub(crate) animate: bool,
+    // switch the matrix off when the device is disconnected, instead of leaving the last frame
+    pub(crate) power_off_on_disconnect: bool,
+    // dim the matrix down to black before the socket is closed, instead of leaving the last frame
+    pub(crate) fade_out: bool,
+    // compute the frames, but don't connect to the device. Frames still go to the preview stream.
+    pub(crate) dry_run: bool,
+    // how the matrix is mounted. Frames are rotated clockwise by this and then mirrored
+    // horizontally, if set, before they are sent.
+    pub(crate) rotation: Rotation,
+    pub(crate) mirror: bool,
+    // global brightness of the matrix. 0 switches all pixels off, 255 paints at full brightness.
+    pub(crate) brightness: u8,
+    // resolve writes only once the device echoes the written pixels back. Not every device
+    // echoes, so writes resolve as soon as they are sent by default.
+    pub(crate) wait_for_ack: bool,
+    pub(crate) ack_timeout: Duration,
+    // keepalive of idle sockets. Without a pong within the timeout, the socket is treated as dead.
+    pub(crate) ping_interval: Duration,
+    pub(crate) pong_timeout: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PausedBehavior {
+    // switch all pixels off
+    Clear,
+    // keep the album art, but limit its brightness to the given value
+    Dim(u8),
+    // keep showing the album art as is
+    Leave,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FitMode {
+    // scale each axis separately, distorting the image
+    Stretch,
+    // scale the whole image onto the matrix and fill the rest with the background color
+    Contain,
+    // fill the whole matrix and crop what sticks out, keeping the center
+    Cover,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rotation {
+    None,
+    Clockwise90,
+    Clockwise180,
+    Clockwise270,
+}
+
+/// The color settings tuned on the calibration page, so that the lamp looks like the preview.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Calibration {
+    pub(crate) gamma: f32,
+    pub(crate) brightness: u8,
+    pub(crate) saturation: f32,
+}
+
+impl Calibration {
+    pub fn of(config: &ElliConfig) -> Self {
+        Self {
+            gamma: config.gamma,
+            brightness: config.brightness,
+            saturation: config.saturation,
+        }
+    }
+
+    /// Sets the values on the config. Gamma and saturation are clamped to a usable range, as
+    /// they come straight from a form.
+    pub fn apply(&self, config: &mut ElliConfig) {
+        config.gamma = self.gamma.clamp(0.5, 4.0);
+        config.brightness = self.brightness;
+        config.saturation = self.saturation.clamp(0.0, 3.0);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RenderMode {
+    // the downscaled album art
+    AlbumArt,
+    // every pixel in the dominant color of the album art, e.g. for ambient lighting
+    DominantColor,
+    // the album art, and a slow rainbow sweep once nothing has played for `attract_after`
+    Attract,
+    // the edges of the album art around the border and a dim center, as a bias-light
+    AmbientGlow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeFilter {
+    // crisp pixels on small matrices, smoothing on larger ones
+    Auto,
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
 }
 
 impl ElliConfig {
@@ -19,32 +211,336 @@ impl ElliConfig {
             "new socket config with:{}, {}, {}, {}",
             host, b_code, d_code, size
         );
+        let size = capped_size(size, MAX_SIZE);
         Self {
             host,
             b_code,
             d_code,
             size,
+            max_size: MAX_SIZE,
+            device_type: String::from(DEFAULT_DEVICE_TYPE),
+            auth_param: String::from(DEFAULT_AUTH_PARAM),
+            write_retries: DEFAULT_WRITE_RETRIES,
+            min_val: 0,
+            pixel_batch_size: 1,
+            pixel_delay: Duration::from_millis(5 * size as u64),
+            pixel_concurrency: 1,
+            command_queue: 32,
+            gamma: 2.2,
+            mode: RenderMode::AlbumArt,
+            filter: ResizeFilter::Auto,
+            fit: FitMode::Stretch,
+            fit_background: [0, 0, 0],
+            paused_behavior: PausedBehavior::Dim(32),
+            progress_bar: false,
+            progress_bar_color: [255, 255, 255],
+            show_title: false,
+            title_color: [255, 255, 255],
+            poll_interval: Duration::from_secs(1),
+            saturation: 1.0,
+            auto_contrast: false,
+            dither: false,
+            dither_levels: 16,
+            palette: None,
+            crossfade_steps: 0,
+            max_fps: 15,
+            animate: false,
+            idle_image: None,
+            attract_after: Duration::from_secs(60),
+            power_off_on_disconnect: false,
+            fade_out: false,
+            dry_run: false,
+            rotation: Rotation::None,
+            mirror: false,
+            brightness: 255,
+            wait_for_ack: false,
+            ack_timeout: Duration::from_secs(2),
+            ping_interval: Duration::from_secs(20),
+            pong_timeout: Duration::from_secs(60),
+        }
+    }
+
+    /// Filter of the `image` crate to resize the album art with.
+    pub fn filter_type(&self) -> FilterType {
+        match self.filter {
+            ResizeFilter::Auto if self.size < 10 => FilterType::Nearest,
+            ResizeFilter::Auto => FilterType::Lanczos3,
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+
+    /// Number of rows showing the album art. The others are used for overlays.
+    pub fn art_rows(&self) -> u32 {
+        if self.progress_bar && self.size > 1 {
+            self.size - 1
+        } else {
+            self.size
         }
     }
 
-    pub fn from_ccc(ccc: &str) -> Result<Self, ContentTypeError> {
+    pub fn from_ccc(ccc: &str) -> Result<Self, CccError> {
         let (b_code, d_code, opt_size) = Self::parse_ccc(ccc)?;
-        let host = String::from("wss://ws.elemon.de:443");
         let size = opt_size.unwrap_or(5);
-        Ok(Self::new(host, b_code, d_code, size))
+        let mut config = Self::new(String::from(DEFAULT_HOST), b_code, d_code, size);
+        config.dry_run = env::var("ELLI_DRY_RUN").is_ok_and(|v| v == "1" || v == "true");
+        if let Some(concurrency) = env::var("ELLI_PIXEL_CONCURRENCY")
+            .ok()
+            .and_then(|concurrency| concurrency.parse().ok())
+        {
+            config.pixel_concurrency = concurrency;
+        }
+        config.power_off_on_disconnect =
+            env::var("ELLI_POWER_OFF_ON_DISCONNECT").is_ok_and(|v| v == "1" || v == "true");
+        config.fade_out = env::var("ELLI_FADE_OUT").is_ok_and(|v| v == "1" || v == "true");
+        config.idle_image = env::var("ELLI_IDLE_IMAGE").ok().map(PathBuf::from);
+        if env::var("ELLI_PALETTE").is_ok_and(|v| v == "1" || v == "true") {
+            config.palette = Some(DEFAULT_PALETTE.to_vec());
+        }
+        if let Some(delay) = env::var("ELLI_PIXEL_DELAY_MS")
+            .ok()
+            .and_then(|delay| delay.parse().ok())
+        {
+            config.pixel_delay = Duration::from_millis(delay);
+        }
+        if let Ok(device_type) = env::var("ELLI_DEVICE_TYPE") {
+            config.device_type = device_type;
+        }
+        if let Ok(param) = env::var("ELLI_AUTH_PARAM") {
+            config.auth_param = param;
+        }
+        if let Some(max_size) = env::var("ELLI_MAX_SIZE")
+            .ok()
+            .and_then(|max_size| max_size.parse::<u32>().ok())
+        {
+            config.max_size = max_size.clamp(1, MAX_SIZE);
+            config.set_size(config.size);
+        }
+        if let Some(fps) = env::var("ELLI_MAX_FPS")
+            .ok()
+            .and_then(|fps| fps.parse().ok())
+        {
+            config.max_fps = fps;
+        }
+        // e.g. a local mock server for testing
+        match env::var("ELLI_WS_HOST") {
+            Ok(host) => Ok(config.with_host(host)),
+            Err(_) => Ok(config),
+        }
+    }
+
+    /// Sets the size of the matrix, capped at `max_size`.
+    pub fn set_size(&mut self, size: u32) {
+        self.size = capped_size(size, self.max_size);
+    }
+
+    /// Points the config at another websocket server than the elemon one.
+    pub fn with_host(mut self, host: String) -> Self {
+        self.host = host;
+        self
+    }
+
+    /// Splits a ccc into b_code, d_code and the optional matrix size. A ccc consists of two
+    /// alphanumeric codes of 8 characters each, optionally followed by two digits for the size.
+    pub fn parse_ccc(ccc: &str) -> Result<(String, String, Option<u32>), CccError> {
+        if ccc.len() != 16 && ccc.len() != 18 {
+            return Err(CccError::WrongLength);
+        }
+        let b_code = ccc.get(0..8).ok_or(CccError::NonAlphanumeric)?;
+        let d_code = ccc.get(8..16).ok_or(CccError::NonAlphanumeric)?;
+        let is_code = |code: &str| code.chars().all(|c| c.is_ascii_alphanumeric());
+        if !is_code(b_code) || !is_code(d_code) {
+            return Err(CccError::NonAlphanumeric);
+        }
+        let size = match ccc.get(16..18) {
+            Some(size) => {
+                let size = size.parse().map_err(|_| CccError::InvalidSize)?;
+                if !(1..=MAX_SIZE).contains(&size) {
+                    return Err(CccError::SizeOutOfRange(size));
+                }
+                Some(size)
+            }
+            None => None,
+        };
+        Ok((b_code.to_string(), d_code.to_string(), size))
     }
+}
 
-    fn parse_ccc(ccc: &str) -> Result<(String, String, Option<u32>), ContentTypeError> {
-        let b_code = ccc.get(0..8).ok_or(ParseError)?.to_string();
-        let d_code = ccc.get(8..16).ok_or(ParseError)?.to_string();
-        let size = ccc.get(16..18).and_then(|s| s.parse().ok());
-        Ok((b_code, d_code, size))
+// the size within 1 and max_size. A size above is clamped with a warning, so that a malformed
+// ccc or device can't make the server render and send huge frames.
+fn capped_size(size: u32, max_size: u32) -> u32 {
+    if size > max_size {
+        warn!(
+            "Matrix size {} is above the maximum of {}. Painting {}x{} instead.",
+            size, max_size, max_size, max_size
+        );
+        max_size
+    } else {
+        size.max(1)
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, PartialEq)]
+pub enum CccError {
+    WrongLength,
+    NonAlphanumeric,
+    InvalidSize,
+    SizeOutOfRange(u32),
+}
+
+impl std::fmt::Display for CccError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CccError::WrongLength => write!(f, "Device code must be 16 or 18 characters"),
+            CccError::NonAlphanumeric => {
+                write!(f, "Device code must only contain letters and digits")
+            }
+            CccError::InvalidSize => write!(f, "The last two characters must be the matrix size"),
+            CccError::SizeOutOfRange(size) => {
+                write!(
+                    f,
+                    "Matrix size must be between 1 and {}, got {}",
+                    MAX_SIZE, size
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for CccError {}
+
+// a bad ccc always comes from the url, so it's the client's fault
+impl ResponseError for CccError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+}
+
+/// State of the socket to a device. This is the only status type for devices, used by the
+/// connection actor as well as the update workers and the health endpoint. A device without
+/// an established socket has no status at all.
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub enum ConnectionStatus {
+    // the socket is open, but not authenticated yet
     Connected,
+    // the socket is dead and won't be re-established by the connection itself
     Error,
+    // the device accepted the authentication and can be painted
     Authenticated,
+    // the socket died and is being re-established
+    Reconnecting,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ccc_wrong_length() {
+        assert_eq!(
+            ElliConfig::parse_ccc("0FBL3E2B3UPU"),
+            Err(CccError::WrongLength)
+        );
+        assert_eq!(
+            ElliConfig::parse_ccc("0FBL3E2B3UPU4R9Z1"),
+            Err(CccError::WrongLength)
+        );
+    }
+
+    #[test]
+    fn test_parse_ccc_non_alphanumeric() {
+        assert_eq!(
+            ElliConfig::parse_ccc("0FBL3E2B-UPU4R9Z"),
+            Err(CccError::NonAlphanumeric)
+        );
+    }
+
+    #[test]
+    fn test_size_is_capped() {
+        let mut config = ElliConfig::new(
+            String::from(DEFAULT_HOST),
+            String::from("0FBL3E2B"),
+            String::from("3UPU4R9Z"),
+            99,
+        );
+        assert_eq!(config.size, MAX_SIZE);
+        config.max_size = 8;
+        config.set_size(16);
+        assert_eq!(config.size, 8);
+        config.set_size(0);
+        assert_eq!(config.size, 1);
+    }
+
+    #[test]
+    fn test_filter_type() {
+        let mut config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z05").unwrap();
+        assert_eq!(config.filter_type(), FilterType::Nearest);
+        config.size = 16;
+        assert_eq!(config.filter_type(), FilterType::Lanczos3);
+        config.filter = ResizeFilter::Nearest;
+        assert_eq!(config.filter_type(), FilterType::Nearest);
+    }
+
+    #[test]
+    fn test_calibration_is_clamped() {
+        let mut config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").unwrap();
+        let calibration = Calibration {
+            gamma: 0.0,
+            brightness: 100,
+            saturation: 10.0,
+        };
+        calibration.apply(&mut config);
+        assert_eq!(
+            Calibration::of(&config),
+            Calibration {
+                gamma: 0.5,
+                brightness: 100,
+                saturation: 3.0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_ccc() {
+        let (b_code, d_code, size) = ElliConfig::parse_ccc("0FBL3E2B3UPU4R9Z10").unwrap();
+        assert_eq!(b_code, "0FBL3E2B");
+        assert_eq!(d_code, "3UPU4R9Z");
+        assert_eq!(size, Some(10));
+    }
+
+    #[test]
+    fn test_parse_ccc_without_size() {
+        let (_, _, size) = ElliConfig::parse_ccc("0FBL3E2B3UPU4R9Z").unwrap();
+        assert_eq!(size, None);
+    }
+
+    #[test]
+    fn test_parse_ccc_non_numeric_size() {
+        assert_eq!(
+            ElliConfig::parse_ccc("0FBL3E2B3UPU4R9ZAB"),
+            Err(CccError::InvalidSize)
+        );
+    }
+
+    #[test]
+    fn test_parse_ccc_size_out_of_range() {
+        assert_eq!(
+            ElliConfig::parse_ccc("0FBL3E2B3UPU4R9Z00"),
+            Err(CccError::SizeOutOfRange(0))
+        );
+        assert_eq!(
+            ElliConfig::parse_ccc("0FBL3E2B3UPU4R9Z33"),
+            Err(CccError::SizeOutOfRange(33))
+        );
+    }
+
+    #[test]
+    fn test_from_ccc_error_is_bad_request() {
+        let err = ElliConfig::from_ccc("0FBL3E2B").err().unwrap();
+        assert_eq!(err, CccError::WrongLength);
+        assert_eq!(err.error_response().status(), StatusCode::BAD_REQUEST);
+    }
 }
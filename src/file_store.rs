@@ -0,0 +1,122 @@
+//! Values keyed by ccc in a single JSON file, which is rewritten on every change. The stores
+//! for the Spotify accesses and the device settings keep their values in one.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{self, ErrorKind, Write};
+use std::marker::PhantomData;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub struct JsonFileMap<V> {
+    path: PathBuf,
+    // serializes read-modify-write cycles on the file
+    lock: Mutex<()>,
+    values: PhantomData<V>,
+}
+
+impl<V: Serialize + DeserializeOwned> JsonFileMap<V> {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            lock: Mutex::new(()),
+            values: PhantomData,
+        }
+    }
+
+    pub fn load(&self) -> Result<HashMap<String, V>, Box<dyn Error>> {
+        let _guard = self.lock.lock().unwrap();
+        self.read()
+    }
+
+    pub fn insert(&self, ccc: &str, value: V) -> Result<(), Box<dyn Error>> {
+        let _guard = self.lock.lock().unwrap();
+        let mut values = self.read()?;
+        values.insert(ccc.to_string(), value);
+        self.write(&values)
+    }
+
+    pub fn remove(&self, ccc: &str) -> Result<(), Box<dyn Error>> {
+        let _guard = self.lock.lock().unwrap();
+        let mut values = self.read()?;
+        if values.remove(ccc).is_some() {
+            self.write(&values)?;
+        }
+        Ok(())
+    }
+
+    fn read(&self) -> Result<HashMap<String, V>, Box<dyn Error>> {
+        match fs::read_to_string(&self.path) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            // nothing was stored yet
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(e)?,
+        }
+    }
+
+    fn write(&self, values: &HashMap<String, V>) -> Result<(), Box<dyn Error>> {
+        write_private(&self.path, serde_json::to_string_pretty(values)?.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Replaces the file with the contents, readable by the owner only. The contents go to a
+/// temporary file first, so that a crash while writing leaves the old file in place.
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    // the mode only applies to new files, so a temporary file left by a crash goes first
+    match fs::remove_file(&temp_path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_remove() {
+        let path = std::env::temp_dir().join(format!("elli-map-{}.json", std::process::id()));
+        let map = JsonFileMap::new(path.clone());
+        assert!(map.load().unwrap().is_empty());
+
+        map.insert("ccc1", 1).unwrap();
+        map.insert("ccc2", 2).unwrap();
+        map.insert("ccc1", 3).unwrap();
+        map.remove("ccc2").unwrap();
+        // removing what isn't there is fine
+        map.remove("ccc3").unwrap();
+        let loaded = map.load().unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded, HashMap::from([(String::from("ccc1"), 3)]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_only_the_owner_reads_the_file() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("elli-private-{}.json", std::process::id()));
+        JsonFileMap::new(path.clone()).insert("ccc", 1).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::remove_file(path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -1,32 +1,109 @@
+mod device_settings;
+mod device_token;
 mod elli;
+mod file_store;
+mod matrix;
+mod metrics;
+mod render;
 mod spotify;
 mod state;
 mod templates;
+mod text;
+mod token_store;
 mod update;
 
-use crate::elli::ElliConfig;
-use crate::spotify::SpotifyClient;
+use crate::device_settings::{DeviceSettings, FileSettingsStore};
+use crate::device_token::{ControlledDevice, DeviceCaller, Operator};
+use crate::elli::elli_connection::{ElliConnection, ReconnectPolicy};
+use crate::elli::messages::websocket::PixelData;
+use crate::elli::{Calibration, CccError, ElliConfig};
+use crate::matrix::{ColorMatrix, SinglePixel};
+use crate::render::TestPattern;
+use crate::spotify::{ImageError, SpotifyClient, SpotifyError};
 use crate::state::AppState;
 use crate::templates::{
-    into_response, ColorMatrixModel, ConnectedDeviceTemplate, ConnectedTemplate, IndexTemplate,
-    NoTrackTemplate, PlayingModel,
+    into_response, CalibrateTemplate, ColorMatrixModel, ConnectedDeviceTemplate, ConnectedTemplate,
+    ErrorTemplate, IndexTemplate, LivePlayingTemplate, NoTrackTemplate, PlaybackModel,
+    PlayingModel,
 };
-use crate::update::ElliUpdate;
+use crate::token_store::FileTokenStore;
+use crate::update::{send_frame, ElliUpdate, FrameLimiter, LampUnreachable, MatrixFrame};
 use actix_files as fs;
 use actix_session::storage::CookieSessionStore;
 use actix_session::{Session, SessionMiddleware};
 use actix_web::cookie::{Key, SameSite};
-use actix_web::error::ErrorInternalServerError;
-use actix_web::{get, web, App, HttpResponse, HttpServer};
-use env_logger::Env;
-use image::imageops::FilterType;
-use image::GenericImageView;
-use log::info;
+use actix_web::error::{ErrorForbidden, ErrorInternalServerError};
+use actix_web::http::{header, StatusCode};
+use actix_web::{get, post, web, App, HttpRequest, HttpResponse, HttpServer};
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use futures_util::future::join_all;
+use futures_util::stream;
+use image::{DynamicImage, ImageFormat};
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::env;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::time::interval;
+use tracing::{debug, info, warn};
+use tracing_subscriber::EnvFilter;
+use url::Url;
 
+// how often spotify accesses which can't be refreshed anymore and abandoned logins are removed
+const TOKEN_SWEEP_INTERVAL: Duration = Duration::from_secs(600);
+// the app registered for the hosted instance. Self-hosted instances set SPOTIFY_CLIENT_ID.
+const DEFAULT_SPOTIFY_CLIENT_ID: &str = "38f14e6cbed74638857280d0165bc93a";
+
+#[derive(Deserialize)]
+struct IndexParams {
+    // submitted by the form on the page
+    device_code: Option<String>,
+}
+
+// the form to enter a device code. Submitting it comes back here and moves on to the device.
 #[get("/")]
-async fn index() -> Result<HttpResponse, actix_web::Error> {
-    Ok(into_response(IndexTemplate {}))
+async fn index(params: web::Query<IndexParams>) -> Result<HttpResponse, actix_web::Error> {
+    let Some(ccc) = params.device_code.as_deref().map(str::trim) else {
+        return into_response(IndexTemplate {});
+    };
+    if let Err(e) = ElliConfig::parse_ccc(ccc) {
+        return invalid_ccc(e);
+    }
+    Ok(HttpResponse::Found()
+        .append_header(("Location", format!("/device/{ccc}")))
+        .finish())
+}
+
+fn invalid_ccc(e: CccError) -> Result<HttpResponse, actix_web::Error> {
+    let mut response = into_response(ErrorTemplate {
+        error: String::from("Invalid device code"),
+        description: e.to_string(),
+    })?;
+    *response.status_mut() = StatusCode::BAD_REQUEST;
+    Ok(response)
+}
+
+#[get("/healthz")]
+async fn healthz(app_state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(app_state.stats())
+}
+
+#[get("/metrics")]
+async fn prometheus_metrics(app_state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(app_state.metrics().render())
+}
+
+// read-only and without a session, so that dashboards can poll it. The ccc would let anybody
+// drive the lamps, so only operators see the list.
+#[get("/api/devices")]
+async fn api_devices(_operator: Operator, app_state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(app_state.devices())
 }
 
 #[get("/device/{ccc}")]
@@ -34,109 +111,864 @@ async fn device(
     ccc: web::Path<String>,
     session: Session,
 ) -> Result<HttpResponse, actix_web::Error> {
-    // TODO this should first check whether we can parse the device code and return the index with an error if not.
+    if let Err(e) = ElliConfig::parse_ccc(&ccc) {
+        return invalid_ccc(e);
+    }
 
     session
         .insert("ccc", ccc.as_str().to_string())
         .map_err(ErrorInternalServerError)?;
-    Ok(into_response(ConnectedDeviceTemplate {
+    into_response(ConnectedDeviceTemplate {
         ccc: ccc.as_str().to_string(),
-    }))
+    })
+}
+
+#[derive(Deserialize)]
+struct ConnectedParams {
+    // take the device over from the session which connected it
+    #[serde(default)]
+    force: bool,
+}
+
+// what /device/{ccc}/connected found, before it is formatted for the client
+enum Connected {
+    // no spotify access for the device, or one spotify has revoked
+    NotAuthenticated,
+    // another session owns the device and the request didn't ask to take it over
+    InUse,
+    // the device was taken over from another session, which has to connect spotify again
+    TakenOver,
+    // spotify is linked, but the lamp didn't connect and authenticate
+    LampOffline(String),
+    NoTrack,
+    Playing(Box<ConnectedTemplate>),
 }
 
 #[get("/device/{ccc}/connected")]
 async fn connected(
+    req: HttpRequest,
     ccc: web::Path<String>,
+    params: web::Query<ConnectedParams>,
+    caller: DeviceCaller,
     app_state: web::Data<AppState>,
     spotify_client: web::Data<SpotifyClient>,
 ) -> Result<HttpResponse, actix_web::Error> {
     info!("Route: /device/{ccc}/connected");
+    let outcome = connect_device(&ccc, params.force, caller, app_state, spotify_client).await?;
+    connected_response(outcome, &ccc, wants_json(&req))
+}
 
-    // redirect to the device page if not connected
-    if let None = app_state.get_access(ccc.as_str()) {
-        let response = HttpResponse::Found()
-            .append_header(("Location", format!("/device/{ccc}")))
-            .finish();
-        return Ok(response);
+async fn connect_device(
+    ccc: &str,
+    force: bool,
+    caller: DeviceCaller,
+    app_state: web::Data<AppState>,
+    spotify_client: web::Data<SpotifyClient>,
+) -> Result<Connected, actix_web::Error> {
+    if app_state.get_access(ccc).is_none() {
+        return Ok(Connected::NotAuthenticated);
     }
 
-    let update = ElliUpdate::new(ccc.clone(), app_state.clone(), spotify_client.clone()).await?;
-    app_state.insert_elli_update(&ccc, update);
+    // a token was issued to the owner, so it controls the device like the owner's session
+    if let DeviceCaller::Session(session_id) = caller {
+        if app_state.is_owned_by_other(ccc, &session_id) {
+            if !force {
+                return Ok(Connected::InUse);
+            }
+            // stop the other account driving the device, so that this session can connect its
+            // own
+            info!("Session takes over {}", ccc);
+            let device_guard = app_state.lock_device(ccc).await;
+            if let Some(update) = app_state.remove_elli_update(ccc) {
+                update.close().await?;
+            }
+            drop(device_guard);
+            app_state.remove_access(ccc).await;
+            return Ok(Connected::TakenOver);
+        }
+        // e.g. an access restored from the token store
+        app_state.set_owner(ccc, session_id);
+    }
 
-    let config = ElliConfig::from_ccc(&ccc)?;
-    let elli_size = config.size;
+    // another request for the device might be starting its update right now
+    let device_guard = app_state.lock_device(ccc).await;
+    let config = match app_state.update_config(ccc) {
+        // e.g. a second tab, which shows the running update
+        Some(config) => config,
+        None => {
+            let update =
+                match ElliUpdate::new(ccc.to_string(), app_state.clone(), spotify_client.clone())
+                    .await
+                {
+                    Ok(update) => update,
+                    Err(e) if e.is::<LampUnreachable>() => {
+                        warn!("{}", e);
+                        return Ok(Connected::LampOffline(e.to_string()));
+                    }
+                    Err(e) => return Err(ErrorInternalServerError(e.to_string())),
+                };
+            let config = update.config();
+            if let Some(replaced) = app_state.insert_elli_update(ccc, update) {
+                replaced.close().await?;
+            }
+            config
+        }
+    };
+    drop(device_guard);
 
     // fetch currently playing status from spotify
-    let playing_model = if let Some(current_track) = spotify_client
-        .get_current_track(ccc.as_str(), app_state)
-        .await
-        .map_err(ErrorInternalServerError)?
-    {
-        PlayingModel::from(current_track)
+    let playback = match spotify_client.get_playback_state(ccc, app_state).await {
+        Ok(playback) => playback,
+        Err(e) => match e.downcast_ref::<SpotifyError>() {
+            // the tokens are gone or lack a scope, so the user has to connect spotify again
+            Some(SpotifyError::TokenRejected(_))
+            | Some(SpotifyError::Unauthorized)
+            | Some(SpotifyError::MissingScope) => return Ok(Connected::NotAuthenticated),
+            // logging in again doesn't help with these
+            Some(SpotifyError::PremiumRequired)
+            | Some(SpotifyError::UserNotRegistered)
+            | Some(SpotifyError::Forbidden(_)) => return Err(ErrorForbidden(e.to_string())),
+            _ => return Err(ErrorInternalServerError(e.to_string())),
+        },
+    };
+    let (playing_model, playback_model) = if let Some(playback) = playback {
+        let playback_model = PlaybackModel::from(&playback);
+        (PlayingModel::from(playback.current), playback_model)
     } else {
-        let response = into_response(NoTrackTemplate {
-            ccc: ccc.as_str().to_string(),
-        });
-        return Ok(response);
+        return Ok(Connected::NoTrack);
     };
 
-    // if something is playing, fetch the album art
-    let image = spotify_client.get_image(&playing_model.image_url).await?;
-    let filter_type = if elli_size < 10 {
-        FilterType::Nearest
+    let image = album_art(&spotify_client, &playing_model, &config).await?;
+    let downsized_image = render::frame(&image, &config, config.filter_type());
+    let colors = render::hex_colors(&downsized_image, &config, playing_model.progress());
+
+    Ok(Connected::Playing(Box::new(ConnectedTemplate {
+        player_status: playing_model,
+        playback: playback_model,
+        matrix_model: ColorMatrixModel {
+            size: config.size,
+            colors,
+        },
+    })))
+}
+
+// the album art of what is playing, or the placeholder if there is none or it can't be decoded
+async fn album_art(
+    spotify_client: &SpotifyClient,
+    playing_model: &PlayingModel,
+    config: &ElliConfig,
+) -> Result<DynamicImage, actix_web::Error> {
+    let image = if playing_model.has_image() {
+        spotify_client.get_image(&playing_model.image_url).await
     } else {
-        FilterType::Lanczos3
+        render::placeholder(config).map_err(Into::into)
     };
+    match image {
+        Ok(image) => Ok(image),
+        // like the worker, which paints the placeholder for it
+        Err(e) if ImageError::is_undecodable(e.as_ref()) => {
+            warn!("{}. Showing the placeholder instead.", e);
+            render::placeholder(config).map_err(ErrorInternalServerError)
+        }
+        Err(e) => Err(ErrorInternalServerError(e.to_string())),
+    }
+}
 
-    let downsized_image = image.resize(elli_size, elli_size, filter_type);
-    let colors = downsized_image
-        .pixels()
-        .map(|(_, _, rgba)| format!("#{:02x}{:02x}{:02x}", rgba[0], rgba[1], rgba[2]))
+// like /connected, but the page follows the lamp instead of showing a snapshot
+#[get("/device/{ccc}/live")]
+async fn live(
+    ccc: web::Path<String>,
+    caller: DeviceCaller,
+    app_state: web::Data<AppState>,
+    spotify_client: web::Data<SpotifyClient>,
+) -> Result<HttpResponse, actix_web::Error> {
+    info!("Route: /device/{ccc}/live");
+    match connect_device(&ccc, false, caller, app_state, spotify_client).await? {
+        Connected::Playing(template) => {
+            let template = *template;
+            into_response(LivePlayingTemplate {
+                ccc: ccc.to_string(),
+                player_status: template.player_status,
+                matrix_model: template.matrix_model,
+                stream_url: format!("/device/{ccc}/stream"),
+                status_url: format!("/device/{ccc}/status"),
+            })
+        }
+        outcome => connected_response(outcome, &ccc, false),
+    }
+}
+
+// browsers are sent back to the device page, API clients get a status they can act on
+fn connected_response(
+    outcome: Connected,
+    ccc: &str,
+    json: bool,
+) -> Result<HttpResponse, actix_web::Error> {
+    match outcome {
+        Connected::NotAuthenticated if json => {
+            Ok(HttpResponse::NotFound().json(serde_json::json!({ "error": "not_authenticated" })))
+        }
+        Connected::NotAuthenticated | Connected::TakenOver => Ok(HttpResponse::Found()
+            .append_header(("Location", format!("/device/{ccc}")))
+            .finish()),
+        Connected::InUse => {
+            let mut response = into_response(ErrorTemplate {
+                error: String::from("Device in use"),
+                description: format!(
+                    "Another session has connected this device to its Spotify account. \
+                     Open /device/{}/connected?force=true to take it over.",
+                    ccc
+                ),
+            })?;
+            *response.status_mut() = StatusCode::CONFLICT;
+            Ok(response)
+        }
+        Connected::LampOffline(reason) if json => Ok(HttpResponse::ServiceUnavailable()
+            .json(serde_json::json!({ "error": "lamp_offline", "reason": reason }))),
+        Connected::LampOffline(reason) => {
+            let mut response = into_response(ErrorTemplate {
+                error: String::from("Lamp unreachable"),
+                description: format!(
+                    "Spotify is linked, but the lamp didn't answer. Check that it is switched on \
+                     and online, then reload this page. ({})",
+                    reason
+                ),
+            })?;
+            *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            Ok(response)
+        }
+        Connected::NoTrack => into_response(NoTrackTemplate {
+            ccc: ccc.to_string(),
+        }),
+        Connected::Playing(template) => into_response(*template),
+    }
+}
+
+// whether the client asked for JSON rather than a page
+fn wants_json(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"))
+}
+
+#[post("/device/{ccc}/matrix")]
+async fn push_matrix(
+    ControlledDevice(ccc): ControlledDevice,
+    body: web::Json<ColorMatrix>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    info!("Route: /device/{ccc}/matrix");
+    let config = device_config(&app_state, &ccc)?;
+    let pixels = match body.to_pixels(config.size) {
+        Ok(pixels) => adjusted(pixels, &config),
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+    };
+    paint_direct(&app_state, &ccc, pixels, config).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// lights a single pixel and leaves the others as they are
+#[post("/device/{ccc}/pixel")]
+async fn push_pixel(
+    ControlledDevice(ccc): ControlledDevice,
+    body: web::Json<SinglePixel>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    info!("Route: /device/{ccc}/pixel");
+    let config = device_config(&app_state, &ccc)?;
+    let pixel = match body.to_pixel(config.size) {
+        Ok(pixel) => render::orient(pixel, &config),
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+    };
+    paint_direct(&app_state, &ccc, vec![pixel], config).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// switches all pixels off. A running update paints again once the next track plays.
+#[post("/device/{ccc}/clear")]
+async fn clear_matrix(
+    ControlledDevice(ccc): ControlledDevice,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    info!("Route: /device/{ccc}/clear");
+    let config = device_config(&app_state, &ccc)?;
+    let pixels = render::blank_pixels(&config);
+    paint_direct(&app_state, &ccc, pixels, config).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// the config of the running update, which knows the size the device reported. Without one,
+// the config an update would start with.
+fn device_config(app_state: &AppState, ccc: &str) -> Result<ElliConfig, actix_web::Error> {
+    match app_state.update_config(ccc) {
+        Some(config) => Ok(config),
+        None => {
+            let mut config = ElliConfig::from_ccc(ccc)?;
+            app_state.settings(ccc).apply(&mut config);
+            Ok(config)
+        }
+    }
+}
+
+// paints the pixels on the socket of the running update, or on a one-off socket without one
+async fn paint_direct(
+    app_state: &AppState,
+    ccc: &str,
+    pixels: Vec<PixelData>,
+    config: ElliConfig,
+) -> Result<(), actix_web::Error> {
+    if config.dry_run {
+        info!("Dry run. Not painting {} pixels.", pixels.len());
+        return Ok(());
+    }
+    if let Some(socket) = app_state.socket(ccc) {
+        let written = socket
+            .write_pixels(pixels.clone(), &config)
+            .await
+            .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+        if written {
+            return Ok(());
+        }
+    }
+    let mut connection = open_connection(config.clone()).await?;
+    let result = send_frame(&mut connection, pixels, &config).await;
+    close_connection(connection).await?;
+    result.map_err(|e| ErrorInternalServerError(e.to_string()))
+}
+
+// paints one image onto every device with a running update, each in its own size. The body
+// is the image file, e.g. a png. Only operators may paint on lamps they don't control.
+#[post("/api/matrix/broadcast")]
+async fn broadcast_matrix(
+    _operator: Operator,
+    body: web::Bytes,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    info!("Route: /api/matrix/broadcast");
+    let image = match image::load_from_memory(&body) {
+        Ok(image) => image,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+    };
+
+    let configs: Vec<_> = app_state
+        .all_device_cccs()
+        .into_iter()
+        .filter_map(|ccc| app_state.update_config(&ccc).map(|config| (ccc, config)))
+        .collect();
+    let paints = configs.into_iter().map(|(ccc, config)| {
+        let image = &image;
+        let app_state = &app_state;
+        async move {
+            let result = paint_image(app_state, &ccc, image, config).await;
+            (ccc, result)
+        }
+    });
+    // devices which fail don't keep the others from being painted
+    let results: HashMap<String, String> = join_all(paints)
+        .await
+        .into_iter()
+        .map(|(ccc, result)| match result {
+            Ok(()) => (ccc, String::from("ok")),
+            Err(e) => {
+                warn!("Broadcast to {} failed: {}", ccc, e);
+                (ccc, e.to_string())
+            }
+        })
         .collect();
+    Ok(HttpResponse::Ok().json(results))
+}
 
-    let template = ConnectedTemplate {
-        player_status: playing_model,
+// renders the image for the device like its update does and paints it on the socket of the
+// update, so that it doesn't race the worker on a socket of its own
+async fn paint_image(
+    app_state: &AppState,
+    ccc: &str,
+    image: &DynamicImage,
+    config: ElliConfig,
+) -> Result<(), actix_web::Error> {
+    let frame = render::frame(image, &config, config.filter_type());
+    let pixels = adjusted(render::to_pixels(&frame, &config), &config);
+    paint_direct(app_state, ccc, pixels, config).await
+}
+
+// the pixels with the brightness and orientation of the config
+fn adjusted(pixels: Vec<PixelData>, config: &ElliConfig) -> Vec<PixelData> {
+    pixels
+        .into_iter()
+        .map(|p| render::orient(p.dimmed(config.brightness), config))
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct CalibrateParams {
+    pattern: Option<TestPattern>,
+    // values to try. Missing ones are taken from the saved settings.
+    gamma: Option<f32>,
+    brightness: Option<u8>,
+    saturation: Option<f32>,
+}
+
+// paints a test pattern on the lamp and shows it next to the form to tune the colors
+#[get("/device/{ccc}/calibrate")]
+async fn calibrate(
+    ControlledDevice(ccc): ControlledDevice,
+    params: web::Query<CalibrateParams>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    info!("Route: /device/{ccc}/calibrate");
+    let mut config = device_config(&app_state, &ccc)?;
+    let saved = Calibration::of(&config);
+    Calibration {
+        gamma: params.gamma.unwrap_or(saved.gamma),
+        brightness: params.brightness.unwrap_or(saved.brightness),
+        saturation: params.saturation.unwrap_or(saved.saturation),
+    }
+    .apply(&mut config);
+    let calibration = Calibration::of(&config);
+
+    let pattern = params.pattern.unwrap_or(TestPattern::White);
+    let grid = render::test_pattern(pattern, &config);
+    // the preview is dimmed like the lamp
+    let dimmed: Vec<_> = grid
+        .iter()
+        .map(|color| color.map(|c| (c as u32 * calibration.brightness as u32 / 255) as u8))
+        .collect();
+    let template = CalibrateTemplate {
+        ccc: ccc.to_string(),
+        pattern: pattern.name(),
+        patterns: TestPattern::ALL.iter().map(TestPattern::name).collect(),
+        calibration,
+        saved,
         matrix_model: ColorMatrixModel {
-            size: elli_size,
-            colors,
+            size: config.size,
+            colors: render::to_hex(&dimmed),
         },
     };
-    Ok(into_response(template))
+    let pixels = adjusted(render::grid_pixels(&grid, &config), &config);
+    paint_direct(&app_state, &ccc, pixels, config).await?;
+    into_response(template)
+}
+
+#[post("/device/{ccc}/calibrate")]
+async fn save_calibration(
+    ControlledDevice(ccc): ControlledDevice,
+    form: web::Form<Calibration>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    info!("Route: /device/{ccc}/calibrate");
+    // the running update paints the album art over the test pattern with the new colors
+    app_state
+        .update_settings(&ccc, DeviceSettings::from(form.into_inner()))
+        .await;
+    Ok(HttpResponse::Found()
+        .append_header(("Location", format!("/device/{ccc}/connected")))
+        .finish())
+}
+
+// every setting the device runs with, saved or not
+#[get("/device/{ccc}/settings")]
+async fn settings(
+    ControlledDevice(ccc): ControlledDevice,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    info!("Route: /device/{ccc}/settings");
+    let config = device_config(&app_state, &ccc)?;
+    Ok(HttpResponse::Ok().json(DeviceSettings::of(&config)))
+}
+
+// saves the settings in the body and applies them to the running update. Settings left out
+// stay as they are.
+#[post("/device/{ccc}/settings")]
+async fn save_settings(
+    ControlledDevice(ccc): ControlledDevice,
+    body: web::Json<DeviceSettings>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    info!("Route: /device/{ccc}/settings");
+    Ok(HttpResponse::Ok().json(app_state.update_settings(&ccc, body.into_inner()).await))
+}
+
+#[get("/device/{ccc}/rename/{name}")]
+async fn rename(
+    ControlledDevice(ccc): ControlledDevice,
+    path: web::Path<(String, String)>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (_, name) = path.into_inner();
+    info!("Route: /device/{ccc}/rename/{name}");
+    let config = device_config(&app_state, &ccc)?;
+
+    // the running update's socket, so that the device doesn't get a second one
+    if let Some(socket) = app_state.socket(&ccc) {
+        let renamed = socket
+            .set_name(name.clone())
+            .await
+            .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+        if renamed {
+            return Ok(HttpResponse::NoContent().finish());
+        }
+    }
+    let mut connection = open_connection(config).await?;
+    let result = connection.set_name(name).await;
+    close_connection(connection).await?;
+    result.map_err(|e| ErrorInternalServerError(e.to_string()))?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/device/{ccc}/matrix")]
+async fn read_matrix(
+    ControlledDevice(ccc): ControlledDevice,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    info!("Route: /device/{ccc}/matrix");
+    // the running update knows the size the device reported
+    let config = device_config(&app_state, &ccc)?;
+
+    if let Some(socket) = app_state.socket(&ccc) {
+        let pixels = socket
+            .read_matrix()
+            .await
+            .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+        if let Some(pixels) = pixels {
+            return Ok(HttpResponse::Ok().json(pixels));
+        }
+    }
+    let mut connection = open_connection(config).await?;
+    let result = connection.read_matrix().await;
+    close_connection(connection).await?;
+    let pixels = result.map_err(|e| ErrorInternalServerError(e.to_string()))?;
+
+    Ok(HttpResponse::Ok().json(pixels))
+}
+
+// what the update would paint for the current track, scaled up with a grid between the pixels
+#[get("/device/{ccc}/preview.png")]
+async fn preview_png(
+    ControlledDevice(ccc): ControlledDevice,
+    app_state: web::Data<AppState>,
+    spotify_client: web::Data<SpotifyClient>,
+) -> Result<HttpResponse, actix_web::Error> {
+    info!("Route: /device/{ccc}/preview.png");
+    if app_state.get_access(&ccc).is_none() {
+        return Ok(HttpResponse::NotFound().body("Spotify is not connected for this device"));
+    }
+    let config = device_config(&app_state, &ccc)?;
+    let playback = spotify_client
+        .get_playback_state(&ccc, app_state)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+    let Some(playback) = playback else {
+        return Ok(HttpResponse::NotFound().body("Nothing is playing"));
+    };
+    let playing_model = PlayingModel::from(playback.current);
+
+    let image = album_art(&spotify_client, &playing_model, &config).await?;
+    let frame = render::frame(&image, &config, config.filter_type());
+    let grid = render::rgb_grid(&frame, &config, playing_model.progress());
+    let mut png = Vec::new();
+    render::preview_image(&grid, config.size)
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().content_type("image/png").body(png))
+}
+
+// one-off socket for routes which talk to the device directly
+async fn open_connection(config: ElliConfig) -> Result<ElliConnection, actix_web::Error> {
+    let d_code = config.d_code.clone();
+    let mut connection = ElliConnection::new(config, ReconnectPolicy::default())
+        .await
+        .map_err(|e| {
+            warn!("Device {}: {}", d_code, e);
+            ErrorInternalServerError(e.to_string())
+        })?;
+    connection
+        .authenticate()
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+    Ok(connection)
+}
+
+async fn close_connection(connection: ElliConnection) -> Result<(), actix_web::Error> {
+    connection
+        .close()
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))
+}
+
+#[get("/device/{ccc}/brightness/{level}")]
+async fn brightness(
+    ControlledDevice(ccc): ControlledDevice,
+    path: web::Path<(String, u8)>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (_, level) = path.into_inner();
+    info!("Route: /device/{ccc}/brightness/{level}");
+    // kept like the other settings, so that the brightness survives a restart
+    let changes = DeviceSettings {
+        brightness: Some(level),
+        ..DeviceSettings::default()
+    };
+    app_state.update_settings(&ccc, changes).await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/device/{ccc}/refresh")]
+async fn refresh(
+    ControlledDevice(ccc): ControlledDevice,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    info!("Route: /device/{ccc}/refresh");
+    if app_state.refresh(&ccc) {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().body(format!("No running update for device {}", ccc))
+    }
+}
+
+// the bearer token for scripts, handed out to the session owning the device
+#[get("/device/{ccc}/token")]
+async fn issue_token(
+    ccc: web::Path<String>,
+    session: Session,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    info!("Route: /device/{ccc}/token");
+    let session_id = spotify::session_id(&session)?;
+    // the token is bound to the owner, so that it stops working once the session loses the device
+    if app_state.get_access(&ccc).is_none()
+        || app_state.owner(&ccc).as_deref() != Some(session_id.as_str())
+    {
+        return Ok(HttpResponse::Forbidden().body("Connect the device to spotify first"));
+    }
+    let token = app_state.device_tokens().issue(&ccc, &session_id);
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "token": token })))
+}
+
+// raw protocol traffic of the running update, to debug devices
+#[get("/device/{ccc}/debug/frames")]
+async fn debug_frames(
+    ControlledDevice(ccc): ControlledDevice,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    info!("Route: /device/{ccc}/debug/frames");
+    match app_state.frame_log(&ccc) {
+        Some(frames) => HttpResponse::Ok().json(frames),
+        None => HttpResponse::NotFound().body(format!("No running update for device {}", ccc)),
+    }
+}
+
+// polled by the device page for its connection indicator
+#[get("/device/{ccc}/status")]
+async fn device_status(ccc: web::Path<String>, app_state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(app_state.device_status(&ccc))
+}
+
+#[get("/device/{ccc}/stream")]
+async fn stream_frames(ccc: web::Path<String>, app_state: web::Data<AppState>) -> HttpResponse {
+    info!("Route: /device/{ccc}/stream");
+    let Some(frames) = app_state.subscribe_frames(&ccc) else {
+        return HttpResponse::NotFound().body(format!("No running update for device {}", ccc));
+    };
+
+    let max_fps = app_state
+        .update_config(&ccc)
+        .map_or(0, |config| config.max_fps);
+
+    // every frame painted by the worker is sent as one server-sent event, at most max_fps of
+    // them per second. Frames which come in faster are coalesced into the latest one.
+    let limiter = FrameLimiter::default();
+    let events = stream::unfold((frames, limiter), move |(mut frames, limiter)| async move {
+        loop {
+            match frames.recv().await {
+                Ok(frame) => {
+                    limiter.wait(max_fps).await;
+                    let frame = latest_frame(&mut frames, frame);
+                    let event = web::Bytes::from(frame.to_event());
+                    return Some((Ok::<_, actix_web::Error>(event), (frames, limiter)));
+                }
+                // a slow client only misses some frames. It catches up with the next one.
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events)
+}
+
+// the newest of the frames waiting in the subscription, or the given one if none are
+fn latest_frame(
+    frames: &mut broadcast::Receiver<MatrixFrame>,
+    mut frame: MatrixFrame,
+) -> MatrixFrame {
+    loop {
+        match frames.try_recv() {
+            Ok(newer) => frame = newer,
+            Err(TryRecvError::Lagged(_)) => continue,
+            Err(_) => return frame,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DisconnectParams {
+    // also unlink spotify, instead of only forgetting the tokens here
+    #[serde(default)]
+    revoke: bool,
 }
 
 #[get("/device/{ccc}/disconnect")]
 async fn disconnect(
-    ccc: web::Path<String>,
+    ControlledDevice(ccc): ControlledDevice,
+    params: web::Query<DisconnectParams>,
     app_state: web::Data<AppState>,
 ) -> Result<HttpResponse, actix_web::Error> {
     // remove state from the app state.
-    if app_state.has_update(&ccc) {
-        if let Some(update) = app_state.remove_elli_update(&ccc) {
-            update.close().await?;
-        }
+    let device_guard = app_state.lock_device(&ccc).await;
+    if let Some(update) = app_state.remove_elli_update(&ccc) {
+        update.disconnect().await?;
     }
-    app_state.remove_access(ccc.as_str());
+    drop(device_guard);
+    let access = app_state.get_access(&ccc);
+    app_state.remove_access(ccc.as_str()).await;
 
     info!("Disconnect called for ccc: {}", ccc);
+    // the local state is gone either way, including the refresh token. Spotify has no way to
+    // revoke the tokens, so users who want to unlink the app are sent to remove it themselves.
+    let location = match access {
+        Some(access) if params.revoke => {
+            debug!(
+                "Dropped spotify access of {}. Its token stays valid for up to {}s.",
+                ccc,
+                access.valid_for_after_drop().as_secs()
+            );
+            String::from(spotify::SPOTIFY_APPS_URL)
+        }
+        _ => format!("/device/{ccc}"),
+    };
     let response = HttpResponse::Found()
-        .append_header(("Location", format!("/device/{ccc}")))
+        .append_header(("Location", location))
         .finish();
     Ok(response)
 }
 
+/// The key signing the session cookies. Without `SESSION_SECRET` every restart signs with
+/// a new key, which logs everybody out and forgets which device they linked.
+fn session_key() -> Key {
+    match env::var("SESSION_SECRET") {
+        Ok(secret) => {
+            let secret = BASE64_STANDARD
+                .decode(secret.trim())
+                .expect("SESSION_SECRET must be base64");
+            Key::try_from(secret.as_slice()).unwrap_or_else(|_| {
+                panic!(
+                    "SESSION_SECRET must be at least 64 bytes, but was {} bytes",
+                    secret.len()
+                )
+            })
+        }
+        Err(_) => {
+            let key = Key::generate();
+            warn!(
+                "SESSION_SECRET not set. Sessions are valid until a restart. To keep them, set SESSION_SECRET={}",
+                BASE64_STANDARD.encode(key.master())
+            );
+            key
+        }
+    }
+}
+
+// ctrl-c, or SIGTERM as sent by `docker stop` and systemd
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    println!("Server starting at http://127.0.0.1:3000");
+    let bind_addr = env::var("ELLI_BIND_ADDR").unwrap_or_else(|_| String::from("127.0.0.1"));
+    let port: u16 = env::var("ELLI_PORT")
+        .map(|port| port.parse().expect("ELLI_PORT must be a port number"))
+        .unwrap_or(3000);
+    let redirect_uri = env::var("ELLI_REDIRECT_URI")
+        .unwrap_or_else(|_| String::from("http://127.0.0.1:3000/spotify/callback"));
+    let redirect_uri = Url::parse(&redirect_uri).expect("ELLI_REDIRECT_URI must be a valid url");
+    println!("Server starting at http://{}:{}", bind_addr, port);
 
-    // Initialize the logger
-    env_logger::init_from_env(Env::default().default_filter_or("info"));
+    // Initialize the logger. RUST_LOG filters as before, and the log lines of dependencies are
+    // forwarded.
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
 
+    let client_id =
+        env::var("SPOTIFY_CLIENT_ID").unwrap_or_else(|_| String::from(DEFAULT_SPOTIFY_CLIENT_ID));
     let secret = env::var("SPOTIFY_CLIENT_SECRET").expect("SPOTIFY_CLIENT_SECRET must be set");
-    let session_key = Key::generate();
-    let state = web::Data::new(AppState::new(secret));
-    let spotify_client = web::Data::new(SpotifyClient::new());
+    let session_key = session_key();
+    let token_file = env::var("ELLI_TOKEN_FILE").unwrap_or_else(|_| String::from("tokens.json"));
+    let token_store = Box::new(FileTokenStore::new(PathBuf::from(token_file)));
+    let settings_file =
+        env::var("ELLI_SETTINGS_FILE").unwrap_or_else(|_| String::from("settings.json"));
+    let settings_store = Box::new(FileSettingsStore::new(PathBuf::from(settings_file)));
+    let mut state = AppState::new(client_id, secret, redirect_uri, token_store)
+        .with_settings_store(settings_store);
+    match env::var("ELLI_TOKEN_SECRET") {
+        Ok(token_secret) => state = state.with_token_secret(token_secret.into_bytes()),
+        Err(_) => info!("ELLI_TOKEN_SECRET not set. Device tokens are valid until a restart."),
+    }
+    match env::var("ELLI_OPERATOR_TOKEN") {
+        Ok(operator_token) => state = state.with_operator_token(operator_token),
+        Err(_) => info!("ELLI_OPERATOR_TOKEN not set. The routes across all devices are disabled."),
+    }
+    let state = web::Data::new(state);
+    let mut spotify_client = SpotifyClient::new();
+    if let Ok(retries) = env::var("ELLI_IMAGE_RETRIES") {
+        let retries = retries
+            .parse()
+            .expect("ELLI_IMAGE_RETRIES must be a number");
+        spotify_client = spotify_client.with_image_retries(retries);
+    }
+    if let Ok(retries) = env::var("ELLI_UNAUTHORIZED_RETRIES") {
+        let retries = retries
+            .parse()
+            .expect("ELLI_UNAUTHORIZED_RETRIES must be a number");
+        spotify_client = spotify_client.with_unauthorized_retries(retries);
+    }
+    let spotify_client = web::Data::new(spotify_client);
+
+    let shutdown_state = state.clone();
+    let sweep_state = state.clone();
+    tokio::spawn(async move {
+        let mut sweep = interval(TOKEN_SWEEP_INTERVAL);
+        loop {
+            sweep.tick().await;
+            for ccc in sweep_state.sweep_expired().await {
+                info!("Removed expired spotify access for {}", ccc);
+            }
+            let abandoned = sweep_state.sweep_oauth_states();
+            if abandoned > 0 {
+                info!("Removed {} abandoned spotify logins", abandoned);
+            }
+            sweep_state.sweep_device_locks();
+        }
+    });
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         let session =
             SessionMiddleware::builder(CookieSessionStore::default(), session_key.clone())
                 .cookie_http_only(true) // no JavaScript access
@@ -151,13 +983,47 @@ async fn main() -> std::io::Result<()> {
             .app_data(spotify_client.clone())
             .wrap(session)
             .service(index)
+            .service(healthz)
+            .service(prometheus_metrics)
+            .service(api_devices)
             .service(spotify::scope())
             .service(device)
             .service(connected)
+            .service(live)
+            .service(push_matrix)
+            .service(push_pixel)
+            .service(clear_matrix)
+            .service(broadcast_matrix)
+            .service(rename)
+            .service(calibrate)
+            .service(save_calibration)
+            .service(settings)
+            .service(save_settings)
+            .service(read_matrix)
+            .service(preview_png)
+            .service(brightness)
+            .service(refresh)
+            .service(device_status)
+            .service(debug_frames)
+            .service(issue_token)
+            .service(stream_frames)
             .service(disconnect)
             .service(fs::Files::new("/static", "./static").show_files_listing())
     })
-    .bind(("127.0.0.1", 3000))?
-    .run()
-    .await
+    // the signals are handled below, so that the devices are released before we exit
+    .disable_signals()
+    .bind((bind_addr, port))?
+    .run();
+
+    let server_handle = server.handle();
+    tokio::spawn(async move {
+        if let Err(e) = shutdown_signal().await {
+            warn!("Failed to listen for shutdown signals: {}", e);
+            return;
+        }
+        info!("Shutting down. Closing all device connections.");
+        shutdown_state.close_all().await;
+        server_handle.stop(true).await;
+    });
+    server.await
 }
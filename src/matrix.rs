@@ -0,0 +1,287 @@
+use crate::elli::messages::websocket::PixelData;
+use serde::Deserialize;
+
+/// Colors pushed to the device directly, bypassing Spotify. Either one color per pixel in
+/// row-major order, or one array of colors per row. Colors are hex like `#ff8800`, or
+/// `hsv(hue, sat, val)` with the bytes the device takes as they are.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ColorMatrix {
+    Flat(Vec<String>),
+    Rows(Vec<Vec<String>>),
+}
+
+/// One pixel pushed to the device directly, leaving the others as they are.
+#[derive(Debug, Deserialize)]
+pub struct SinglePixel {
+    pub row: usize,
+    pub col: usize,
+    #[serde(flatten)]
+    pub color: PixelColor,
+}
+
+/// Color of a single pixel, either as `r`, `g` and `b` or in the device's own `hue`, `sat` and
+/// `val`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum PixelColor {
+    Rgb { r: u8, g: u8, b: u8 },
+    Hsv { hue: u8, sat: u8, val: u8 },
+}
+
+impl SinglePixel {
+    /// Checks the position against the matrix size and converts the color into a device pixel.
+    pub fn to_pixel(&self, size: u32) -> Result<PixelData, MatrixError> {
+        let size = size as usize;
+        if self.row >= size || self.col >= size {
+            return Err(MatrixError::OutOfRange {
+                row: self.row,
+                col: self.col,
+                size,
+            });
+        }
+        Ok(match self.color {
+            PixelColor::Rgb { r, g, b } => PixelData::from_rgb(r, g, b, self.row, self.col),
+            PixelColor::Hsv { hue, sat, val } => {
+                PixelData::from_hsv(hue, sat, val, self.row, self.col)
+            }
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MatrixError {
+    WrongPixelCount {
+        expected: usize,
+        actual: usize,
+    },
+    WrongRowCount {
+        expected: usize,
+        actual: usize,
+    },
+    WrongRowLength {
+        row: usize,
+        expected: usize,
+        actual: usize,
+    },
+    InvalidColor(String),
+    OutOfRange {
+        row: usize,
+        col: usize,
+        size: usize,
+    },
+}
+
+impl std::fmt::Display for MatrixError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MatrixError::WrongPixelCount { expected, actual } => {
+                write!(f, "Expected {} colors, got {}", expected, actual)
+            }
+            MatrixError::WrongRowCount { expected, actual } => {
+                write!(f, "Expected {} rows, got {}", expected, actual)
+            }
+            MatrixError::WrongRowLength {
+                row,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Expected {} colors in row {}, got {}",
+                expected, row, actual
+            ),
+            MatrixError::InvalidColor(color) => {
+                write!(
+                    f,
+                    "Invalid color '{}', expected a hex color like #ff8800 or hsv(170, 255, 255)",
+                    color
+                )
+            }
+            MatrixError::OutOfRange { row, col, size } => write!(
+                f,
+                "Pixel at row {}, column {} is outside of the {}x{} matrix",
+                row, col, size, size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MatrixError {}
+
+impl ColorMatrix {
+    /// Checks the dimensions against the matrix size and converts the colors into device pixels.
+    pub fn to_pixels(&self, size: u32) -> Result<Vec<PixelData>, MatrixError> {
+        let size = size as usize;
+        let colors: Vec<&String> = match self {
+            ColorMatrix::Flat(colors) => {
+                if colors.len() != size * size {
+                    return Err(MatrixError::WrongPixelCount {
+                        expected: size * size,
+                        actual: colors.len(),
+                    });
+                }
+                colors.iter().collect()
+            }
+            ColorMatrix::Rows(rows) => {
+                if rows.len() != size {
+                    return Err(MatrixError::WrongRowCount {
+                        expected: size,
+                        actual: rows.len(),
+                    });
+                }
+                if let Some((row, colors)) = rows.iter().enumerate().find(|(_, r)| r.len() != size)
+                {
+                    return Err(MatrixError::WrongRowLength {
+                        row,
+                        expected: size,
+                        actual: colors.len(),
+                    });
+                }
+                rows.iter().flatten().collect()
+            }
+        };
+
+        colors
+            .into_iter()
+            .enumerate()
+            .map(|(i, color)| parse_color(color, i / size, i % size))
+            .collect()
+    }
+}
+
+fn parse_color(color: &str, row: usize, col: usize) -> Result<PixelData, MatrixError> {
+    match color
+        .strip_prefix("hsv(")
+        .and_then(|hsv| hsv.strip_suffix(')'))
+    {
+        Some(hsv) => {
+            let [hue, sat, val] = parse_hsv(hsv).ok_or_else(|| invalid_color(color))?;
+            Ok(PixelData::from_hsv(hue, sat, val, row, col))
+        }
+        None => {
+            let [r, g, b] = parse_hex(color)?;
+            Ok(PixelData::from_rgb(r, g, b, row, col))
+        }
+    }
+}
+
+// three bytes separated by commas, e.g. "170, 255, 255"
+fn parse_hsv(hsv: &str) -> Option<[u8; 3]> {
+    let mut channels = hsv.split(',').map(|channel| channel.trim().parse().ok());
+    let hsv = [channels.next()??, channels.next()??, channels.next()??];
+    channels.next().is_none().then_some(hsv)
+}
+
+fn invalid_color(color: &str) -> MatrixError {
+    MatrixError::InvalidColor(color.to_string())
+}
+
+// accepts "#rrggbb" and "rrggbb"
+fn parse_hex(color: &str) -> Result<[u8; 3], MatrixError> {
+    let invalid = || invalid_color(color);
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flat_and_rows_match() {
+        let flat: ColorMatrix =
+            serde_json::from_str(r##"["#ff0000", "00ff00", "#0000ff", "#ffffff"]"##).unwrap();
+        let rows: ColorMatrix =
+            serde_json::from_str(r##"[["#ff0000", "00ff00"], ["#0000ff", "#ffffff"]]"##).unwrap();
+
+        let flat = flat.to_pixels(2).unwrap();
+        let rows = rows.to_pixels(2).unwrap();
+        assert_eq!(flat.len(), 4);
+        for (a, b) in flat.iter().zip(rows.iter()) {
+            assert_eq!(
+                (a.hue, a.sat, a.val, a.row, a.col),
+                (b.hue, b.sat, b.val, b.row, b.col)
+            );
+        }
+        assert_eq!((flat[2].hue, flat[2].row, flat[2].col), (170, 1, 0));
+    }
+
+    #[test]
+    fn test_wrong_dimensions() {
+        let flat = ColorMatrix::Flat(vec![String::from("#000000"); 3]);
+        assert_eq!(
+            flat.to_pixels(2).unwrap_err(),
+            MatrixError::WrongPixelCount {
+                expected: 4,
+                actual: 3
+            }
+        );
+        let rows = ColorMatrix::Rows(vec![
+            vec![String::from("#000000"); 2],
+            vec![String::from("#000000"); 1],
+        ]);
+        assert_eq!(
+            rows.to_pixels(2).unwrap_err(),
+            MatrixError::WrongRowLength {
+                row: 1,
+                expected: 2,
+                actual: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_color() {
+        let flat = ColorMatrix::Flat(vec![String::from("#00zz00")]);
+        assert_eq!(
+            flat.to_pixels(1).unwrap_err(),
+            MatrixError::InvalidColor(String::from("#00zz00"))
+        );
+    }
+
+    #[test]
+    fn test_single_pixel() {
+        let pixel: SinglePixel =
+            serde_json::from_str(r#"{"row": 1, "col": 0, "r": 0, "g": 0, "b": 255}"#).unwrap();
+        let pixel = pixel.to_pixel(2).unwrap();
+        assert_eq!((pixel.hue, pixel.row, pixel.col), (170, 1, 0));
+
+        let outside = SinglePixel {
+            row: 0,
+            col: 2,
+            color: PixelColor::Rgb { r: 0, g: 0, b: 0 },
+        };
+        assert_eq!(
+            outside.to_pixel(2).unwrap_err(),
+            MatrixError::OutOfRange {
+                row: 0,
+                col: 2,
+                size: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_hsv_colors() {
+        let pixel: SinglePixel =
+            serde_json::from_str(r#"{"row": 0, "col": 1, "hue": 86, "sat": 17, "val": 3}"#)
+                .unwrap();
+        let pixel = pixel.to_pixel(2).unwrap();
+        assert_eq!((pixel.hue, pixel.sat, pixel.val), (86, 17, 3));
+
+        let flat = ColorMatrix::Flat(vec![String::from("hsv(86, 17, 3)")]);
+        let pixels = flat.to_pixels(1).unwrap();
+        assert_eq!((pixels[0].hue, pixels[0].sat, pixels[0].val), (86, 17, 3));
+        for invalid in ["hsv(86, 17)", "hsv(86, 17, 3, 4)", "hsv(256, 0, 0)"] {
+            let flat = ColorMatrix::Flat(vec![String::from(invalid)]);
+            assert_eq!(
+                flat.to_pixels(1).unwrap_err(),
+                MatrixError::InvalidColor(String::from(invalid))
+            );
+        }
+    }
+}
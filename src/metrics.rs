@@ -0,0 +1,112 @@
+//! Counters for tuning the update workers, exposed in the Prometheus text format.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Counters shared by all workers. Each update only adds to a few atomics.
+#[derive(Default)]
+pub struct Metrics {
+    updates: AtomicU64,
+    failed_updates: AtomicU64,
+    update_micros: AtomicU64,
+    pixels_sent: AtomicU64,
+    spotify_calls: AtomicU64,
+    // updates which found the frame on the matrix already
+    skipped_updates: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_update(&self, duration: Duration, failed: bool) {
+        self.updates.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.failed_updates.fetch_add(1, Ordering::Relaxed);
+        }
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        self.update_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub fn record_pixels(&self, pixels: usize) {
+        self.pixels_sent.fetch_add(pixels as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_spotify_call(&self) {
+        self.spotify_calls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_skipped_update(&self) {
+        self.skipped_updates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// All counters in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: &[(&str, String)]| {
+            // writing into a string doesn't fail
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (suffix, value) in values {
+                let _ = writeln!(out, "{}{} {}", name, suffix, value);
+            }
+        };
+        let seconds = get(&self.update_micros) as f64 / 1_000_000.0;
+        metric(
+            "elli_update_duration_seconds",
+            "summary",
+            "Time the updates took from polling spotify to the last pixel sent.",
+            &[
+                ("_sum", seconds.to_string()),
+                ("_count", get(&self.updates).to_string()),
+            ],
+        );
+        metric(
+            "elli_failed_updates_total",
+            "counter",
+            "Updates which ended with an error.",
+            &[("", get(&self.failed_updates).to_string())],
+        );
+        metric(
+            "elli_skipped_updates_total",
+            "counter",
+            "Updates which didn't paint, because the frame was on the matrix already.",
+            &[("", get(&self.skipped_updates).to_string())],
+        );
+        metric(
+            "elli_pixels_sent_total",
+            "counter",
+            "Pixels sent to the devices.",
+            &[("", get(&self.pixels_sent).to_string())],
+        );
+        metric(
+            "elli_spotify_calls_total",
+            "counter",
+            "Requests to the spotify api, including token refreshes.",
+            &[("", get(&self.spotify_calls).to_string())],
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.record_update(Duration::from_millis(1500), false);
+        metrics.record_update(Duration::from_millis(500), true);
+        metrics.record_pixels(25);
+        metrics.record_spotify_call();
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE elli_update_duration_seconds summary\n"));
+        assert!(text.contains("elli_update_duration_seconds_sum 2\n"));
+        assert!(text.contains("elli_update_duration_seconds_count 2\n"));
+        assert!(text.contains("elli_failed_updates_total 1\n"));
+        assert!(text.contains("elli_skipped_updates_total 0\n"));
+        assert!(text.contains("elli_pixels_sent_total 25\n"));
+        assert!(text.contains("elli_spotify_calls_total 1\n"));
+    }
+}
@@ -0,0 +1,944 @@
+use crate::elli::messages::websocket::PixelData;
+use crate::elli::{ElliConfig, FitMode, RenderMode, Rotation};
+use crate::text;
+use image::imageops::FilterType;
+use image::{imageops, DynamicImage, GenericImageView, ImageBuffer, Rgb, RgbImage};
+use serde::Deserialize;
+use std::time::Duration;
+use tracing::warn;
+
+// brightness of the center in RenderMode::AmbientGlow, relative to the album art
+const AMBIENT_CENTER: f32 = 0.2;
+// matrices smaller than this have hardly a center for RenderMode::AmbientGlow, and glow in a
+// single color
+const AMBIENT_MIN_SIZE: u32 = 4;
+// time the attract animation takes once around the color wheel
+const ATTRACT_CYCLE: Duration = Duration::from_secs(20);
+// edge length of one pixel of the matrix in the png preview, including its grid line
+const PREVIEW_CELL: u32 = 24;
+const PREVIEW_GRID_COLOR: Rgb<u8> = Rgb([40, 40, 40]);
+
+/// Downscales album art to the size of the matrix. The scaling happens in linear light,
+/// so that averaging bright and dark areas doesn't crush the shadows into black.
+pub fn downscale(image: &DynamicImage, config: &ElliConfig, filter: FilterType) -> DynamicImage {
+    let gamma = config.gamma;
+    let mut linear = fit(image, config).to_rgb32f();
+    for pixel in linear.pixels_mut() {
+        pixel.0 = pixel.0.map(|c| c.powf(gamma));
+    }
+
+    // fit brought the image to the aspect ratio of the art area already, so resizing it exactly
+    // only distorts it with FitMode::Stretch. Keeping the aspect ratio here would leave rows or
+    // columns of the matrix without pixels for art which isn't square.
+    let resized = DynamicImage::ImageRgb32F(linear)
+        .resize_exact(config.size, config.art_rows(), filter)
+        .into_rgb32f();
+
+    let (width, height) = resized.dimensions();
+    let srgb = ImageBuffer::from_fn(width, height, |x, y| {
+        let pixel = resized.get_pixel(x, y);
+        // filters like Lanczos overshoot, so clamp before converting back
+        Rgb(pixel
+            .0
+            .map(|c| (c.clamp(0.0, 1.0).powf(1.0 / gamma) * 255.0).round() as u8))
+    });
+    DynamicImage::ImageRgb8(srgb)
+}
+
+/// Brings the image to the aspect ratio of the album art area according to the fit mode, so
+/// that resizing it to the matrix doesn't distort it.
+pub fn fit(image: &DynamicImage, config: &ElliConfig) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    let target = config.size as f32 / config.art_rows() as f32;
+    let wider = width as f32 / height as f32 > target;
+    match config.fit {
+        FitMode::Stretch => image.clone(),
+        FitMode::Cover => {
+            let (crop_width, crop_height) = if wider {
+                ((height as f32 * target).round() as u32, height)
+            } else {
+                (width, (width as f32 / target).round() as u32)
+            };
+            image.crop_imm(
+                (width - crop_width) / 2,
+                (height - crop_height) / 2,
+                crop_width,
+                crop_height,
+            )
+        }
+        FitMode::Contain => {
+            let (canvas_width, canvas_height) = if wider {
+                (width, (width as f32 / target).round() as u32)
+            } else {
+                ((height as f32 * target).round() as u32, height)
+            };
+            let mut canvas =
+                RgbImage::from_pixel(canvas_width, canvas_height, Rgb(config.fit_background));
+            imageops::overlay(
+                &mut canvas,
+                &image.to_rgb8(),
+                ((canvas_width - width) / 2) as i64,
+                ((canvas_height - height) / 2) as i64,
+            );
+            DynamicImage::ImageRgb8(canvas)
+        }
+    }
+}
+
+/// Colors the lamp reproduces distinctly, used for palette mapping with `ELLI_PALETTE`. These
+/// are the colors of the sample image the connection tests paint.
+pub const DEFAULT_PALETTE: [[u8; 3]; 18] = [
+    [241, 142, 23],
+    [230, 71, 29],
+    [223, 10, 56],
+    [223, 6, 87],
+    [228, 22, 122],
+    [251, 214, 20],
+    [216, 6, 129],
+    [174, 202, 32],
+    [63, 69, 145],
+    [136, 31, 126],
+    [96, 178, 54],
+    [255, 255, 255],
+    [39, 132, 199],
+    [49, 54, 135],
+    [91, 37, 121],
+    [32, 155, 108],
+    [32, 161, 157],
+    [29, 97, 172],
+];
+
+/// Runs the full image pipeline from the album art to the image shown on the matrix. Both the
+/// browser preview and the device use this, so that they look the same.
+pub fn frame(image: &DynamicImage, config: &ElliConfig, filter: FilterType) -> DynamicImage {
+    let downscaled = match config.mode {
+        RenderMode::AlbumArt | RenderMode::Attract => downscale(image, config, filter),
+        RenderMode::DominantColor => {
+            let color = Rgb(dominant_color(image));
+            DynamicImage::ImageRgb8(RgbImage::from_pixel(config.size, config.art_rows(), color))
+        }
+        RenderMode::AmbientGlow => ambient_glow(image, config, filter),
+    };
+    let downscaled = if config.saturation != 1.0 {
+        saturate(&downscaled, config.saturation)
+    } else {
+        downscaled
+    };
+    let downscaled = if config.auto_contrast {
+        auto_levels(&downscaled)
+    } else {
+        downscaled
+    };
+    let dithered = if config.dither {
+        dither(&downscaled, config.dither_levels)
+    } else {
+        downscaled
+    };
+    match &config.palette {
+        Some(palette) => map_to_palette(&dithered, palette),
+        None => dithered,
+    }
+}
+
+/// The edges of the album art around the border of the matrix, with a dimmed center, like a
+/// bias-light behind the cover. Small matrices glow in the average color of the image instead.
+pub fn ambient_glow(image: &DynamicImage, config: &ElliConfig, filter: FilterType) -> DynamicImage {
+    let (width, height) = (config.size, config.art_rows());
+    if width.min(height) < AMBIENT_MIN_SIZE {
+        let color = Rgb(average_color(image, config.gamma));
+        return DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, color));
+    }
+    // the border pixels of the downscaled image average the edges of the album art
+    let mut glow = downscale(image, config, filter).into_rgb8();
+    for (x, y, pixel) in glow.enumerate_pixels_mut() {
+        let border = x == 0 || y == 0 || x + 1 == width || y + 1 == height;
+        if !border {
+            pixel.0 = pixel.0.map(|c| (c as f32 * AMBIENT_CENTER).round() as u8);
+        }
+    }
+    DynamicImage::ImageRgb8(glow)
+}
+
+// the mean color of the image, averaged in linear light like `downscale` does
+fn average_color(image: &DynamicImage, gamma: f32) -> [u8; 3] {
+    let linear = image.to_rgb32f();
+    let count = linear.pixels().len().max(1) as f32;
+    let mut sum = [0.0; 3];
+    for pixel in linear.pixels() {
+        for (total, c) in sum.iter_mut().zip(pixel.0) {
+            *total += c.powf(gamma);
+        }
+    }
+    sum.map(|total| ((total / count).powf(1.0 / gamma) * 255.0).round() as u8)
+}
+
+/// The color covering most of the image. Colors are grouped by the upper four bits of each
+/// channel, and the colors of the largest group are averaged.
+pub fn dominant_color(image: &DynamicImage) -> [u8; 3] {
+    let bucket =
+        |c: [u8; 3]| (c[0] as usize >> 4) << 8 | (c[1] as usize >> 4) << 4 | c[2] as usize >> 4;
+    let mut counts = vec![0u64; 1 << 12];
+    let mut sums = vec![[0u64; 3]; 1 << 12];
+    for pixel in image.to_rgb8().pixels() {
+        let index = bucket(pixel.0);
+        counts[index] += 1;
+        for (sum, c) in sums[index].iter_mut().zip(pixel.0) {
+            *sum += c as u64;
+        }
+    }
+    let Some((index, &count)) = counts.iter().enumerate().max_by_key(|(_, count)| **count) else {
+        return [0, 0, 0];
+    };
+    if count == 0 {
+        return [0, 0, 0];
+    }
+    sums[index].map(|sum| (sum as f64 / count as f64).round() as u8)
+}
+
+/// Stands in for the album art of items which have none, e.g. local files. This is the idle
+/// image, if one is configured, and an unlit matrix otherwise.
+pub fn placeholder(config: &ElliConfig) -> image::ImageResult<DynamicImage> {
+    match &config.idle_image {
+        Some(path) => image::open(path),
+        None => Ok(DynamicImage::ImageRgb8(RgbImage::new(1, 1))),
+    }
+}
+
+/// Stretches the image, so that its darkest pixel becomes black and its brightest one white.
+/// All channels are mapped alike, by the range of the luminance, so colors keep their hue.
+/// Images of a single brightness are left as they are.
+pub fn auto_levels(image: &DynamicImage) -> DynamicImage {
+    let mut rgb = image.to_rgb8();
+    let luminance = |c: [u8; 3]| 0.2126 * c[0] as f32 + 0.7152 * c[1] as f32 + 0.0722 * c[2] as f32;
+    let (min, max) = rgb
+        .pixels()
+        .map(|pixel| luminance(pixel.0))
+        .fold((f32::MAX, f32::MIN), |(min, max), l| {
+            (min.min(l), max.max(l))
+        });
+    if max - min < 1.0 {
+        return DynamicImage::ImageRgb8(rgb);
+    }
+    let scale = 255.0 / (max - min);
+    for pixel in rgb.pixels_mut() {
+        pixel.0 = pixel
+            .0
+            .map(|c| ((c as f32 - min) * scale).round().clamp(0.0, 255.0) as u8);
+    }
+    DynamicImage::ImageRgb8(rgb)
+}
+
+/// Multiplies the saturation of every pixel, keeping its hue and value. Saturation is capped
+/// where the weakest channel reaches 0, so boosting never shifts the hue.
+pub fn saturate(image: &DynamicImage, factor: f32) -> DynamicImage {
+    let mut rgb = image.to_rgb8();
+    for pixel in rgb.pixels_mut() {
+        let max = *pixel.0.iter().max().unwrap() as f32;
+        let min = *pixel.0.iter().min().unwrap() as f32;
+        if max == min {
+            // gray has no hue to boost
+            continue;
+        }
+        let factor = factor.max(0.0).min(max / (max - min));
+        for channel in pixel.0.iter_mut() {
+            *channel = (max - (max - *channel as f32) * factor).round() as u8;
+        }
+    }
+    DynamicImage::ImageRgb8(rgb)
+}
+
+/// Replaces every pixel with the closest palette color. Distances are measured in CIELAB,
+/// where they roughly match how different the colors look.
+pub fn map_to_palette(image: &DynamicImage, palette: &[[u8; 3]]) -> DynamicImage {
+    if palette.is_empty() {
+        return image.clone();
+    }
+    let palette_lab: Vec<[f32; 3]> = palette.iter().map(|c| to_lab(*c)).collect();
+    let mut buffer = image.to_rgb8();
+    for pixel in buffer.pixels_mut() {
+        let lab = to_lab(pixel.0);
+        let nearest = palette_lab
+            .iter()
+            .map(|p| (0..3).map(|i| (p[i] - lab[i]).powi(2)).sum::<f32>())
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(i, _)| i)
+            .unwrap_or(0);
+        pixel.0 = palette[nearest];
+    }
+    DynamicImage::ImageRgb8(buffer)
+}
+
+// sRGB to CIELAB with the D65 white point
+fn to_lab(rgb: [u8; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb.map(|c| {
+        let c = c as f32 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    });
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+    let f = |t: f32| {
+        if t > 0.008856 {
+            t.cbrt()
+        } else {
+            7.787 * t + 16.0 / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// Floyd–Steinberg dithering, which reduces each channel to the given number of levels and
+/// diffuses the rounding error onto the neighboring pixels.
+pub fn dither(image: &DynamicImage, levels: u8) -> DynamicImage {
+    let mut buffer = image.to_rgb32f();
+    let (width, height) = buffer.dimensions();
+    let steps = (levels.max(2) - 1) as f32;
+
+    for y in 0..height {
+        for x in 0..width {
+            let old = buffer.get_pixel(x, y).0;
+            let new = old.map(|c| (c.clamp(0.0, 1.0) * steps).round() / steps);
+            buffer.put_pixel(x, y, Rgb(new));
+
+            let error = [old[0] - new[0], old[1] - new[1], old[2] - new[2]];
+            let neighbors = [
+                (x as i64 + 1, y as i64, 7.0 / 16.0),
+                (x as i64 - 1, y as i64 + 1, 3.0 / 16.0),
+                (x as i64, y as i64 + 1, 5.0 / 16.0),
+                (x as i64 + 1, y as i64 + 1, 1.0 / 16.0),
+            ];
+            for (nx, ny, weight) in neighbors {
+                if nx < 0 || nx >= width as i64 || ny >= height as i64 {
+                    continue;
+                }
+                let neighbor = buffer.get_pixel_mut(nx as u32, ny as u32);
+                for (c, e) in neighbor.0.iter_mut().zip(error) {
+                    *c += e * weight;
+                }
+            }
+        }
+    }
+    DynamicImage::ImageRgb32F(buffer).into_rgb8().into()
+}
+
+/// Converts a downscaled image into the pixels sent to the device.
+pub fn to_pixels(image: &DynamicImage, config: &ElliConfig) -> Vec<PixelData> {
+    let size = config.size as usize;
+    art_grid(image, config)
+        .into_iter()
+        .enumerate()
+        .map(|(i, [r, g, b])| {
+            PixelData::from_rgb(r, g, b, i / size, i % size).with_min_value(config.min_val)
+        })
+        .collect()
+}
+
+/// Colors of the whole matrix in row-major order, including the progress bar.
+pub fn rgb_grid(image: &DynamicImage, config: &ElliConfig, progress: Option<f32>) -> Vec<[u8; 3]> {
+    let mut colors = art_grid(image, config);
+    if config.progress_bar {
+        colors.extend(progress_bar(config, progress));
+    }
+    colors
+}
+
+// the album art area in row-major order, exactly `size` by `art_rows`. An image of another
+// size would leave cells of the matrix unpainted with the previous frame on them, so the cells
+// it doesn't cover get the background color and the pixels outside of the area are dropped.
+fn art_grid(image: &DynamicImage, config: &ElliConfig) -> Vec<[u8; 3]> {
+    let (size, rows) = (config.size, config.art_rows());
+    let (width, height) = image.dimensions();
+    if (width, height) != (size, rows) {
+        warn!(
+            "Frame is {}x{}, but the matrix shows {}x{}. Filling the gaps.",
+            width, height, size, rows
+        );
+    }
+    (0..rows)
+        .flat_map(|y| (0..size).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            if x < width && y < height {
+                let rgba = image.get_pixel(x, y);
+                [rgba[0], rgba[1], rgba[2]]
+            } else {
+                config.fit_background
+            }
+        })
+        .collect()
+}
+
+/// Hex colors of the whole matrix in row-major order, as shown in the browser preview.
+pub fn hex_colors(image: &DynamicImage, config: &ElliConfig, progress: Option<f32>) -> Vec<String> {
+    to_hex(&rgb_grid(image, config, progress))
+}
+
+pub fn to_hex(grid: &[[u8; 3]]) -> Vec<String> {
+    grid.iter()
+        .map(|[r, g, b]| format!("#{:02x}{:02x}{:02x}", r, g, b))
+        .collect()
+}
+
+/// Frames blending from one grid into the other, excluding both the start and the target.
+/// `steps` frames are needed to get from start to target, so `steps - 1` are returned.
+pub fn crossfade(from: &[[u8; 3]], to: &[[u8; 3]], steps: u8) -> Vec<Vec<[u8; 3]>> {
+    (1..steps.max(1))
+        .map(|step| {
+            let t = step as f32 / steps as f32;
+            from.iter()
+                .zip(to)
+                .map(|(a, b)| {
+                    [0, 1, 2].map(|i| (a[i] as f32 + (b[i] as f32 - a[i] as f32) * t).round() as u8)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Converts a row-major grid into device pixels. Pixels of the album art are lifted to the
+/// minimum value, like in [`to_pixels`].
+pub fn grid_pixels(grid: &[[u8; 3]], config: &ElliConfig) -> Vec<PixelData> {
+    let size = config.size as usize;
+    let art_rows = config.art_rows() as usize;
+    grid.iter()
+        .enumerate()
+        .map(|(i, [r, g, b])| {
+            let pixel = PixelData::from_rgb(*r, *g, *b, i / size, i % size);
+            if i / size < art_rows {
+                pixel.with_min_value(config.min_val)
+            } else {
+                pixel
+            }
+        })
+        .collect()
+}
+
+/// Number of lit columns of the progress bar.
+pub fn progress_columns(config: &ElliConfig, progress: Option<f32>) -> u32 {
+    progress
+        .map(|p| (p.clamp(0.0, 1.0) * config.size as f32).round() as u32)
+        .unwrap_or(0)
+}
+
+/// Colors of the progress bar row. Columns up to the track's progress are lit, the others are
+/// off. Without a known progress, the whole row is off.
+pub fn progress_bar(config: &ElliConfig, progress: Option<f32>) -> Vec<[u8; 3]> {
+    let lit = progress_columns(config, progress);
+    (0..config.size)
+        .map(|col| {
+            if col < lit {
+                config.progress_bar_color
+            } else {
+                [0, 0, 0]
+            }
+        })
+        .collect()
+}
+
+/// Pixels of the progress bar in the bottom row of the matrix.
+pub fn progress_bar_pixels(config: &ElliConfig, progress: Option<f32>) -> Vec<PixelData> {
+    let row = config.size as usize - 1;
+    progress_bar(config, progress)
+        .into_iter()
+        .enumerate()
+        .map(|(col, [r, g, b])| PixelData::from_rgb(r, g, b, row, col))
+        .collect()
+}
+
+/// Moves the pixel to where it has to be painted on a rotated or mirrored matrix.
+pub fn orient(mut pixel: PixelData, config: &ElliConfig) -> PixelData {
+    let last = config.size as usize - 1;
+    let (row, col) = (pixel.row, pixel.col);
+    let (row, col) = match config.rotation {
+        Rotation::None => (row, col),
+        Rotation::Clockwise90 => (col, last - row),
+        Rotation::Clockwise180 => (last - row, last - col),
+        Rotation::Clockwise270 => (last - col, row),
+    };
+    pixel.row = row;
+    pixel.col = if config.mirror { last - col } else { col };
+    pixel
+}
+
+/// Grids moving the text from the right edge of the album art rows out over the left edge, one
+/// column per grid. Empty if the art rows are too few for the font.
+pub fn scroll_frames(text: &str, config: &ElliConfig, progress: Option<f32>) -> Vec<Vec<[u8; 3]>> {
+    let size = config.size as usize;
+    let art_rows = config.art_rows() as usize;
+    if art_rows < text::GLYPH_HEIGHT {
+        return Vec::new();
+    }
+    let bitmap = text::rasterize(text);
+    let width = bitmap[0].len();
+    let top = (art_rows - text::GLYPH_HEIGHT) / 2;
+    (0..size + width)
+        .map(|offset| {
+            let mut grid = vec![[0, 0, 0]; size * art_rows];
+            for (row, columns) in bitmap.iter().enumerate() {
+                for col in 0..size {
+                    // the column of the text shown in this column of the matrix
+                    let lit = (col + offset)
+                        .checked_sub(size)
+                        .and_then(|text_col| columns.get(text_col))
+                        .is_some_and(|lit| *lit);
+                    if lit {
+                        grid[(top + row) * size + col] = config.title_color;
+                    }
+                }
+            }
+            if config.progress_bar {
+                grid.extend(progress_bar(config, progress));
+            }
+            grid
+        })
+        .collect()
+}
+
+/// Frame of the attract animation `elapsed` into it: a rainbow running diagonally across the
+/// whole matrix, which goes once around the color wheel every `ATTRACT_CYCLE`.
+pub fn attract_pixels(config: &ElliConfig, elapsed: Duration) -> Vec<PixelData> {
+    let size = config.size as usize;
+    let shift = elapsed.as_secs_f32() / ATTRACT_CYCLE.as_secs_f32() * 256.0;
+    (0..size * size)
+        .map(|i| {
+            let (row, col) = (i / size, i % size);
+            // both diagonals together span the color wheel once
+            let offset = (row + col) as f32 / (2 * size) as f32 * 256.0;
+            PixelData {
+                hue: ((offset + shift) % 256.0) as u8,
+                sat: 255,
+                val: 255,
+                row,
+                col,
+            }
+        })
+        .collect()
+}
+
+/// Patterns painted while calibrating a lamp against the browser preview.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TestPattern {
+    // ramps from dark to full brightness across the matrix, to tune brightness and saturation
+    Red,
+    Green,
+    Blue,
+    White,
+    // pixels alternating between black and white, next to the gray album art of such a pattern
+    // is downscaled to. Both halves look alike from a distance with the right gamma.
+    Gamma,
+}
+
+impl TestPattern {
+    pub const ALL: [TestPattern; 5] = [
+        TestPattern::Red,
+        TestPattern::Green,
+        TestPattern::Blue,
+        TestPattern::White,
+        TestPattern::Gamma,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TestPattern::Red => "red",
+            TestPattern::Green => "green",
+            TestPattern::Blue => "blue",
+            TestPattern::White => "white",
+            TestPattern::Gamma => "gamma",
+        }
+    }
+}
+
+/// Colors of the whole matrix for the test pattern in row-major order, before the brightness
+/// is applied. The ramps go through the image pipeline like album art.
+pub fn test_pattern(pattern: TestPattern, config: &ElliConfig) -> Vec<[u8; 3]> {
+    let size = config.size;
+    let channels = match pattern {
+        TestPattern::Red => [1, 0, 0],
+        TestPattern::Green => [0, 1, 0],
+        TestPattern::Blue => [0, 0, 1],
+        TestPattern::White => [1, 1, 1],
+        TestPattern::Gamma => {
+            // what downscaling makes of an even mix of black and white
+            let gray = (0.5f32.powf(1.0 / config.gamma) * 255.0).round() as u8;
+            return (0..size * size)
+                .map(|i| {
+                    let (row, col) = (i / size, i % size);
+                    if col >= size / 2 {
+                        [gray; 3]
+                    } else if (row + col) % 2 == 0 {
+                        [255; 3]
+                    } else {
+                        [0; 3]
+                    }
+                })
+                .collect();
+        }
+    };
+    let ramp = RgbImage::from_fn(size, size, |x, _| {
+        let value = ((x + 1) * 255 / size) as u8;
+        Rgb(channels.map(|on| on * value))
+    });
+    let image = frame(&DynamicImage::ImageRgb8(ramp), config, FilterType::Nearest);
+    let mut grid = rgb_grid(&image, config, None);
+    // the pattern covers the rows of overlays, too
+    grid.resize((size * size) as usize, [0, 0, 0]);
+    grid
+}
+
+/// The grid of a `size`x`size` matrix, scaled up for viewing with a line between the pixels.
+pub fn preview_image(grid: &[[u8; 3]], size: u32) -> RgbImage {
+    let edge = size * PREVIEW_CELL + 1;
+    RgbImage::from_fn(edge, edge, |x, y| {
+        if x % PREVIEW_CELL == 0 || y % PREVIEW_CELL == 0 {
+            return PREVIEW_GRID_COLOR;
+        }
+        let (row, col) = (y / PREVIEW_CELL, x / PREVIEW_CELL);
+        grid.get((row * size + col) as usize)
+            .map_or(Rgb([0, 0, 0]), |&color| Rgb(color))
+    })
+}
+
+/// Pixels which switch off the whole matrix.
+pub fn blank_pixels(config: &ElliConfig) -> Vec<PixelData> {
+    let size = config.size as usize;
+    (0..size * size)
+        .map(|i| PixelData::from_rgb(0, 0, 0, i / size, i % size))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    fn config_with_gamma(size: u32, gamma: f32) -> ElliConfig {
+        let mut config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").unwrap();
+        config.size = size;
+        config.gamma = gamma;
+        config
+    }
+
+    #[test]
+    fn test_downscale_keeps_uniform_color() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(10, 10, Rgb([200, 40, 90])));
+        let scaled = downscale(&image, &config_with_gamma(5, 2.2), FilterType::Triangle);
+        assert_eq!(scaled.dimensions(), (5, 5));
+        assert_eq!(scaled.get_pixel(2, 2).0[..3], [200, 40, 90]);
+    }
+
+    #[test]
+    fn test_downscale_leaves_room_for_progress_bar() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(10, 10, Rgb([200, 40, 90])));
+        let mut config = config_with_gamma(5, 2.2);
+        config.progress_bar = true;
+        let scaled = downscale(&image, &config, FilterType::Nearest);
+        assert_eq!(scaled.dimensions(), (5, 4));
+    }
+
+    // row and col of the pixel in the top left corner, and of the one right of it
+    fn oriented_corners(rotation: Rotation, mirror: bool) -> [(usize, usize); 2] {
+        let mut config = config_with_gamma(3, 1.0);
+        config.rotation = rotation;
+        config.mirror = mirror;
+        [(0, 0), (0, 1)].map(|(row, col)| {
+            let pixel = orient(PixelData::from_rgb(0, 0, 0, row, col), &config);
+            (pixel.row, pixel.col)
+        })
+    }
+
+    #[test]
+    fn test_orient() {
+        assert_eq!(oriented_corners(Rotation::None, false), [(0, 0), (0, 1)]);
+        assert_eq!(
+            oriented_corners(Rotation::Clockwise90, false),
+            [(0, 2), (1, 2)]
+        );
+        assert_eq!(
+            oriented_corners(Rotation::Clockwise180, false),
+            [(2, 2), (2, 1)]
+        );
+        assert_eq!(
+            oriented_corners(Rotation::Clockwise270, false),
+            [(2, 0), (1, 0)]
+        );
+        assert_eq!(oriented_corners(Rotation::None, true), [(0, 2), (0, 1)]);
+        assert_eq!(
+            oriented_corners(Rotation::Clockwise90, true),
+            [(0, 0), (1, 0)]
+        );
+    }
+
+    // 4x2 image, left half red, right half blue
+    fn wide_image() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(4, 2, |x, _| {
+            if x < 2 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            }
+        }))
+    }
+
+    #[test]
+    fn test_fit_cover_crops_center() {
+        let mut config = config_with_gamma(2, 1.0);
+        config.fit = FitMode::Cover;
+        let fitted = fit(&wide_image(), &config);
+        assert_eq!(fitted.dimensions(), (2, 2));
+        assert_eq!(fitted.get_pixel(0, 0).0[..3], [255, 0, 0]);
+        assert_eq!(fitted.get_pixel(1, 0).0[..3], [0, 0, 255]);
+    }
+
+    #[test]
+    fn test_fit_contain_adds_bars() {
+        let mut config = config_with_gamma(2, 1.0);
+        config.fit = FitMode::Contain;
+        config.fit_background = [0, 255, 0];
+        let fitted = fit(&wide_image(), &config);
+        assert_eq!(fitted.dimensions(), (4, 4));
+        assert_eq!(fitted.get_pixel(0, 0).0[..3], [0, 255, 0]);
+        assert_eq!(fitted.get_pixel(0, 1).0[..3], [255, 0, 0]);
+        assert_eq!(fitted.get_pixel(3, 3).0[..3], [0, 255, 0]);
+        // the result still downscales to the matrix
+        assert_eq!(
+            downscale(&wide_image(), &config, FilterType::Nearest).dimensions(),
+            (2, 2)
+        );
+    }
+
+    #[test]
+    fn test_scroll_frames() {
+        let config = config_with_gamma(5, 1.0);
+        let frames = scroll_frames("I", &config, None);
+        // the 3 columns of the glyph enter, cross and leave the 5 columns of the matrix
+        assert_eq!(frames.len(), 5 + 3);
+        assert!(frames[0].iter().all(|c| *c == [0, 0, 0]));
+        // the top bar of the I has fully entered the last columns
+        let lit = |grid: &[[u8; 3]], col: usize| grid[col] == config.title_color;
+        assert!(lit(&frames[3], 2) && lit(&frames[3], 3) && lit(&frames[3], 4));
+        assert!(!lit(&frames[3], 1));
+        // too few rows for the font
+        assert!(scroll_frames("I", &config_with_gamma(4, 1.0), None).is_empty());
+    }
+
+    #[test]
+    fn test_saturate() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(2, 1, |x, _| {
+            if x == 0 {
+                Rgb([200, 100, 100])
+            } else {
+                Rgb([90, 90, 90])
+            }
+        }));
+        let boosted = saturate(&image, 1.5);
+        assert_eq!(boosted.get_pixel(0, 0).0[..3], [200, 50, 50]);
+        assert_eq!(boosted.get_pixel(1, 0).0[..3], [90, 90, 90]);
+        // fully saturated at most, the hue stays red
+        assert_eq!(saturate(&image, 3.0).get_pixel(0, 0).0[..3], [200, 0, 0]);
+    }
+
+    #[test]
+    fn test_dominant_color() {
+        // three quarters of the image are two close shades of red
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(4, 4, |x, y| match (x, y) {
+            (_, 0) => Rgb([0, 0, 255]),
+            (x, _) if x < 2 => Rgb([202, 10, 10]),
+            _ => Rgb([200, 12, 12]),
+        }));
+        assert_eq!(dominant_color(&image), [201, 11, 11]);
+
+        let mut config = config_with_gamma(3, 2.2);
+        config.mode = RenderMode::DominantColor;
+        let solid = frame(&image, &config, FilterType::Nearest).to_rgb8();
+        assert_eq!(solid.dimensions(), (3, 3));
+        assert!(solid.pixels().all(|pixel| pixel.0 == [201, 11, 11]));
+    }
+
+    #[test]
+    fn test_ramp_pattern() {
+        let config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").expect("Failed to parse ccc");
+        let grid = test_pattern(TestPattern::Red, &config);
+        assert_eq!(grid.len(), 25);
+        assert_eq!(grid[0], [51, 0, 0]);
+        assert_eq!(grid[4], [255, 0, 0]);
+        // every row has the same ramp
+        assert_eq!(grid[5..10], grid[0..5]);
+    }
+
+    #[test]
+    fn test_gamma_pattern() {
+        let config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").expect("Failed to parse ccc");
+        let grid = test_pattern(TestPattern::Gamma, &config);
+        assert_eq!(grid[0..2], [[255; 3], [0; 3]]);
+        assert_eq!(grid[5..7], [[0; 3], [255; 3]]);
+        // gray in the right half, as bright as the average of the checkerboard with gamma 2.2
+        assert_eq!(grid[2], [186; 3]);
+        assert_eq!(grid[24], [186; 3]);
+    }
+
+    #[test]
+    fn test_attract_pixels() {
+        let config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").expect("Failed to parse ccc");
+        let start = attract_pixels(&config, Duration::ZERO);
+        assert_eq!(start.len(), 25);
+        assert_eq!(start[0].hue, 0);
+        // the bottom right corner is almost around the color wheel
+        assert_eq!(start[24].hue, 204);
+        assert!(start.iter().all(|p| p.sat == 255 && p.val == 255));
+
+        // a quarter cycle later everything has moved on by a quarter of the wheel
+        let later = attract_pixels(&config, ATTRACT_CYCLE / 4);
+        assert_eq!(later[0].hue, 64);
+        assert_eq!(later[24].hue, 12);
+        assert_eq!(attract_pixels(&config, ATTRACT_CYCLE)[0].hue, 0);
+    }
+
+    #[test]
+    fn test_auto_levels() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(2, 1, |x, _| {
+            if x == 0 {
+                Rgb([100, 100, 100])
+            } else {
+                Rgb([120, 120, 120])
+            }
+        }));
+        let stretched = auto_levels(&image);
+        assert_eq!(stretched.get_pixel(0, 0).0[..3], [0, 0, 0]);
+        assert_eq!(stretched.get_pixel(1, 0).0[..3], [255, 255, 255]);
+
+        // nothing to stretch
+        let uniform = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([50, 80, 20])));
+        assert_eq!(auto_levels(&uniform).get_pixel(1, 1).0[..3], [50, 80, 20]);
+    }
+
+    #[test]
+    fn test_crossfade() {
+        let frames = crossfade(
+            &[[0, 0, 0], [200, 100, 0]],
+            &[[100, 200, 0], [0, 100, 0]],
+            4,
+        );
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0], vec![[25, 50, 0], [150, 100, 0]]);
+        assert_eq!(frames[1], vec![[50, 100, 0], [100, 100, 0]]);
+        assert_eq!(frames[2], vec![[75, 150, 0], [50, 100, 0]]);
+        assert!(crossfade(&[[0, 0, 0]], &[[255, 255, 255]], 1).is_empty());
+    }
+
+    #[test]
+    fn test_map_to_palette() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(3, 1, |x, _| match x {
+            0 => Rgb([250, 250, 240]),
+            1 => Rgb([40, 140, 190]),
+            _ => Rgb([0, 0, 0]),
+        }));
+        let mapped = map_to_palette(&image, &DEFAULT_PALETTE);
+        assert_eq!(mapped.get_pixel(0, 0).0[..3], [255, 255, 255]);
+        assert_eq!(mapped.get_pixel(1, 0).0[..3], [39, 132, 199]);
+        // every pixel ends up in the palette
+        assert!(mapped
+            .pixels()
+            .all(|(_, _, p)| DEFAULT_PALETTE.contains(&[p[0], p[1], p[2]])));
+    }
+
+    #[test]
+    fn test_dither_keeps_average_brightness() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([128, 128, 128])));
+        let dithered = dither(&image, 2);
+        let values: Vec<u8> = dithered.pixels().map(|(_, _, p)| p.0[0]).collect();
+        // only the two levels remain, mixed so that about half of the pixels are lit
+        assert!(values.iter().all(|v| *v == 0 || *v == 255));
+        let lit = values.iter().filter(|v| **v == 255).count();
+        assert!((28..=36).contains(&lit));
+    }
+
+    #[test]
+    fn test_progress_bar() {
+        let mut config = config_with_gamma(5, 2.2);
+        config.progress_bar = true;
+        let on = config.progress_bar_color;
+        let off = [0, 0, 0];
+        assert_eq!(
+            progress_bar(&config, Some(0.4)),
+            vec![on, on, off, off, off]
+        );
+        assert_eq!(progress_bar(&config, None), vec![off; 5]);
+
+        let pixels = progress_bar_pixels(&config, Some(1.0));
+        assert!(pixels.iter().all(|p| p.row == 4 && p.val == 255));
+    }
+
+    #[test]
+    fn test_downscale_averages_in_linear_light() {
+        // black and white stripes, which average to a mid gray in linear light
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(2, 2, |x, _| {
+            if x == 0 {
+                Rgb([0, 0, 0])
+            } else {
+                Rgb([255, 255, 255])
+            }
+        }));
+        let linear = downscale(&image, &config_with_gamma(1, 2.2), FilterType::Triangle);
+        let srgb = downscale(&image, &config_with_gamma(1, 1.0), FilterType::Triangle);
+        assert!(linear.get_pixel(0, 0).0[0] > 180);
+        assert!((127..=128).contains(&srgb.get_pixel(0, 0).0[0]));
+    }
+
+    #[test]
+    fn test_frames_cover_the_whole_matrix() {
+        let mut config = config_with_gamma(3, 2.2);
+        config.fit_background = [1, 2, 3];
+        // e.g. the art of an episode, resized keeping its aspect ratio
+        let pixels = to_pixels(&wide_image(), &config);
+        assert_eq!(pixels.len(), 9);
+        let grid = rgb_grid(&wide_image(), &config, None);
+        assert_eq!(grid.len(), 9);
+        assert_eq!(grid[0], [255, 0, 0]);
+        assert_eq!(grid[2], [0, 0, 255]);
+        // the bottom row isn't covered by the image, and its fourth column is dropped
+        assert_eq!(grid[6..], [[1, 2, 3]; 3]);
+    }
+
+    #[test]
+    fn test_preview_image() {
+        let grid = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]];
+        let preview = preview_image(&grid, 2);
+        assert_eq!(
+            preview.dimensions(),
+            (2 * PREVIEW_CELL + 1, 2 * PREVIEW_CELL + 1)
+        );
+        assert_eq!(*preview.get_pixel(0, 5), PREVIEW_GRID_COLOR);
+        assert_eq!(*preview.get_pixel(PREVIEW_CELL, 5), PREVIEW_GRID_COLOR);
+        assert_eq!(preview.get_pixel(5, 5).0, [255, 0, 0]);
+        assert_eq!(preview.get_pixel(PREVIEW_CELL + 5, 5).0, [0, 255, 0]);
+        assert_eq!(preview.get_pixel(5, PREVIEW_CELL + 5).0, [0, 0, 255]);
+    }
+
+    #[test]
+    fn test_ambient_glow() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(10, 10, |x, _| {
+            if x < 5 {
+                Rgb([200, 0, 0])
+            } else {
+                Rgb([0, 0, 200])
+            }
+        }));
+        let mut config = config_with_gamma(5, 1.0);
+        config.mode = RenderMode::AmbientGlow;
+        let glow = frame(&image, &config, FilterType::Nearest).to_rgb8();
+        assert_eq!(glow.get_pixel(0, 2).0, [200, 0, 0]);
+        assert_eq!(glow.get_pixel(4, 2).0, [0, 0, 200]);
+        // the center only glows faintly
+        assert_eq!(glow.get_pixel(1, 2).0, [40, 0, 0]);
+
+        config.size = 2;
+        let solid = frame(&image, &config, FilterType::Nearest).to_rgb8();
+        assert!(solid.pixels().all(|pixel| pixel.0 == [100, 0, 100]));
+    }
+}
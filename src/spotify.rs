@@ -1,22 +1,48 @@
-use crate::state::{rnd_string, AppState, SpotifyAppCredentials};
+use crate::state::{rnd_string, AppState, OAuthState, SpotifyAppCredentials};
+use crate::templates::{into_response, ErrorTemplate};
 use actix_session::Session;
 use actix_web::error::ErrorInternalServerError;
-use actix_web::{get, web, HttpResponse, Scope};
+use actix_web::http::{header, StatusCode};
+use actix_web::{get, web, HttpRequest, HttpResponse, Scope};
 use base64::prelude::BASE64_STANDARD;
 use base64::Engine;
-use image::DynamicImage;
-use log::info;
+use image::codecs::gif::GifDecoder;
+use image::{AnimationDecoder, DynamicImage, ImageFormat};
 use reqwest::Client;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::fmt::Debug;
-use std::sync::Arc;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use tracing::{info, warn};
 use url::Url;
 
-const SPOTIFY_SCOPE: &str = "user-read-currently-playing";
+// tokens only carry the scopes requested when the user logged in. Extending this list needs a
+// new login, which the connected page asks for once spotify rejects a token for missing scopes.
+const SPOTIFY_SCOPES: &[&str] = &["user-read-currently-playing", "user-read-playback-state"];
 const SPOTIFY_AUTH_URL: &str = "https://accounts.spotify.com/authorize";
 const SPOTIFY_TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
-const REDIRECT_URI: &str = "http://127.0.0.1:3000/spotify/callback";
+const SPOTIFY_API_URL: &str = "https://api.spotify.com/v1";
+/// Where users remove the app from their spotify account, which invalidates all of its tokens.
+/// Spotify has no endpoint for apps to revoke their tokens themselves.
+pub const SPOTIFY_APPS_URL: &str = "https://www.spotify.com/account/apps/";
+// how much earlier than spotify says an access is refreshed
+const EXPIRY_MARGIN: Duration = Duration::from_secs(120);
+// used when spotify rate limits us without telling for how long
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+// album art kept in memory. A handful covers every device switching back and forth between tracks.
+const IMAGE_CACHE_SIZE: usize = 16;
+// browsers show gif frames without a usable delay this long, so we do the same
+const DEFAULT_FRAME_DELAY: Duration = Duration::from_millis(100);
+// further attempts to download an image after a network error or a server error of the cdn
+const DEFAULT_IMAGE_RETRIES: u32 = 2;
+// pause before the first retry, doubled for every further one
+const IMAGE_RETRY_DELAY: Duration = Duration::from_millis(200);
+// refreshes of a rejected access token before a request fails
+const DEFAULT_UNAUTHORIZED_RETRIES: u32 = 1;
 
 #[derive(Deserialize)]
 struct CallbackParams {
@@ -31,12 +57,85 @@ pub struct TokenResponse {
     pub refresh_token: Option<String>,
 }
 
+#[derive(Deserialize, Debug)]
+struct TokenErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+// errors of the web api. Not every endpoint fills in the reason.
+#[derive(Deserialize, Debug)]
+struct ApiErrorResponse {
+    error: ApiError,
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiError {
+    message: Option<String>,
+    reason: Option<String>,
+}
+
+// the token endpoint answers with either the tokens or an error
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum TokenResult {
+    Token(TokenResponse),
+    Error(TokenErrorResponse),
+}
+
 #[derive(Deserialize, Debug)]
 pub struct CurrentlyPlaying {
-    // pub progress_ms: u64,
-    // pub is_playing: bool,
-    pub item: Option<Track>,
-    pub currently_playing_type: String,
+    pub progress_ms: Option<u64>,
+    pub is_playing: bool,
+    #[serde(flatten)]
+    pub item: PlayingItem,
+}
+
+/// Everything `/me/player` tells about the playback, on top of the currently playing item.
+#[derive(Deserialize, Debug)]
+pub struct PlaybackState {
+    pub device: Option<PlaybackDevice>,
+    pub shuffle_state: bool,
+    pub repeat_state: String,
+    #[serde(flatten)]
+    pub current: CurrentlyPlaying,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PlaybackDevice {
+    pub name: String,
+    pub is_active: bool,
+    pub volume_percent: Option<u32>,
+}
+
+// the kind of the item is given by the currently_playing_type next to it
+#[derive(Deserialize, Debug)]
+#[serde(
+    tag = "currently_playing_type",
+    content = "item",
+    rename_all = "lowercase"
+)]
+pub enum PlayingItem {
+    Track(Option<Track>),
+    Episode(Option<Episode>),
+    // a chapter of an audiobook
+    #[serde(alias = "chapter")]
+    Audiobook(Option<Chapter>),
+    Ad,
+    #[serde(other)]
+    Unknown,
+}
+
+impl PlayingItem {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            PlayingItem::Track(_) => "track",
+            PlayingItem::Episode(_) => "episode",
+            PlayingItem::Audiobook(_) => "audiobook",
+            PlayingItem::Ad => "ad",
+            PlayingItem::Unknown => "unknown",
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -44,10 +143,56 @@ pub struct Track {
     pub album: Album,
     pub artists: Vec<Artist>,
     pub name: String,
+    pub duration_ms: u64,
+    // identifies the track, also local files which have no id
+    #[serde(default)]
+    pub uri: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Episode {
+    pub name: String,
+    pub duration_ms: u64,
+    #[serde(default)]
+    pub uri: String,
+    // art of the episode itself, which the show's art is the fallback for
+    #[serde(default)]
+    pub images: Vec<Image>,
+    pub show: Show,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Chapter {
+    pub name: String,
+    pub duration_ms: u64,
+    #[serde(default)]
+    pub uri: String,
+    #[serde(default)]
+    pub images: Vec<Image>,
+    pub audiobook: Option<Audiobook>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Audiobook {
+    pub name: String,
+    #[serde(default)]
+    pub images: Vec<Image>,
+    #[serde(default)]
+    pub authors: Vec<Artist>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Show {
+    pub name: String,
+    pub images: Vec<Image>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Album {
+    #[serde(default)]
+    pub name: String,
+    // a year, month or day like 1999, 1999-12 or 1999-12-31, depending on what is known
+    pub release_date: Option<String>,
     pub images: Vec<Image>,
 }
 
@@ -56,57 +201,306 @@ pub struct Artist {
     pub name: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 pub struct Image {
     pub url: String,
     pub width: u32,
 }
 
-impl Default for Image {
-    fn default() -> Self {
-        Image {
-            url: String::new(),
-            width: 0,
+/// Responses of the web api which don't carry the requested data.
+#[derive(Debug, PartialEq)]
+pub enum SpotifyError {
+    // too many requests. Try again after the given time.
+    RateLimited(Duration),
+    // the token was rejected. It has to be refreshed or the user has to log in again.
+    Unauthorized,
+    // the token lacks a scope for the request. The user has to log in again to grant it.
+    MissingScope,
+    // the user needs spotify premium for the request
+    PremiumRequired,
+    // the app is in development mode, and the user isn't on its allowlist in the dashboard
+    UserNotRegistered,
+    // any other refusal, with the message spotify gave
+    Forbidden(String),
+    Unexpected(reqwest::StatusCode),
+    // the token endpoint refused to hand out tokens, e.g. because the refresh token was revoked.
+    // The user has to log in again.
+    TokenRejected(String),
+}
+
+impl std::fmt::Display for SpotifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpotifyError::RateLimited(retry_after) => {
+                write!(f, "Rate limited by spotify. Retry after {:?}", retry_after)
+            }
+            SpotifyError::Unauthorized => write!(f, "Spotify rejected the access token"),
+            SpotifyError::MissingScope => {
+                write!(f, "The access token lacks a scope for the request")
+            }
+            SpotifyError::PremiumRequired => write!(f, "Spotify premium is required"),
+            SpotifyError::UserNotRegistered => write!(
+                f,
+                "The spotify user isn't registered for the app in the developer dashboard"
+            ),
+            SpotifyError::Forbidden(message) => {
+                write!(f, "Spotify refused the request: {}", message)
+            }
+            SpotifyError::Unexpected(status) => {
+                write!(f, "Unexpected response from spotify: {}", status)
+            }
+            SpotifyError::TokenRejected(error) => {
+                write!(f, "Spotify rejected the token request: {}", error)
+            }
         }
     }
 }
 
+impl std::error::Error for SpotifyError {}
+
+/// Reasons why album art couldn't be fetched.
+#[derive(Debug)]
+pub enum ImageError {
+    // the cdn couldn't be reached or the download broke off. Worth retrying.
+    Network(reqwest::Error),
+    // the cdn answered with an error. Retried for server errors only.
+    Status(reqwest::StatusCode),
+    // the downloaded data isn't an image we can read, e.g. a format the enabled codecs of the
+    // image crate don't decode, like AVIF. Retrying won't help.
+    Decode {
+        error: image::ImageError,
+        // as the cdn sent it
+        content_type: Option<String>,
+    },
+}
+
+impl ImageError {
+    fn is_transient(&self) -> bool {
+        match self {
+            ImageError::Network(_) => true,
+            ImageError::Status(status) => status.is_server_error(),
+            ImageError::Decode { .. } => false,
+        }
+    }
+
+    /// Whether the album art was downloaded, but can't be shown. Callers show a placeholder
+    /// instead, as the art won't become readable.
+    pub fn is_undecodable(error: &(dyn std::error::Error + 'static)) -> bool {
+        matches!(
+            error.downcast_ref::<ImageError>(),
+            Some(ImageError::Decode { .. })
+        )
+    }
+}
+
+impl std::fmt::Display for ImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageError::Network(e) => write!(f, "Failed to download image: {}", e),
+            ImageError::Status(status) => write!(f, "Image download failed with {}", status),
+            ImageError::Decode {
+                error,
+                content_type,
+            } => write!(
+                f,
+                "Failed to decode image of type {}: {}",
+                content_type.as_deref().unwrap_or("unknown"),
+                error
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+fn decode_frames(data: &[u8]) -> Result<Vec<(DynamicImage, Duration)>, image::ImageError> {
+    if image::guess_format(data)? == ImageFormat::Gif {
+        let frames = GifDecoder::new(Cursor::new(data))?
+            .into_frames()
+            .collect_frames()?;
+        if !frames.is_empty() {
+            let frames = frames
+                .into_iter()
+                .map(|frame| {
+                    let delay = Duration::from(frame.delay());
+                    let delay = if delay <= Duration::from_millis(10) {
+                        DEFAULT_FRAME_DELAY
+                    } else {
+                        delay
+                    };
+                    (DynamicImage::ImageRgba8(frame.into_buffer()), delay)
+                })
+                .collect();
+            return Ok(frames);
+        }
+    }
+    Ok(vec![(image::load_from_memory(data)?, Duration::ZERO)])
+}
+
+// tells the causes of a 403 apart. Spotify only names some of them in the reason, and answers
+// users missing from the allowlist of an app in development mode with plain text.
+fn forbidden(body: &str) -> SpotifyError {
+    let (message, reason) = match serde_json::from_str::<ApiErrorResponse>(body) {
+        Ok(response) => (
+            response.error.message.unwrap_or_default(),
+            response.error.reason.unwrap_or_default(),
+        ),
+        Err(_) => (body.trim().to_string(), String::new()),
+    };
+    let lowercase = message.to_lowercase();
+    if reason == "PREMIUM_REQUIRED" || lowercase.contains("premium required") {
+        SpotifyError::PremiumRequired
+    } else if lowercase.contains("registered") {
+        SpotifyError::UserNotRegistered
+    } else if lowercase.contains("scope") {
+        SpotifyError::MissingScope
+    } else {
+        SpotifyError::Forbidden(message)
+    }
+}
+
+// spotify sends the number of seconds to wait in the Retry-After header
+fn retry_after(headers: &reqwest::header::HeaderMap) -> Duration {
+    headers
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_RETRY_AFTER)
+}
+
+/// Decoded images by url, dropping the least recently used one once full.
+struct ImageCache {
+    capacity: usize,
+    // most recently used last
+    entries: VecDeque<(String, DynamicImage)>,
+}
+
+impl ImageCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn get(&mut self, url: &str) -> Option<DynamicImage> {
+        let index = self.entries.iter().position(|(u, _)| u == url)?;
+        let entry = self.entries.remove(index)?;
+        let image = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(image)
+    }
+
+    fn insert(&mut self, url: &str, image: DynamicImage) {
+        if let Some(index) = self.entries.iter().position(|(u, _)| u == url) {
+            self.entries.remove(index);
+        } else if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((url.to_string(), image));
+    }
+}
+
 #[derive(Clone)]
 pub struct SpotifyClient {
     client: Client,
+    api_url: String,
+    images: Arc<Mutex<ImageCache>>,
+    image_retries: u32,
+    // refreshes of an access token which spotify rejected before it expired, e.g. after the
+    // user changed their password, before giving up
+    unauthorized_retries: u32,
 }
 
 impl SpotifyClient {
     pub fn new() -> Self {
         Self {
             client: Client::new(),
+            api_url: String::from(SPOTIFY_API_URL),
+            images: Arc::new(Mutex::new(ImageCache::new(IMAGE_CACHE_SIZE))),
+            image_retries: DEFAULT_IMAGE_RETRIES,
+            unauthorized_retries: DEFAULT_UNAUTHORIZED_RETRIES,
         }
     }
 
-    pub async fn get_current_track(
+    /// Points the client at another api than spotify's, e.g. a local mock server.
+    #[cfg(test)]
+    pub fn with_api_url(mut self, url: String) -> Self {
+        self.api_url = url;
+        self
+    }
+
+    /// Changes how often a failed image download is retried.
+    pub fn with_image_retries(mut self, retries: u32) -> Self {
+        self.image_retries = retries;
+        self
+    }
+
+    /// Changes how often a rejected access token is refreshed before a request fails.
+    pub fn with_unauthorized_retries(mut self, retries: u32) -> Self {
+        self.unauthorized_retries = retries;
+        self
+    }
+
+    /// The current track together with the device playing it, shuffle and repeat.
+    pub async fn get_playback_state(
         &self,
         ccc: &str,
         state: web::Data<AppState>,
-    ) -> Result<Option<CurrentlyPlaying>, Box<dyn std::error::Error>> {
-        info!("Fetching current track for ccc: {}", ccc);
-        let access = Self::ensure_fresh_token(ccc, state).await?;
-        let bearer = format!("Bearer {}", access.access_token());
-
-        let response = self
-            .client
-            .get("https://api.spotify.com/v1/me/player/currently-playing")
-            .header("Authorization", bearer)
-            .send()
-            .await?;
+    ) -> Result<Option<PlaybackState>, Box<dyn std::error::Error>> {
+        info!("Fetching playback state for ccc: {}", ccc);
+        self.get_player(ccc, state, "").await
+    }
 
-        if response.status() == reqwest::StatusCode::NO_CONTENT {
-            Ok(None)
-        } else {
-            // let bla = response.text().await?;
-            // info!("get track response: {}", bla);
-            // let result = serde_json::from_str::<CurrentlyPlaying>(&bla)?;
-            let result = response.json::<CurrentlyPlaying>().await?;
-            Ok(Some(result))
+    // requests an endpoint below /me/player. No content means nothing is playing.
+    async fn get_player<T: DeserializeOwned>(
+        &self,
+        ccc: &str,
+        state: web::Data<AppState>,
+        path: &str,
+    ) -> Result<Option<T>, Box<dyn std::error::Error>> {
+        let mut attempt = 0;
+        loop {
+            // the token looked fresh, but spotify rejected it on the attempt before
+            let force_refresh = attempt > 0;
+            let access = Self::ensure_fresh_token(ccc, state.clone(), force_refresh).await?;
+            let bearer = format!("Bearer {}", access.access_token());
+            state.metrics().record_spotify_call();
+
+            let response = self
+                .client
+                .get(format!("{}/me/player{}", self.api_url, path))
+                // without this, spotify doesn't send the item for podcasts
+                .query(&[("additional_types", "track,episode")])
+                .header("Authorization", bearer)
+                .send()
+                .await?;
+
+            return match response.status() {
+                reqwest::StatusCode::OK => {
+                    let result = response.json::<T>().await?;
+                    Ok(Some(result))
+                }
+                reqwest::StatusCode::NO_CONTENT => Ok(None),
+                reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                    Err(SpotifyError::RateLimited(retry_after(response.headers())).into())
+                }
+                reqwest::StatusCode::UNAUTHORIZED if attempt < self.unauthorized_retries => {
+                    warn!(
+                        "Spotify rejected the access token for {}. Refreshing it.",
+                        ccc
+                    );
+                    attempt += 1;
+                    continue;
+                }
+                reqwest::StatusCode::UNAUTHORIZED => Err(SpotifyError::Unauthorized.into()),
+                reqwest::StatusCode::FORBIDDEN => {
+                    let body = response.text().await.unwrap_or_default();
+                    Err(forbidden(&body).into())
+                }
+                status => Err(SpotifyError::Unexpected(status).into()),
+            };
         }
     }
 
@@ -114,30 +508,114 @@ impl SpotifyClient {
         &self,
         image_url: &str,
     ) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+        if let Some(image) = self.images.lock().unwrap().get(image_url) {
+            return Ok(image);
+        }
+
         info!("Fetching image: {}", image_url);
-        let response = self.client.get(image_url).send().await?;
-        let data = response.bytes().await?;
-        let image = image::load_from_memory(&data)?;
+        let (data, content_type) = self.download(image_url).await?;
+        let image = image::load_from_memory(&data).map_err(|error| ImageError::Decode {
+            error,
+            content_type,
+        })?;
 
+        self.images.lock().unwrap().insert(image_url, image.clone());
         Ok(image)
     }
 
+    /// Like `get_image`, but an animated gif comes with all its frames and how long each one is
+    /// shown. Still images have a single frame.
+    pub async fn get_frames(
+        &self,
+        image_url: &str,
+    ) -> Result<Vec<(DynamicImage, Duration)>, Box<dyn std::error::Error>> {
+        info!("Fetching frames: {}", image_url);
+        let (data, content_type) = self.download(image_url).await?;
+        let frames = decode_frames(&data).map_err(|error| ImageError::Decode {
+            error,
+            content_type,
+        })?;
+
+        self.images
+            .lock()
+            .unwrap()
+            .insert(image_url, frames[0].0.clone());
+        Ok(frames)
+    }
+
+    // downloads the image together with its content type, retrying transient failures with a
+    // growing delay
+    async fn download(&self, image_url: &str) -> Result<(web::Bytes, Option<String>), ImageError> {
+        let mut attempt = 0;
+        loop {
+            let result = match self.client.get(image_url).send().await {
+                Ok(response) if response.status().is_success() => {
+                    let content_type = response
+                        .headers()
+                        .get(reqwest::header::CONTENT_TYPE)
+                        .and_then(|value| value.to_str().ok())
+                        .map(String::from);
+                    response
+                        .bytes()
+                        .await
+                        .map(|data| (data, content_type))
+                        .map_err(ImageError::Network)
+                }
+                Ok(response) => Err(ImageError::Status(response.status())),
+                Err(e) => Err(ImageError::Network(e)),
+            };
+            match result {
+                Err(e) if e.is_transient() && attempt < self.image_retries => {
+                    warn!("{}. Retrying {}", e, image_url);
+                    sleep(IMAGE_RETRY_DELAY * 2u32.pow(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // refreshes the access once it expires, or right away if `force` is set
     async fn ensure_fresh_token(
         ccc: &str,
         state: web::Data<AppState>,
+        force: bool,
     ) -> Result<Arc<SpotifyAccess>, Box<dyn std::error::Error>> {
         let access = state
             .get_access(ccc)
-            .ok_or_else(|| "No access token found, but should be present.")?;
-        if access.should_refresh() {
+            .ok_or("No access token found, but should be present.")?;
+        if force || access.should_refresh() {
             let spotify_credentials = state.get_spotify_credentials();
-            let new_access = SpotifyAccess::refresh(&access, spotify_credentials).await?;
-            state.insert_access(ccc, new_access);
+            state.metrics().record_spotify_call();
+            // the boxed error isn't Send, so the rejection is told apart before awaiting anything
+            let refreshed = match SpotifyAccess::refresh(&access, spotify_credentials).await {
+                Ok(new_access) => Ok(new_access),
+                Err(e) => match e.downcast::<SpotifyError>() {
+                    Ok(e) if matches!(*e, SpotifyError::TokenRejected(_)) => Err(e),
+                    e => {
+                        // the sweeper gives up on the access after repeated failures
+                        state.record_refresh_failure(ccc);
+                        return Err(match e {
+                            Ok(e) => e as Box<dyn std::error::Error>,
+                            Err(e) => e,
+                        });
+                    }
+                },
+            };
+            let new_access = match refreshed {
+                Ok(new_access) => new_access,
+                Err(e) => {
+                    // the access is gone for good. The user has to log in again.
+                    state.remove_access(ccc).await;
+                    return Err(e);
+                }
+            };
+            state.insert_access(ccc, new_access).await;
         }
         // we use unwrap because we have just inserted the access_token
         let result = state
             .get_access(ccc)
-            .ok_or_else(|| "Failed to retreive freshly inserted token")?;
+            .ok_or("Failed to retreive freshly inserted token")?;
         Ok(result)
     }
 }
@@ -158,6 +636,15 @@ impl SpotifyAccess {
         }
     }
 
+    /// Rebuilds an access from its remaining validity, e.g. when loading it from disk.
+    pub fn restore(access_token: String, refresh_token: Option<String>, remaining: u64) -> Self {
+        Self {
+            access_token,
+            refresh_token,
+            expires_at: Instant::now() + Duration::from_secs(remaining),
+        }
+    }
+
     pub fn access_token(&self) -> &str {
         &self.access_token
     }
@@ -170,6 +657,18 @@ impl SpotifyAccess {
         Instant::now() > self.expires_at
     }
 
+    /// Time left until the access should be refreshed.
+    pub fn remaining(&self) -> Duration {
+        self.expires_at.saturating_duration_since(Instant::now())
+    }
+
+    /// How long spotify keeps accepting the access token once the access is dropped. Spotify has
+    /// no endpoint for revoking tokens, so only the user can end it early, by removing the app
+    /// on [SPOTIFY_APPS_URL].
+    pub fn valid_for_after_drop(&self) -> Duration {
+        self.remaining() + EXPIRY_MARGIN
+    }
+
     pub async fn refresh(
         spotify_access: &SpotifyAccess,
         spotify_credentials: &SpotifyAppCredentials,
@@ -177,7 +676,7 @@ impl SpotifyAccess {
         if let Some(refresh_token) = spotify_access.refresh_token() {
             let form_data = [
                 ("grant_type", "refresh_token"),
-                ("refresh_token", &refresh_token),
+                ("refresh_token", refresh_token),
             ];
             let result = Self::token(&form_data, spotify_credentials).await?;
             let new_refresh_token = result
@@ -197,11 +696,11 @@ impl SpotifyAccess {
     async fn authorize(
         code: &str,
         spotify_app_credentials: &SpotifyAppCredentials,
-    ) -> Result<Self, reqwest::Error> {
+    ) -> Result<Self, Box<dyn std::error::Error>> {
         let form_data = [
             ("grant_type", "authorization_code"),
             ("code", code),
-            ("redirect_uri", REDIRECT_URI),
+            ("redirect_uri", spotify_app_credentials.redirect_uri()),
         ];
         let result = Self::token(&form_data, spotify_app_credentials).await?;
 
@@ -213,7 +712,7 @@ impl SpotifyAccess {
     async fn token<T: Serialize + ?Sized + Debug>(
         form_data: &T,
         spotify_credentials: &SpotifyAppCredentials,
-    ) -> Result<TokenResponse, reqwest::Error> {
+    ) -> Result<TokenResponse, Box<dyn std::error::Error>> {
         let auth_header = auth_header(spotify_credentials);
 
         // TODO replace with spotify client
@@ -226,18 +725,23 @@ impl SpotifyAccess {
             .text()
             .await?;
 
-        let parsed_response = serde_json::from_str::<TokenResponse>(&token_response).expect(
-            "Could not deserialize token response. \
-                 Please check if the Spotify API has changed.",
-        );
-
-        Ok(parsed_response)
+        match serde_json::from_str::<TokenResult>(&token_response)? {
+            TokenResult::Token(token) => Ok(token),
+            TokenResult::Error(e) => {
+                let description = e.error_description.unwrap_or_default();
+                warn!(
+                    "Spotify token request failed: {} ({})",
+                    e.error, description
+                );
+                Err(SpotifyError::TokenRejected(e.error).into())
+            }
+        }
     }
 
     fn calculate_expiry(expires_in: u64) -> Instant {
         // stores access and refresh token as well as the instant two minutes before the
         // access_token expires
-        Instant::now() + Duration::from_secs(expires_in - 120)
+        Instant::now() + Duration::from_secs(expires_in) - EXPIRY_MARGIN
     }
 }
 
@@ -247,6 +751,24 @@ pub fn scope() -> Scope {
         .service(callback)
 }
 
+// key of the random id identifying a browser session across requests
+const SESSION_ID_KEY: &str = "session_id";
+
+/// Id of the browser session, created on its first use.
+pub fn session_id(session: &Session) -> Result<String, actix_web::Error> {
+    if let Some(id) = session
+        .get::<String>(SESSION_ID_KEY)
+        .map_err(ErrorInternalServerError)?
+    {
+        return Ok(id);
+    }
+    let id = rnd_string();
+    session
+        .insert(SESSION_ID_KEY, &id)
+        .map_err(ErrorInternalServerError)?;
+    Ok(id)
+}
+
 #[get("/auth")]
 async fn authenticate(
     session: Session,
@@ -271,15 +793,18 @@ async fn authenticate(
     let state = rnd_string();
     // we can use unwrap here, as we hardcoded this url
     let mut url = Url::parse(SPOTIFY_AUTH_URL).unwrap();
+    let credentials = app_state.get_spotify_credentials();
     url.query_pairs_mut()
         .append_pair("response_type", "code")
-        .append_pair("client_id", app_state.get_spotify_credentials().id())
-        .append_pair("scope", SPOTIFY_SCOPE)
-        .append_pair("redirect_uri", REDIRECT_URI)
+        .append_pair("client_id", credentials.id())
+        .append_pair("scope", &SPOTIFY_SCOPES.join(" "))
+        .append_pair("redirect_uri", credentials.redirect_uri())
         .append_pair("state", &state);
 
+    // the state only completes the login for this session
+    let session_id = session_id(&session)?;
     // store the state in the app_state
-    app_state.insert_oauth_state(&ccc, state);
+    app_state.insert_oauth_state(&ccc, OAuthState::new(state, session_id));
 
     let response = HttpResponse::Found()
         .append_header(("Location", url.as_str()))
@@ -287,8 +812,75 @@ async fn authenticate(
     Ok(response)
 }
 
+/// Reasons why the oauth callback can't proceed.
+#[derive(Debug, PartialEq)]
+enum CallbackError {
+    NoSession,
+    NoState,
+    // deliberately without the states, so that the expected one doesn't leak
+    StateMismatch,
+    StateExpired,
+    // another session has connected its spotify account to the device
+    DeviceTaken,
+}
+
+impl CallbackError {
+    fn code(&self) -> &'static str {
+        match self {
+            CallbackError::NoSession => "no_session",
+            CallbackError::NoState => "no_state",
+            CallbackError::StateMismatch => "state_mismatch",
+            CallbackError::StateExpired => "state_expired",
+            CallbackError::DeviceTaken => "device_taken",
+        }
+    }
+
+    /// Renders the error as JSON if the client asks for it, as error page otherwise.
+    fn respond(self, req: &HttpRequest) -> Result<HttpResponse, actix_web::Error> {
+        let wants_json = req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("application/json"));
+        if wants_json {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": self.code(),
+                "description": self.to_string(),
+            })));
+        }
+        let mut response = into_response(ErrorTemplate {
+            error: String::from("Connecting to Spotify failed"),
+            description: self.to_string(),
+        })?;
+        *response.status_mut() = StatusCode::BAD_REQUEST;
+        Ok(response)
+    }
+}
+
+impl std::fmt::Display for CallbackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CallbackError::NoSession => write!(f, "No device selected in this session"),
+            CallbackError::NoState => write!(f, "No login was started for this device"),
+            CallbackError::StateMismatch => {
+                write!(f, "The login doesn't match the one started for this device")
+            }
+            CallbackError::StateExpired => {
+                write!(f, "The login took too long. Please connect Spotify again.")
+            }
+            CallbackError::DeviceTaken => {
+                write!(
+                    f,
+                    "Another session controls this device. Take it over first."
+                )
+            }
+        }
+    }
+}
+
 #[get("/callback")]
 async fn callback(
+    req: HttpRequest,
     params: web::Query<CallbackParams>,
     session: Session,
     app_state: web::Data<AppState>,
@@ -302,21 +894,32 @@ async fn callback(
     {
         ccc
     } else {
-        let response = HttpResponse::BadRequest().body("No session id found");
-        return Ok(response);
+        return CallbackError::NoSession.respond(&req);
     };
 
-    // check whether the previously saved state matches the state param sent back by the auth api
-    if let Some(state) = app_state.get_oauth_state(&ccc) {
-        if state == params.state {
+    let session_id = session
+        .get::<String>(SESSION_ID_KEY)
+        .map_err(ErrorInternalServerError)?;
+
+    // check whether the previously saved state matches the state param sent back by the auth api,
+    // and whether this session started the login
+    match app_state.get_oauth_state(&ccc) {
+        Some(state) if state.is_expired() => {
             app_state.remove_oauth_state(&ccc);
-        } else {
-            let response = HttpResponse::BadRequest().body("State mismatch");
-            return Ok(response);
+            return CallbackError::StateExpired.respond(&req);
         }
-    } else {
-        let response = HttpResponse::BadRequest().body("No state found");
-        return Ok(response);
+        Some(state)
+            if state.state == params.state && session_id.as_ref() == Some(&state.session_id) =>
+        {
+            app_state.remove_oauth_state(&ccc)
+        }
+        Some(_) => return CallbackError::StateMismatch.respond(&req),
+        None => return CallbackError::NoState.respond(&req),
+    }
+    // the state matched, so the session has an id
+    let session_id = session_id.unwrap_or_default();
+    if app_state.is_owned_by_other(&ccc, &session_id) {
+        return CallbackError::DeviceTaken.respond(&req);
     }
 
     // switch authorization token against access token and refresh token
@@ -324,7 +927,8 @@ async fn callback(
         .await
         .map_err(ErrorInternalServerError)?;
 
-    app_state.insert_access(&ccc, access);
+    app_state.insert_access(&ccc, access).await;
+    app_state.set_owner(&ccc, session_id);
     let redirect_path = format!("/device/{}/connected", ccc);
     let response = HttpResponse::Found()
         .append_header(("Location", redirect_path))
@@ -340,3 +944,378 @@ fn auth_header(spotify_credentials: &SpotifyAppCredentials) -> String {
     );
     format!("Basic {}", BASE64_STANDARD.encode(&credentials))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::templates::PlayingModel;
+    use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+
+    #[test]
+    fn test_decode_gif_frames() {
+        use image::codecs::gif::GifEncoder;
+        use image::{Delay, Frame, Rgba, RgbaImage};
+
+        let mut data = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut data);
+            let frames = [(Rgba([255, 0, 0, 255]), 50), (Rgba([0, 0, 255, 255]), 0)];
+            for (color, delay) in frames {
+                let buffer = RgbaImage::from_pixel(2, 2, color);
+                let delay = Delay::from_numer_denom_ms(delay, 1);
+                encoder
+                    .encode_frame(Frame::from_parts(buffer, 0, 0, delay))
+                    .unwrap();
+            }
+        }
+
+        let frames = decode_frames(&data).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].1, Duration::from_millis(50));
+        assert_eq!(frames[1].1, DEFAULT_FRAME_DELAY);
+        assert_eq!(frames[1].0.to_rgb8().get_pixel(0, 0).0, [0, 0, 255]);
+    }
+
+    // serves the given responses to one connection each and counts the connections
+    async fn image_server(responses: Vec<Vec<u8>>) -> (String, Arc<Mutex<usize>>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/image", listener.local_addr().unwrap());
+        let connections = Arc::new(Mutex::new(0));
+        let counter = connections.clone();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                *counter.lock().unwrap() += 1;
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+        (url, connections)
+    }
+
+    fn http_response(status: &str, body: &[u8]) -> Vec<u8> {
+        let head = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            body.len()
+        );
+        [head.as_bytes(), body].concat()
+    }
+
+    #[tokio::test]
+    async fn test_get_image_retries_server_errors() {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(2, 2)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let (url, connections) = image_server(vec![
+            http_response("503 Service Unavailable", b""),
+            http_response("200 OK", &png),
+        ])
+        .await;
+
+        let image = SpotifyClient::new().get_image(&url).await.unwrap();
+        assert_eq!(image.width(), 2);
+        assert_eq!(*connections.lock().unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_get_image_does_not_retry_decode_errors() {
+        let (url, connections) = image_server(vec![
+            http_response("200 OK", b"no image"),
+            http_response("200 OK", b"no image"),
+        ])
+        .await;
+
+        let e = SpotifyClient::new().get_image(&url).await.unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(ImageError::Decode { .. })));
+        assert_eq!(*connections.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_decode_error_names_content_type() {
+        // e.g. an AVIF cover, which the enabled codecs can't decode
+        let response = [
+            b"HTTP/1.1 200 OK\r\nContent-Type: image/avif\r\nContent-Length: 8\r\n".as_slice(),
+            b"Connection: close\r\n\r\nno image",
+        ]
+        .concat();
+        let (url, _) = image_server(vec![response]).await;
+
+        let e = SpotifyClient::new().get_frames(&url).await.unwrap_err();
+        assert!(ImageError::is_undecodable(e.as_ref()));
+        assert!(e.to_string().contains("image/avif"), "{}", e);
+
+        let e: Box<dyn std::error::Error> =
+            Box::new(ImageError::Status(reqwest::StatusCode::NOT_FOUND));
+        assert!(!ImageError::is_undecodable(e.as_ref()));
+    }
+
+    #[test]
+    fn test_image_cache_evicts_least_recently_used() {
+        let mut cache = ImageCache::new(2);
+        cache.insert("a", DynamicImage::new_rgb8(1, 1));
+        cache.insert("b", DynamicImage::new_rgb8(2, 2));
+        // touching "a" makes "b" the oldest entry
+        assert!(cache.get("a").is_some());
+        cache.insert("c", DynamicImage::new_rgb8(3, 3));
+
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.get("a").unwrap().width(), 1);
+        assert_eq!(cache.get("c").unwrap().width(), 3);
+    }
+
+    #[test]
+    fn test_deserialize_token_result() {
+        let raw = r#"{"access_token":"a","expires_in":3600,"refresh_token":"r"}"#;
+        let result = serde_json::from_str::<TokenResult>(raw).unwrap();
+        assert!(matches!(result, TokenResult::Token(t) if t.access_token == "a"));
+
+        let raw = r#"{"error":"invalid_grant","error_description":"Refresh token revoked"}"#;
+        let result = serde_json::from_str::<TokenResult>(raw).unwrap();
+        assert!(matches!(result, TokenResult::Error(e) if e.error == "invalid_grant"));
+    }
+
+    #[test]
+    fn test_callback_error_as_json() {
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_http_request();
+        let response = CallbackError::StateMismatch.respond(&req).unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), DEFAULT_RETRY_AFTER);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("12"));
+        assert_eq!(retry_after(&headers), Duration::from_secs(12));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("soon"));
+        assert_eq!(retry_after(&headers), DEFAULT_RETRY_AFTER);
+    }
+
+    #[test]
+    fn test_forbidden() {
+        let premium = r#"{"error": {"status": 403, "message": "Player command failed: Premium required", "reason": "PREMIUM_REQUIRED"}}"#;
+        assert_eq!(forbidden(premium), SpotifyError::PremiumRequired);
+        let scope = r#"{"error": {"status": 403, "message": "Insufficient client scope"}}"#;
+        assert_eq!(forbidden(scope), SpotifyError::MissingScope);
+        let unregistered =
+            "Check settings on developer.spotify.com/dashboard, the user may not be registered.";
+        assert_eq!(forbidden(unregistered), SpotifyError::UserNotRegistered);
+        let other = r#"{"error": {"status": 403, "message": "Restricted device"}}"#;
+        assert_eq!(
+            forbidden(other),
+            SpotifyError::Forbidden(String::from("Restricted device"))
+        );
+    }
+
+    #[test]
+    fn test_deserialize_track() {
+        let json = r#"{
+            "progress_ms": 1000,
+            "is_playing": true,
+            "currently_playing_type": "track",
+            "item": {
+                "name": "Song",
+                "duration_ms": 200000,
+                "artists": [{"name": "Artist"}],
+                "album": {"images": [{"url": "https://img/640", "width": 640}]}
+            }
+        }"#;
+        let playing = serde_json::from_str::<CurrentlyPlaying>(json).unwrap();
+        match playing.item {
+            PlayingItem::Track(Some(track)) => assert_eq!(track.name, "Song"),
+            other => panic!("Expected track, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_deserialize_album() {
+        let json = r#"{
+            "progress_ms": 1000,
+            "is_playing": true,
+            "currently_playing_type": "track",
+            "item": {
+                "name": "Song",
+                "duration_ms": 200000,
+                "artists": [{"name": "Artist"}],
+                "album": {
+                    "name": "Record",
+                    "release_date": "1999-12-31",
+                    "release_date_precision": "day",
+                    "images": [{"url": "https://img/640", "width": 640}]
+                }
+            }
+        }"#;
+        let playing = serde_json::from_str::<CurrentlyPlaying>(json).unwrap();
+        let model = PlayingModel::from(playing);
+        assert_eq!(model.album(), "Record");
+        assert_eq!(model.release_year(), Some("1999"));
+    }
+
+    #[test]
+    fn test_local_track_without_album_art() {
+        let json = r#"{
+            "progress_ms": 1000,
+            "is_playing": true,
+            "currently_playing_type": "track",
+            "item": {
+                "name": "Demo Tape",
+                "duration_ms": 200000,
+                "is_local": true,
+                "id": null,
+                "uri": "spotify:local:Band::Demo+Tape:200",
+                "artists": [{"name": "Band"}],
+                "album": {"images": []}
+            }
+        }"#;
+        let playing = serde_json::from_str::<CurrentlyPlaying>(json).unwrap();
+        let model = PlayingModel::from(playing);
+        assert_eq!(model.name(), "Demo Tape");
+        assert!(!model.has_image());
+        // local files have no id, but a uri to tell them apart
+        assert_eq!(model.uri(), "spotify:local:Band::Demo+Tape:200");
+    }
+
+    #[test]
+    fn test_deserialize_playback_state() {
+        let json = r#"{
+            "device": {"id": "abc", "name": "Kitchen", "is_active": true, "volume_percent": 40},
+            "shuffle_state": true,
+            "repeat_state": "context",
+            "progress_ms": 1000,
+            "is_playing": true,
+            "currently_playing_type": "track",
+            "item": {
+                "name": "Song",
+                "duration_ms": 200000,
+                "artists": [{"name": "Artist"}],
+                "album": {"images": [{"url": "https://img/640", "width": 640}]}
+            }
+        }"#;
+        let playback = serde_json::from_str::<PlaybackState>(json).unwrap();
+        let device = playback.device.unwrap();
+        assert_eq!(device.name, "Kitchen");
+        assert_eq!(device.volume_percent, Some(40));
+        assert!(playback.shuffle_state);
+        assert!(matches!(playback.current.item, PlayingItem::Track(Some(_))));
+    }
+
+    #[test]
+    fn test_deserialize_episode() {
+        let json = r#"{
+            "progress_ms": 1000,
+            "is_playing": true,
+            "currently_playing_type": "episode",
+            "item": {
+                "name": "Episode 1",
+                "duration_ms": 3600000,
+                "show": {
+                    "name": "Podcast",
+                    "images": [{"url": "https://img/show", "width": 640}]
+                }
+            }
+        }"#;
+        let playing = serde_json::from_str::<CurrentlyPlaying>(json).unwrap();
+        match playing.item {
+            PlayingItem::Episode(Some(episode)) => {
+                assert_eq!(episode.show.images[0].url, "https://img/show")
+            }
+            other => panic!("Expected episode, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_deserialize_episode_with_own_art() {
+        let json = r#"{
+            "progress_ms": 1000,
+            "is_playing": true,
+            "currently_playing_type": "episode",
+            "item": {
+                "name": "Episode 2",
+                "duration_ms": 3600000,
+                "images": [{"url": "https://img/episode", "width": 640}],
+                "show": {
+                    "name": "Podcast",
+                    "images": [{"url": "https://img/show", "width": 640}]
+                }
+            }
+        }"#;
+        let playing = serde_json::from_str::<CurrentlyPlaying>(json).unwrap();
+        let model = PlayingModel::from(playing);
+        assert_eq!(model.image_url, "https://img/episode");
+        assert_eq!(model.artists(), ["Podcast"]);
+    }
+
+    #[test]
+    fn test_deserialize_audiobook_chapter() {
+        let json = r#"{
+            "progress_ms": 1000,
+            "is_playing": true,
+            "currently_playing_type": "audiobook",
+            "item": {
+                "name": "Chapter 3",
+                "duration_ms": 1800000,
+                "audiobook": {
+                    "name": "Novel",
+                    "authors": [{"name": "Writer"}],
+                    "images": [
+                        {"url": "https://img/book/300", "width": 300},
+                        {"url": "https://img/book/640", "width": 640}
+                    ]
+                }
+            }
+        }"#;
+        let playing = serde_json::from_str::<CurrentlyPlaying>(json).unwrap();
+        assert!(matches!(playing.item, PlayingItem::Audiobook(Some(_))));
+        let model = PlayingModel::from(playing);
+        assert_eq!(model.name(), "Chapter 3");
+        assert_eq!(model.artists(), ["Writer"]);
+        assert_eq!(model.image_url, "https://img/book/640");
+
+        // chapters might be reported as such, with their own art and no audiobook
+        let json = r#"{
+            "progress_ms": 1000,
+            "is_playing": true,
+            "currently_playing_type": "chapter",
+            "item": {
+                "name": "Chapter 4",
+                "duration_ms": 1800000,
+                "images": [{"url": "https://img/chapter", "width": 640}]
+            }
+        }"#;
+        let playing = serde_json::from_str::<CurrentlyPlaying>(json).unwrap();
+        let model = PlayingModel::from(playing);
+        assert_eq!(model.image_url, "https://img/chapter");
+        assert!(model.artists().is_empty());
+    }
+
+    #[test]
+    fn test_deserialize_ad_and_unknown() {
+        let ad = r#"{"progress_ms": null, "is_playing": true, "currently_playing_type": "ad", "item": null}"#;
+        let playing = serde_json::from_str::<CurrentlyPlaying>(ad).unwrap();
+        assert!(matches!(playing.item, PlayingItem::Ad));
+
+        let unknown = r#"{"progress_ms": null, "is_playing": true, "currently_playing_type": "unknown", "item": null}"#;
+        let playing = serde_json::from_str::<CurrentlyPlaying>(unknown).unwrap();
+        assert!(matches!(playing.item, PlayingItem::Unknown));
+    }
+
+    #[test]
+    fn test_valid_for_after_drop() {
+        let access = SpotifyAccess::new(String::from("token"), None, 3600);
+        // the access is refreshed early, but spotify accepts the token for the whole hour
+        let valid_for = access.valid_for_after_drop();
+        assert!(valid_for <= Duration::from_secs(3600));
+        assert!(valid_for > Duration::from_secs(3590));
+    }
+}
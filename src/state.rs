@@ -1,56 +1,322 @@
+use crate::device_settings::{DeviceSettings, SettingsStore};
+use crate::device_token::DeviceTokens;
+use crate::elli::frame_log::LoggedFrame;
+use crate::elli::{ConnectionStatus, ElliConfig};
+use crate::metrics::Metrics;
 use crate::spotify::SpotifyAccess;
-use crate::update::ElliUpdate;
+use crate::token_store::TokenStore;
+use crate::update::{ElliUpdate, LiveSocket, MatrixFrame};
 use rand::distributions::{Alphanumeric, DistString};
+use serde::Serialize;
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, Mutex, OwnedMutexGuard};
+use tracing::{info, warn};
+use url::Url;
+
+// failed refreshes in a row, after which an expired access is given up
+const MAX_REFRESH_FAILURES: u32 = 3;
+// time a user has to log into spotify, before the callback is rejected
+const OAUTH_STATE_TTL: Duration = Duration::from_secs(600);
 
 pub struct AppState {
     spotify_user_access: RwLock<HashMap<String, Arc<SpotifyAccess>>>,
+    // a device is driven by one spotify account, that of the session which connected it. Other
+    // sessions can watch it, but have to take the device over explicitly to connect their own
+    // account. Accesses restored from the token store have no owner until a session claims them.
+    access_owners: RwLock<HashMap<String, String>>,
     elli_updates: RwLock<HashMap<String, RwLock<Option<ElliUpdate>>>>,
+    // held while an update of the ccc is started or stopped
+    device_locks: RwLock<HashMap<String, Arc<Mutex<()>>>>,
     spotify_credentials: SpotifyAppCredentials,
-    oauth_states: RwLock<HashMap<String, String>>,
+    oauth_states: RwLock<HashMap<String, OAuthState>>,
+    // failed refreshes in a row per ccc
+    refresh_failures: RwLock<HashMap<String, u32>>,
+    token_store: Arc<dyn TokenStore>,
+    metrics: Metrics,
+    device_tokens: DeviceTokens,
+    // bearer token for the routes across all devices. None disables them.
+    operator_token: Option<String>,
+    // settings per ccc, applied to every update of the device. Without a store, they are lost
+    // on a restart.
+    device_settings: RwLock<HashMap<String, DeviceSettings>>,
+    settings_store: Option<Arc<dyn SettingsStore>>,
+    // held while settings are merged and stored, so that the store gets the changes in order
+    settings_lock: Mutex<()>,
 }
 
 impl AppState {
     // deliberately move the secret.
-    pub fn new(spotify_secret: String) -> Self {
+    pub fn new(
+        spotify_id: String,
+        spotify_secret: String,
+        redirect_uri: Url,
+        token_store: Box<dyn TokenStore>,
+    ) -> Self {
+        let stored_access = match token_store.load() {
+            Ok(accesses) => accesses,
+            Err(e) => {
+                warn!("Failed to load stored spotify tokens: {}", e);
+                HashMap::new()
+            }
+        };
+        info!("Loaded {} stored spotify tokens", stored_access.len());
+        let spotify_user_access = stored_access
+            .into_iter()
+            .map(|(ccc, access)| (ccc, Arc::new(access)))
+            .collect();
+
         AppState {
-            spotify_user_access: RwLock::new(HashMap::new()),
+            spotify_user_access: RwLock::new(spotify_user_access),
+            access_owners: RwLock::new(HashMap::new()),
             elli_updates: RwLock::new(HashMap::new()),
+            device_locks: RwLock::new(HashMap::new()),
             oauth_states: RwLock::new(HashMap::new()),
-            spotify_credentials: SpotifyAppCredentials::new(spotify_secret),
+            refresh_failures: RwLock::new(HashMap::new()),
+            device_settings: RwLock::new(HashMap::new()),
+            settings_store: None,
+            settings_lock: Mutex::new(()),
+            spotify_credentials: SpotifyAppCredentials::new(
+                spotify_id,
+                spotify_secret,
+                redirect_uri,
+            ),
+            token_store: Arc::from(token_store),
+            metrics: Metrics::default(),
+            device_tokens: DeviceTokens::random(),
+            operator_token: None,
+        }
+    }
+
+    /// Signs device tokens with the given secret instead of a random one, so that issued
+    /// tokens stay valid across restarts, once their session owns the device again.
+    pub fn with_token_secret(mut self, secret: Vec<u8>) -> Self {
+        self.device_tokens = DeviceTokens::new(secret);
+        self
+    }
+
+    /// Enables the routes across all devices for callers showing the token.
+    pub fn with_operator_token(mut self, token: String) -> Self {
+        self.operator_token = Some(token);
+        self
+    }
+
+    /// Loads the settings of all devices from the store and saves every change to it.
+    pub fn with_settings_store(mut self, store: Box<dyn SettingsStore>) -> Self {
+        match store.load() {
+            Ok(settings) => {
+                info!("Loaded settings of {} devices", settings.len());
+                self.device_settings = RwLock::new(settings);
+            }
+            Err(e) => warn!("Failed to load stored device settings: {}", e),
         }
+        self.settings_store = Some(Arc::from(store));
+        self
     }
 
-    pub fn insert_access(&self, key: &str, access: SpotifyAccess) {
+    pub async fn insert_access(&self, key: &str, access: SpotifyAccess) {
+        let access = Arc::new(access);
+        let (ccc, stored) = (key.to_string(), access.clone());
+        if let Err(e) = persist(&self.token_store, move |store| store.save(&ccc, &stored)).await {
+            warn!("Failed to store spotify token for {}: {}", key, e);
+        }
         // I think unwrap is fine here, as the insert should not panic
         let mut tokens = self.spotify_user_access.write().unwrap();
-        tokens.insert(key.to_string(), Arc::new(access));
+        tokens.insert(key.to_string(), access);
+        self.refresh_failures.write().unwrap().remove(key);
+    }
+
+    pub fn record_refresh_failure(&self, key: &str) {
+        let mut failures = self.refresh_failures.write().unwrap();
+        *failures.entry(key.to_string()).or_insert(0) += 1;
+    }
+
+    /// Removes expired accesses which can't be refreshed anymore, because they have no refresh
+    /// token or their refresh failed repeatedly. Returns the cccs of the removed accesses.
+    pub async fn sweep_expired(&self) -> Vec<String> {
+        let expired: Vec<String> = {
+            let tokens = self.spotify_user_access.read().unwrap();
+            let failures = self.refresh_failures.read().unwrap();
+            tokens
+                .iter()
+                .filter(|(ccc, access)| {
+                    let failed = failures.get(*ccc).copied().unwrap_or(0) >= MAX_REFRESH_FAILURES;
+                    access.remaining().is_zero() && (access.refresh_token().is_none() || failed)
+                })
+                .map(|(ccc, _)| ccc.clone())
+                .collect()
+        };
+        for ccc in &expired {
+            self.remove_access(ccc).await;
+            self.remove_oauth_state(ccc);
+        }
+        expired
     }
 
     pub fn get_access(&self, key: &str) -> Option<Arc<SpotifyAccess>> {
         // I think unwrap is fine here, as the get should not panic
         let tokens = self.spotify_user_access.read().unwrap();
-        if let Some(access) = tokens.get(key) {
-            Some(access.clone())
-        } else {
-            None
-        }
+        tokens.get(key).cloned()
     }
 
-    pub fn remove_access(&self, key: &str) {
+    pub async fn remove_access(&self, key: &str) {
+        let ccc = key.to_string();
+        if let Err(e) = persist(&self.token_store, move |store| store.remove(&ccc)).await {
+            warn!("Failed to remove stored spotify token for {}: {}", key, e);
+        }
         let mut tokens = self.spotify_user_access.write().unwrap();
         tokens.remove(key);
+        self.refresh_failures.write().unwrap().remove(key);
+        self.access_owners.write().unwrap().remove(key);
+    }
+
+    /// Makes the session the owner of the device's access.
+    pub fn set_owner(&self, key: &str, session_id: String) {
+        let mut owners = self.access_owners.write().unwrap();
+        owners.insert(key.to_string(), session_id);
+    }
+
+    /// The session owning the device, if any.
+    pub fn owner(&self, key: &str) -> Option<String> {
+        self.access_owners.read().unwrap().get(key).cloned()
+    }
+
+    /// Whether the device is owned by another session than the given one. Devices without an
+    /// owner aren't.
+    pub fn is_owned_by_other(&self, key: &str, session_id: &str) -> bool {
+        let owners = self.access_owners.read().unwrap();
+        owners.get(key).is_some_and(|owner| owner != session_id)
     }
 
-    pub fn insert_elli_update(&self, key: &str, update: ElliUpdate) {
+    /// Stores the update for the device. Returns the update it replaces, which the caller has
+    /// to close.
+    pub fn insert_elli_update(&self, key: &str, update: ElliUpdate) -> Option<ElliUpdate> {
         let mut updates = self.elli_updates.write().unwrap();
-        updates.insert(key.to_string(), RwLock::new(Some(update)));
+        updates
+            .insert(key.to_string(), RwLock::new(Some(update)))
+            .and_then(|lock| lock.into_inner().unwrap())
+    }
+
+    /// Stops every running update and closes its socket. Used when shutting down.
+    pub async fn close_all(&self) {
+        let updates: Vec<(String, ElliUpdate)> = {
+            let mut updates = self.elli_updates.write().unwrap();
+            updates
+                .drain()
+                .filter_map(|(ccc, lock)| Some((ccc, lock.into_inner().unwrap()?)))
+                .collect()
+        };
+        for (ccc, update) in updates {
+            if let Err(e) = update.close().await {
+                warn!("Failed to close update for {}: {}", ccc, e);
+            }
+        }
+    }
+
+    /// Serializes starting and stopping the update of a device. Both await the socket, so
+    /// checking for an update and inserting one can't happen under the map's lock.
+    pub async fn lock_device(&self, key: &str) -> OwnedMutexGuard<()> {
+        let lock = self
+            .device_locks
+            .write()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+
+    /// Cccs of all devices with a running update, sorted.
+    pub fn all_device_cccs(&self) -> Vec<String> {
+        let updates = self.elli_updates.read().unwrap();
+        let mut cccs: Vec<_> = updates
+            .iter()
+            .filter(|(_, lock)| lock.read().unwrap().is_some())
+            .map(|(ccc, _)| ccc.clone())
+            .collect();
+        cccs.sort();
+        cccs
     }
 
-    pub fn has_update(&self, key: &str) -> bool {
+    /// Config of the running update for the device. None, if there is no running update.
+    pub fn update_config(&self, key: &str) -> Option<ElliConfig> {
         let updates = self.elli_updates.read().unwrap();
-        updates.contains_key(key)
+        updates.get(key).and_then(|lock| {
+            let update = lock.read().unwrap();
+            update.as_ref().map(|u| u.config())
+        })
+    }
+
+    /// Lets the running update for the device poll spotify right away. Returns false, if there
+    /// is no running update.
+    pub fn refresh(&self, key: &str) -> bool {
+        let updates = self.elli_updates.read().unwrap();
+        updates
+            .get(key)
+            .and_then(|lock| {
+                let update = lock.read().unwrap();
+                update.as_ref().map(|u| u.refresh())
+            })
+            .is_some()
+    }
+
+    /// The saved settings of the device. Empty, if none were changed yet.
+    pub fn settings(&self, key: &str) -> DeviceSettings {
+        let settings = self.device_settings.read().unwrap();
+        settings.get(key).cloned().unwrap_or_default()
+    }
+
+    /// Merges the changes into the saved settings of the device and applies them to the
+    /// running update, if there is one. Returns the saved settings.
+    pub async fn update_settings(&self, key: &str, changes: DeviceSettings) -> DeviceSettings {
+        let _guard = self.settings_lock.lock().await;
+        let settings = {
+            let mut all = self.device_settings.write().unwrap();
+            let settings = all.entry(key.to_string()).or_default();
+            settings.merge(changes.clone());
+            settings.clone()
+        };
+        if let Some(store) = &self.settings_store {
+            let (ccc, stored) = (key.to_string(), settings.clone());
+            if let Err(e) = persist(store, move |store| store.save(&ccc, &stored)).await {
+                warn!("Failed to store settings for {}: {}", key, e);
+            }
+        }
+        let updates = self.elli_updates.read().unwrap();
+        if let Some(lock) = updates.get(key) {
+            if let Some(update) = lock.read().unwrap().as_ref() {
+                update.apply_settings(&changes);
+            }
+        }
+        settings
+    }
+
+    /// Text frames on the socket of the running update. None, if there is no running update.
+    pub fn frame_log(&self, key: &str) -> Option<Vec<LoggedFrame>> {
+        let updates = self.elli_updates.read().unwrap();
+        updates.get(key).and_then(|lock| {
+            let update = lock.read().unwrap();
+            update.as_ref().map(|u| u.frames())
+        })
+    }
+
+    /// Frames painted by the running update for the device. None, if there is no running update.
+    pub fn subscribe_frames(&self, key: &str) -> Option<broadcast::Receiver<MatrixFrame>> {
+        let updates = self.elli_updates.read().unwrap();
+        updates.get(key).and_then(|lock| {
+            let update = lock.read().unwrap();
+            update.as_ref().map(|u| u.subscribe())
+        })
+    }
+
+    /// The socket of the running update for the device. None, if there is no running update.
+    pub fn socket(&self, key: &str) -> Option<LiveSocket> {
+        let updates = self.elli_updates.read().unwrap();
+        updates.get(key).and_then(|lock| {
+            let update = lock.read().unwrap();
+            update.as_ref().map(|u| u.socket())
+        })
     }
 
     pub fn remove_elli_update(&self, key: &str) -> Option<ElliUpdate> {
@@ -62,40 +328,187 @@ impl AppState {
         None
     }
 
+    /// Counts of the stored state. Only the numbers and statuses are copied under the locks, so
+    /// that the update workers aren't blocked for long.
+    pub fn stats(&self) -> AppStats {
+        let spotify_accesses = self.spotify_user_access.read().unwrap().len();
+        let updates = self.elli_updates.read().unwrap();
+        let mut devices = HashMap::new();
+        for lock in updates.values() {
+            let status = lock.read().unwrap().as_ref().and_then(|u| u.status());
+            let key = match status {
+                Some(status) => format!("{:?}", status),
+                None => String::from("NotConnected"),
+            };
+            *devices.entry(key).or_insert(0) += 1;
+        }
+        AppStats {
+            active_updates: updates.len(),
+            spotify_accesses,
+            devices,
+        }
+    }
+
+    /// Every device with a running update and what it shows, ordered by ccc.
+    pub fn devices(&self) -> Vec<DeviceSummary> {
+        let updates = self.elli_updates.read().unwrap();
+        let mut devices: Vec<DeviceSummary> = updates
+            .iter()
+            .filter_map(|(ccc, lock)| {
+                let update = lock.read().unwrap();
+                let update = update.as_ref()?;
+                let now_playing = update.now_playing();
+                let track = now_playing.as_ref().map(|n| &n.track);
+                Some(DeviceSummary {
+                    ccc: ccc.clone(),
+                    device_name: now_playing.as_ref().and_then(|n| n.device_name.clone()),
+                    status: update.status(),
+                    track_name: track.map(|t| t.name().to_string()),
+                    artists: track.map(|t| t.artists().to_vec()).unwrap_or_default(),
+                    image_url: track.filter(|t| t.has_image()).map(|t| t.image_url.clone()),
+                })
+            })
+            .collect();
+        devices.sort_by(|a, b| a.ccc.cmp(&b.ccc));
+        devices
+    }
+
+    /// Connection of the device, also for devices without a running update.
+    pub fn device_status(&self, key: &str) -> DeviceStatus {
+        let updates = self.elli_updates.read().unwrap();
+        let Some(lock) = updates.get(key) else {
+            return DeviceStatus::default();
+        };
+        let update = lock.read().unwrap();
+        match update.as_ref() {
+            Some(update) => DeviceStatus {
+                running: true,
+                status: update.status(),
+                device_name: update.now_playing().and_then(|n| n.device_name),
+            },
+            None => DeviceStatus::default(),
+        }
+    }
+
     pub fn get_spotify_credentials(&self) -> &SpotifyAppCredentials {
         &self.spotify_credentials
     }
 
-    pub fn insert_oauth_state(&self, key: &str, state: String) {
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn device_tokens(&self) -> &DeviceTokens {
+        &self.device_tokens
+    }
+
+    pub fn operator_token(&self) -> Option<&str> {
+        self.operator_token.as_deref()
+    }
+
+    pub fn insert_oauth_state(&self, key: &str, state: OAuthState) {
         let mut oauth_states = self.oauth_states.write().unwrap();
         oauth_states.insert(key.to_string(), state);
     }
 
-    pub fn get_oauth_state(&self, key: &str) -> Option<String> {
+    pub fn get_oauth_state(&self, key: &str) -> Option<OAuthState> {
         let oauth_states = self.oauth_states.read().unwrap();
-        if let Some(state) = oauth_states.get(key) {
-            Some(state.clone())
-        } else {
-            None
-        }
+        oauth_states.get(key).cloned()
     }
 
     pub fn remove_oauth_state(&self, key: &str) {
         let mut oauth_states = self.oauth_states.write().unwrap();
         oauth_states.remove(key);
     }
+
+    /// Removes the states of logins which were abandoned. Returns how many were removed.
+    pub fn sweep_oauth_states(&self) -> usize {
+        let mut oauth_states = self.oauth_states.write().unwrap();
+        let before = oauth_states.len();
+        oauth_states.retain(|_, state| !state.is_expired());
+        before - oauth_states.len()
+    }
+
+    /// Removes the locks of devices nobody holds or waits for. Returns how many were removed.
+    pub fn sweep_device_locks(&self) -> usize {
+        let mut locks = self.device_locks.write().unwrap();
+        let before = locks.len();
+        // holders and waiters keep a clone, which they only get under the write lock
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        before - locks.len()
+    }
+}
+
+/// A spotify login started by a session, waiting for its callback.
+#[derive(Clone)]
+pub struct OAuthState {
+    pub(crate) state: String,
+    // random id kept in the session cookie, so that only the session which started the login
+    // can finish it
+    pub(crate) session_id: String,
+    created: Instant,
+}
+
+impl OAuthState {
+    pub fn new(state: String, session_id: String) -> Self {
+        Self {
+            state,
+            session_id,
+            created: Instant::now(),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.created.elapsed() > OAUTH_STATE_TTL
+    }
+}
+
+#[derive(Serialize)]
+pub struct AppStats {
+    pub active_updates: usize,
+    pub spotify_accesses: usize,
+    // devices per status of their socket, NotConnected until the worker has connected. Only
+    // counts, as the health check is open to everybody and the ccc would let them drive the
+    // lamps. Operators get the status per ccc from the device list.
+    pub devices: HashMap<String, usize>,
+}
+
+/// A device with a running update, as listed by the api. Track fields are empty while
+/// nothing plays.
+#[derive(Serialize)]
+pub struct DeviceSummary {
+    pub ccc: String,
+    // the spotify device playing, e.g. a phone or speaker
+    pub device_name: Option<String>,
+    pub status: Option<ConnectionStatus>,
+    pub track_name: Option<String>,
+    pub artists: Vec<String>,
+    pub image_url: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+pub struct DeviceStatus {
+    // whether an update is running for the device
+    pub running: bool,
+    // None until the update has painted for the first time
+    pub status: Option<ConnectionStatus>,
+    // the spotify device playing, e.g. a phone or speaker
+    pub device_name: Option<String>,
 }
 
 pub struct SpotifyAppCredentials {
     client_id: String,
     client_secret: String,
+    // must match one of the redirect uris registered for the app with spotify
+    redirect_uri: Url,
 }
 
 impl SpotifyAppCredentials {
-    fn new(client_secret: String) -> Self {
+    fn new(client_id: String, client_secret: String, redirect_uri: Url) -> Self {
         Self {
-            client_id: "38f14e6cbed74638857280d0165bc93a".to_string(),
+            client_id,
             client_secret,
+            redirect_uri,
         }
     }
 
@@ -106,8 +519,146 @@ impl SpotifyAppCredentials {
     pub fn id(&self) -> &str {
         &self.client_id
     }
+
+    pub fn redirect_uri(&self) -> &str {
+        self.redirect_uri.as_str()
+    }
+}
+
+// runs a change of a store on the blocking pool, so that the file I/O doesn't stall the runtime.
+// Callers only log a failed change, the in-memory maps stay the source of truth.
+async fn persist<S, F>(store: &Arc<S>, change: F) -> Result<(), String>
+where
+    S: ?Sized + Send + Sync + 'static,
+    F: FnOnce(&S) -> Result<(), Box<dyn std::error::Error>> + Send + 'static,
+{
+    let store = store.clone();
+    tokio::task::spawn_blocking(move || change(store.as_ref()).map_err(|e| e.to_string()))
+        .await
+        .map_err(|e| e.to_string())?
 }
 
 pub fn rnd_string() -> String {
     Alphanumeric.sample_string(&mut rand::thread_rng(), 32)
 }
+
+/// App state for tests. Its tokens only live in its maps, nothing is stored on disk.
+#[cfg(test)]
+pub(crate) fn test_state() -> AppState {
+    struct NoTokenStore;
+
+    impl TokenStore for NoTokenStore {
+        fn load(&self) -> Result<HashMap<String, SpotifyAccess>, Box<dyn std::error::Error>> {
+            Ok(HashMap::new())
+        }
+
+        fn save(&self, _: &str, _: &SpotifyAccess) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+
+        fn remove(&self, _: &str) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+    }
+
+    let redirect_uri = Url::parse("http://127.0.0.1:3000/spotify/callback").unwrap();
+    AppState::new(
+        String::from("id"),
+        String::from("secret"),
+        redirect_uri,
+        Box::new(NoTokenStore),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refresh_token() -> Option<String> {
+        Some(String::from("refresh"))
+    }
+
+    #[tokio::test]
+    async fn test_sweep_expired() {
+        let state = test_state();
+        let access =
+            |refresh, remaining| SpotifyAccess::restore(String::from("a"), refresh, remaining);
+        state.insert_access("valid", access(None, 3600)).await;
+        state.insert_access("no-refresh", access(None, 0)).await;
+        state
+            .insert_access("refreshable", access(refresh_token(), 0))
+            .await;
+        state
+            .insert_access("failing", access(refresh_token(), 0))
+            .await;
+        state.insert_oauth_state(
+            "no-refresh",
+            OAuthState::new(String::from("state"), String::from("session")),
+        );
+        for _ in 0..MAX_REFRESH_FAILURES {
+            state.record_refresh_failure("failing");
+        }
+
+        let mut evicted = state.sweep_expired().await;
+        evicted.sort();
+        assert_eq!(evicted, vec!["failing", "no-refresh"]);
+        assert!(state.get_access("valid").is_some());
+        assert!(state.get_access("refreshable").is_some());
+        assert!(state.get_oauth_state("no-refresh").is_none());
+    }
+
+    #[test]
+    fn test_sweep_oauth_states() {
+        let state = test_state();
+        let started = OAuthState::new(String::from("state"), String::from("session"));
+        let abandoned = OAuthState {
+            created: Instant::now() - OAUTH_STATE_TTL - Duration::from_secs(1),
+            ..started.clone()
+        };
+        assert!(abandoned.is_expired());
+        state.insert_oauth_state("started", started);
+        state.insert_oauth_state("abandoned", abandoned);
+
+        assert_eq!(state.sweep_oauth_states(), 1);
+        assert!(state.get_oauth_state("started").is_some());
+        assert!(state.get_oauth_state("abandoned").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lock_device() {
+        let state = test_state();
+        let guard = state.lock_device("a").await;
+        let wait = Duration::from_millis(10);
+        // another device isn't blocked, the same one is until the guard is dropped
+        assert!(tokio::time::timeout(wait, state.lock_device("b"))
+            .await
+            .is_ok());
+        assert!(tokio::time::timeout(wait, state.lock_device("a"))
+            .await
+            .is_err());
+        drop(guard);
+        let guard = tokio::time::timeout(wait, state.lock_device("a")).await;
+        assert!(guard.is_ok());
+
+        // only the lock which is held stays
+        assert_eq!(state.sweep_device_locks(), 1);
+        drop(guard);
+        assert_eq!(state.sweep_device_locks(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_owner_goes_with_access() {
+        let state = test_state();
+        state
+            .insert_access("ccc", SpotifyAccess::restore(String::from("a"), None, 3600))
+            .await;
+        assert!(!state.is_owned_by_other("ccc", "mine"));
+
+        state.set_owner("ccc", String::from("theirs"));
+        assert!(state.is_owned_by_other("ccc", "mine"));
+        assert!(!state.is_owned_by_other("ccc", "theirs"));
+
+        state.remove_access("ccc").await;
+        assert!(!state.is_owned_by_other("ccc", "mine"));
+    }
+}
@@ -1,8 +1,13 @@
-use crate::spotify::CurrentlyPlaying;
+use crate::elli::Calibration;
+use crate::spotify::{CurrentlyPlaying, Image, PlaybackState, PlayingItem};
+use actix_web::error::ErrorInternalServerError;
 use actix_web::HttpResponse;
 use askama::Template;
+use std::time::Duration;
+use tracing::error;
 
 // Template definitions
+#[expect(dead_code, reason = "no route serves connect.html")]
 #[derive(Template)]
 #[template(path = "connect.html")]
 pub struct ConnectTemplate {
@@ -25,6 +30,32 @@ pub struct ConnectedDeviceTemplate {
 #[template(path = "connected.html")]
 pub struct ConnectedTemplate {
     pub(crate) player_status: PlayingModel,
+    pub(crate) playback: PlaybackModel,
+    pub(crate) matrix_model: ColorMatrixModel,
+}
+
+/// The now playing page, which follows the lamp. The first frame is rendered on the server,
+/// later ones come in over `stream_url`, while `status_url` is polled for the connection.
+#[derive(Template)]
+#[template(path = "live.html")]
+pub struct LivePlayingTemplate {
+    pub(crate) ccc: String,
+    pub(crate) player_status: PlayingModel,
+    pub(crate) matrix_model: ColorMatrixModel,
+    pub(crate) stream_url: String,
+    pub(crate) status_url: String,
+}
+
+/// The calibration page, showing the test pattern painted on the lamp with the values tried.
+#[derive(Template)]
+#[template(path = "calibrate.html")]
+pub struct CalibrateTemplate {
+    pub(crate) ccc: String,
+    pub(crate) pattern: &'static str,
+    pub(crate) patterns: Vec<&'static str>,
+    // the values tried, and the ones which the lamp goes back to without saving
+    pub(crate) calibration: Calibration,
+    pub(crate) saved: Calibration,
     pub(crate) matrix_model: ColorMatrixModel,
 }
 
@@ -41,11 +72,21 @@ pub struct ErrorTemplate {
     pub description: String,
 }
 
-pub fn into_response<T: Template>(template: T) -> HttpResponse {
+/// Renders the template into a response. Render errors are logged together with the template
+/// and end up as internal server error. Debug builds show the error text in the response.
+pub fn into_response<T: Template>(template: T) -> Result<HttpResponse, actix_web::Error> {
     match template.render() {
-        Ok(rendered) => HttpResponse::Ok().body(rendered),
-        Err(_) => HttpResponse::InternalServerError()
-            .body("<h1>Error</h1><p>Uh oh, an error while rendering a template</p>"),
+        Ok(rendered) => Ok(HttpResponse::Ok().body(rendered)),
+        Err(e) => {
+            let name = std::any::type_name::<T>();
+            error!("Failed to render template {}: {}", name, e);
+            let body = if cfg!(debug_assertions) {
+                format!("Failed to render template {}: {}", name, e)
+            } else {
+                String::from("Uh oh, an error while rendering a template")
+            };
+            Err(ErrorInternalServerError(body))
+        }
     }
 }
 
@@ -54,45 +95,178 @@ pub struct ColorMatrixModel {
     pub colors: Vec<String>, // Flattened row-major hex color strings
 }
 
+/// How spotify is playing, next to what it is playing.
+pub struct PlaybackModel {
+    // the active spotify device, e.g. a phone or speaker
+    pub device_name: Option<String>,
+    pub volume_percent: Option<u32>,
+    pub shuffle: bool,
+    // off, track or context
+    pub repeat: String,
+}
+
+impl From<&PlaybackState> for PlaybackModel {
+    fn from(value: &PlaybackState) -> Self {
+        let device = value.device.as_ref().filter(|d| d.is_active);
+        Self {
+            device_name: device.map(|d| d.name.clone()),
+            volume_percent: device.and_then(|d| d.volume_percent),
+            shuffle: value.shuffle_state,
+            repeat: value.repeat_state.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct PlayingModel {
-    // is_playing: bool,
-    // progress_ms: u64,
-    // currently_playing_type: String,
+    pub is_playing: bool,
+    pub progress_ms: Option<u64>,
+    pub duration_ms: Option<u64>,
+    // spotify uri of the item. Empty for items spotify has no data for.
+    uri: String,
     name: String,
     artists: Vec<String>,
-    // album: String,
+    // the album of a track, the audiobook of a chapter. Empty for everything else.
+    album: String,
+    release_date: Option<String>,
     pub image_url: String,
 }
 
+impl PlayingModel {
+    /// Fraction of the track that has been played. None if the duration is unknown.
+    pub fn progress(&self) -> Option<f32> {
+        match (self.progress_ms, self.duration_ms) {
+            (Some(progress), Some(duration)) if duration > 0 => {
+                Some(progress as f32 / duration as f32)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether spotify has album art for the item. Local files have none.
+    pub fn has_image(&self) -> bool {
+        !self.image_url.is_empty()
+    }
+
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn artists(&self) -> &[String] {
+        &self.artists
+    }
+
+    pub fn album(&self) -> &str {
+        &self.album
+    }
+
+    /// The year the album was released in, whatever else spotify knows about the date.
+    pub fn release_year(&self) -> Option<&str> {
+        self.release_date
+            .as_deref()
+            .and_then(|date| date.split('-').next())
+            .filter(|year| !year.is_empty())
+    }
+
+    /// Time until the track ends. None if it is paused or the duration is unknown.
+    pub fn remaining(&self) -> Option<Duration> {
+        match (self.is_playing, self.progress_ms, self.duration_ms) {
+            (true, Some(progress), Some(duration)) => {
+                Some(Duration::from_millis(duration.saturating_sub(progress)))
+            }
+            _ => None,
+        }
+    }
+}
+
 impl From<CurrentlyPlaying> for PlayingModel {
     fn from(value: CurrentlyPlaying) -> Self {
-        if let Some(track) = value.item {
-            let artists = track.artists.into_iter().map(|a| a.name).collect();
-            let image_url = track
-                .album
-                .images
-                .into_iter()
-                .max_by(|a, b| a.width.cmp(&b.width))
-                .unwrap_or_default()
-                .url;
-            Self {
-                // is_playing: value.is_playing,
-                // progress_ms: value.progress_ms,
-                // currently_playing_type: value.currently_playing_type,
-                name: track.name,
-                artists,
-                image_url,
+        let type_name = value.item.type_name();
+        match value.item {
+            PlayingItem::Track(Some(track)) => {
+                let artists = track.artists.into_iter().map(|a| a.name).collect();
+                Self {
+                    is_playing: value.is_playing,
+                    progress_ms: value.progress_ms,
+                    duration_ms: Some(track.duration_ms),
+                    uri: track.uri,
+                    name: track.name,
+                    artists,
+                    album: track.album.name,
+                    release_date: track.album.release_date,
+                    image_url: largest_image_url(track.album.images),
+                }
+            }
+            PlayingItem::Episode(Some(episode)) => Self {
+                is_playing: value.is_playing,
+                progress_ms: value.progress_ms,
+                duration_ms: Some(episode.duration_ms),
+                uri: episode.uri,
+                name: episode.name,
+                artists: vec![episode.show.name],
+                album: String::new(),
+                release_date: None,
+                image_url: art_url([episode.images, episode.show.images]),
+            },
+            PlayingItem::Audiobook(Some(chapter)) => {
+                let (artists, album, book_images) = match chapter.audiobook {
+                    Some(book) => {
+                        let mut authors: Vec<_> =
+                            book.authors.into_iter().map(|a| a.name).collect();
+                        // like the show of an episode
+                        if authors.is_empty() {
+                            authors.push(book.name.clone());
+                        }
+                        (authors, book.name, book.images)
+                    }
+                    None => (Vec::new(), String::new(), Vec::new()),
+                };
+                Self {
+                    is_playing: value.is_playing,
+                    progress_ms: value.progress_ms,
+                    duration_ms: Some(chapter.duration_ms),
+                    uri: chapter.uri,
+                    name: chapter.name,
+                    artists,
+                    album,
+                    release_date: None,
+                    image_url: art_url([chapter.images, book_images]),
+                }
             }
-        } else {
-            Self {
-                // is_playing: value.is_playing,
-                // progress_ms: value.progress_ms,
-                // currently_playing_type: value.currently_playing_type.clone(),
-                name: value.currently_playing_type.to_string(),
+            _ => Self {
+                is_playing: value.is_playing,
+                progress_ms: value.progress_ms,
+                duration_ms: None,
+                uri: String::new(),
+                name: type_name.to_string(),
                 artists: vec!["No data available for currently playing media".to_string()],
+                album: String::new(),
+                release_date: None,
                 image_url: "https://elemonlabs.com/wp-content/uploads/2020/08/logo_transparent.png"
                     .to_string(),
-            }
+            },
         }
     }
 }
+
+// the largest image of the first place which has art, e.g. the episode before its show. Empty
+// if none has.
+fn art_url<const N: usize>(places: [Vec<Image>; N]) -> String {
+    places
+        .into_iter()
+        .find(|images| !images.is_empty())
+        .map(largest_image_url)
+        .unwrap_or_default()
+}
+
+fn largest_image_url(images: Vec<Image>) -> String {
+    images
+        .into_iter()
+        .max_by(|a, b| a.width.cmp(&b.width))
+        .unwrap_or_default()
+        .url
+}
@@ -0,0 +1,92 @@
+//! Tiny bitmap font to write track names onto the matrix.
+
+/// Rows of every glyph. Text needs at least this many rows of the matrix.
+pub const GLYPH_HEIGHT: usize = 5;
+const GLYPH_WIDTH: usize = 3;
+
+// one row per entry, the lowest three bits are the columns with the leftmost one first
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        'A' => [2, 5, 7, 5, 5],
+        'B' => [6, 5, 6, 5, 6],
+        'C' => [3, 4, 4, 4, 3],
+        'D' => [6, 5, 5, 5, 6],
+        'E' => [7, 4, 6, 4, 7],
+        'F' => [7, 4, 6, 4, 4],
+        'G' => [3, 4, 5, 5, 3],
+        'H' => [5, 5, 7, 5, 5],
+        'I' => [7, 2, 2, 2, 7],
+        'J' => [1, 1, 1, 5, 2],
+        'K' => [5, 5, 6, 5, 5],
+        'L' => [4, 4, 4, 4, 7],
+        'M' => [5, 7, 7, 5, 5],
+        'N' => [6, 5, 5, 5, 5],
+        'O' => [2, 5, 5, 5, 2],
+        'P' => [6, 5, 6, 4, 4],
+        'Q' => [2, 5, 5, 6, 3],
+        'R' => [6, 5, 6, 5, 5],
+        'S' => [3, 4, 2, 1, 6],
+        'T' => [7, 2, 2, 2, 2],
+        'U' => [5, 5, 5, 5, 7],
+        'V' => [5, 5, 5, 5, 2],
+        'W' => [5, 5, 7, 7, 5],
+        'X' => [5, 5, 2, 5, 5],
+        'Y' => [5, 5, 2, 2, 2],
+        'Z' => [7, 1, 2, 4, 7],
+        '0' => [7, 5, 5, 5, 7],
+        '1' => [2, 6, 2, 2, 7],
+        '2' => [6, 1, 2, 4, 7],
+        '3' => [6, 1, 2, 1, 6],
+        '4' => [5, 5, 7, 1, 1],
+        '5' => [7, 4, 6, 1, 6],
+        '6' => [3, 4, 7, 5, 7],
+        '7' => [7, 1, 2, 2, 2],
+        '8' => [7, 5, 7, 5, 7],
+        '9' => [7, 5, 7, 1, 6],
+        ' ' => [0, 0, 0, 0, 0],
+        '-' => [0, 0, 7, 0, 0],
+        '.' => [0, 0, 0, 0, 2],
+        ',' => [0, 0, 0, 2, 4],
+        ':' => [0, 2, 0, 2, 0],
+        '!' => [2, 2, 2, 0, 2],
+        '\'' => [2, 2, 0, 0, 0],
+        '&' => [2, 5, 2, 5, 3],
+        '(' => [1, 2, 2, 2, 1],
+        ')' => [4, 2, 2, 2, 4],
+        '/' => [1, 1, 2, 4, 4],
+        // everything else, e.g. letters with accents
+        _ => [7, 1, 2, 0, 2],
+    }
+}
+
+/// Lit pixels of the text, one row of columns per glyph row. Glyphs are separated by an empty
+/// column.
+pub fn rasterize(text: &str) -> Vec<Vec<bool>> {
+    let mut rows = vec![Vec::new(); GLYPH_HEIGHT];
+    for (i, c) in text.chars().enumerate() {
+        let glyph = glyph(c);
+        for (row, bits) in rows.iter_mut().zip(glyph) {
+            if i > 0 {
+                row.push(false);
+            }
+            row.extend((0..GLYPH_WIDTH).map(|col| bits & (4 >> col) != 0));
+        }
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rasterize() {
+        let rows = rasterize("hi");
+        assert_eq!(rows.len(), GLYPH_HEIGHT);
+        assert_eq!(rows[0].len(), 2 * GLYPH_WIDTH + 1);
+        // H, a gap, then I
+        assert_eq!(rows[0], [true, false, true, false, true, true, true]);
+        assert_eq!(rows[2], [true, true, true, false, false, true, false]);
+        assert!(rasterize("").iter().all(|row| row.is_empty()));
+    }
+}
@@ -0,0 +1,129 @@
+use crate::file_store::JsonFileMap;
+use crate::spotify::SpotifyAccess;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Persists Spotify accesses keyed by ccc, so that devices stay linked across restarts.
+pub trait TokenStore: Send + Sync {
+    fn load(&self) -> Result<HashMap<String, SpotifyAccess>, Box<dyn Error>>;
+    fn save(&self, ccc: &str, access: &SpotifyAccess) -> Result<(), Box<dyn Error>>;
+    fn remove(&self, ccc: &str) -> Result<(), Box<dyn Error>>;
+}
+
+// `Instant` can't be serialized, so we store when the access expires as unix seconds and
+// reconstruct the expiry when loading. Accesses stored without one load as expired, so that
+// they are refreshed on first use.
+#[derive(Serialize, Deserialize)]
+struct StoredAccess {
+    access_token: String,
+    refresh_token: Option<String>,
+    #[serde(default)]
+    expires_at: u64,
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl From<&SpotifyAccess> for StoredAccess {
+    fn from(access: &SpotifyAccess) -> Self {
+        Self {
+            access_token: access.access_token().to_string(),
+            refresh_token: access.refresh_token().clone(),
+            expires_at: unix_secs(SystemTime::now() + access.remaining()),
+        }
+    }
+}
+
+impl From<StoredAccess> for SpotifyAccess {
+    fn from(stored: StoredAccess) -> Self {
+        // time passed while the app was down counts against the access
+        let remaining = stored
+            .expires_at
+            .saturating_sub(unix_secs(SystemTime::now()));
+        SpotifyAccess::restore(stored.access_token, stored.refresh_token, remaining)
+    }
+}
+
+/// Keeps all accesses in a single JSON file, which is rewritten on every change.
+pub struct FileTokenStore {
+    file: JsonFileMap<StoredAccess>,
+}
+
+impl FileTokenStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            file: JsonFileMap::new(path),
+        }
+    }
+}
+
+impl TokenStore for FileTokenStore {
+    fn load(&self) -> Result<HashMap<String, SpotifyAccess>, Box<dyn Error>> {
+        let accesses = self
+            .file
+            .load()?
+            .into_iter()
+            .map(|(ccc, stored)| (ccc, SpotifyAccess::from(stored)))
+            .collect();
+        Ok(accesses)
+    }
+
+    fn save(&self, ccc: &str, access: &SpotifyAccess) -> Result<(), Box<dyn Error>> {
+        self.file.insert(ccc, StoredAccess::from(access))
+    }
+
+    fn remove(&self, ccc: &str) -> Result<(), Box<dyn Error>> {
+        self.file.remove(ccc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn test_file_token_store_round_trip() {
+        let path = std::env::temp_dir().join(format!("elli-tokens-{}.json", std::process::id()));
+        let store = FileTokenStore::new(path.clone());
+        let access = SpotifyAccess::new("access".to_string(), Some("refresh".to_string()), 3600);
+
+        store.save("ccc1", &access).unwrap();
+        store.save("ccc2", &access).unwrap();
+        store.remove("ccc2").unwrap();
+        let loaded = store.load().unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded.len(), 1);
+        let loaded_access = &loaded["ccc1"];
+        assert_eq!(loaded_access.access_token(), "access");
+        assert_eq!(loaded_access.refresh_token().as_deref(), Some("refresh"));
+        assert!(!loaded_access.should_refresh());
+        // the expiry is restored to the second, not extended by another refresh margin
+        assert!(loaded_access.remaining() <= access.remaining() + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_expiry_counts_while_stored() {
+        let path = std::env::temp_dir().join(format!("elli-expired-{}.json", std::process::id()));
+        let stored_at = unix_secs(SystemTime::now()) - 60;
+        let content = format!(
+            r#"{{"ccc1": {{"access_token": "access", "refresh_token": null, "expires_at": {}}},
+                "ccc2": {{"access_token": "access", "refresh_token": null}}}}"#,
+            stored_at
+        );
+        fs::write(&path, content).unwrap();
+        let loaded = FileTokenStore::new(path.clone()).load().unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert!(loaded["ccc1"].remaining().is_zero());
+        assert!(loaded["ccc2"].remaining().is_zero());
+    }
+}
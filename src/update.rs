@@ -1,24 +1,185 @@
-use crate::elli::elli_connection::ElliConnection;
+use crate::device_settings::DeviceSettings;
+use crate::elli::elli_connection::{ElliConnection, ReconnectPolicy};
+use crate::elli::frame_log::{FrameLog, LoggedFrame};
 use crate::elli::messages::websocket::PixelData;
-use crate::elli::ElliConfig;
-use crate::spotify::SpotifyClient;
+use crate::elli::{ConnectionStatus, ElliConfig, PausedBehavior, RenderMode};
+use crate::render;
+use crate::spotify::{ImageError, SpotifyClient, SpotifyError};
 use crate::state::AppState;
-use crate::templates::PlayingModel;
-use actix_web::error::ErrorInternalServerError;
+use crate::templates::{PlaybackModel, PlayingModel};
 use actix_web::web;
-use image::imageops::FilterType;
-use image::GenericImageView;
-use log::info;
+use image::DynamicImage;
+use rand::Rng;
+use serde::Serialize;
 use std::error::Error;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::{oneshot, RwLock};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Mutex, RwLock};
 use tokio::task::JoinHandle;
-use tokio::time::interval;
+use tokio::time::{interval, sleep, sleep_until, timeout, Instant};
+use tracing::{info, info_span, warn, Instrument, Span};
+
+// suffix of the frame key while the album art is altered because playback is paused
+const PAUSED_FRAME_KEY: &str = "#paused";
+// frame key while the idle image is shown, which no album art url can match
+const IDLE_FRAME_KEY: &str = "#idle";
+// stands in for the album art url of items without album art
+const NO_ART_FRAME_KEY: &str = "#noart";
+// how long after the expected end of a track we poll for the next one
+const TRACK_END_MARGIN: Duration = Duration::from_millis(1500);
+// polls come this often while no track is about to end, so that skips show up quickly
+const REGULAR_POLL_INTERVAL: Duration = Duration::from_secs(3);
+// failed updates in a row, after which the device is reported as broken
+const MAX_CONSECUTIVE_FAILURES: u32 = 5;
+// frames buffered for slow stream subscribers. Older ones are skipped.
+const FRAME_BUFFER: usize = 4;
+// how long each step of a scrolling title is shown
+const TITLE_SCROLL_DELAY: Duration = Duration::from_millis(150);
+// how long each frame of the attract animation is shown
+const ATTRACT_FRAME_DELAY: Duration = Duration::from_millis(500);
+// frames of the fade to black on close, the last one all black
+const FADE_OUT_STEPS: u32 = 8;
+const FADE_OUT_STEP_DELAY: Duration = Duration::from_millis(80);
+// time the fade on close may take on top of painting its steps, before the socket is closed
+// regardless
+const FADE_OUT_SLACK: Duration = Duration::from_secs(3);
+// quiet time after a refresh request before it runs, e.g. while skipping through tracks
+const REFRESH_DEBOUNCE: Duration = Duration::from_millis(300);
+// longest a burst of refresh requests can hold off the update
+const REFRESH_DEBOUNCE_MAX: Duration = Duration::from_secs(1);
+// fraction of the poll interval by which polls are randomly delayed
+const JITTER_FRACTION: f32 = 0.1;
+// time an update may take on top of painting its pixels, e.g. for spotify and the socket setup
+const UPDATE_TIMEOUT_BASE: Duration = Duration::from_secs(15);
+// time an update may take per pixel it paints, on top of the pixel delay, for the round trip
+const UPDATE_TIMEOUT_PER_PIXEL: Duration = Duration::from_millis(5);
+// time the lamp has to connect and authenticate, before an update isn't started
+const LAMP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The socket to the lamp couldn't be opened or authenticated, e.g. because the lamp is
+/// offline. Spotify might be linked just fine.
+#[derive(Debug)]
+pub struct LampUnreachable {
+    d_code: String,
+    reason: String,
+}
+
+impl LampUnreachable {
+    fn new(config: &ElliConfig, reason: String) -> Self {
+        Self {
+            d_code: config.d_code.clone(),
+            reason,
+        }
+    }
+}
+
+impl std::fmt::Display for LampUnreachable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Lamp {} is unreachable: {}", self.d_code, self.reason)
+    }
+}
+
+impl Error for LampUnreachable {}
 
 pub struct ElliUpdate {
     close_tx: oneshot::Sender<()>,
-    task_handle: JoinHandle<()>,
+    // asks the worker to update right away instead of at its next poll
+    refresh_tx: mpsc::Sender<()>,
+    task_handle: JoinHandle<Worker>,
+    // last status of the socket seen by the worker. None until the first paint.
+    status_rx: watch::Receiver<Option<ConnectionStatus>>,
+    // what the worker found playing on its last poll. None while nothing plays.
+    now_playing_rx: watch::Receiver<Option<NowPlaying>>,
+    // socket to the device, kept open across updates. None if it has to be re-established.
+    connection: SharedConnection,
+    // config of the running worker, which can be changed while it runs
+    config_tx: watch::Sender<ElliConfig>,
+    frames_tx: broadcast::Sender<MatrixFrame>,
+    // text frames on the sockets of the device, across reconnects
+    frame_log: FrameLog,
+    // asks the worker to paint on its next poll, even if the frame hasn't changed
+    redraw: Arc<AtomicBool>,
+}
+
+/// The colors painted on the matrix together with what is playing.
+#[derive(Debug, Clone, Serialize)]
+pub struct MatrixFrame {
+    pub colors: Vec<String>,
+    pub name: String,
+    pub artists: Vec<String>,
+    pub album: String,
+    // empty for items without album art
+    pub image_url: String,
+}
+
+impl MatrixFrame {
+    /// The frame as a server-sent event.
+    pub fn to_event(&self) -> String {
+        // serializing strings into json doesn't fail
+        format!("data: {}\n\n", serde_json::to_string(self).unwrap())
+    }
+}
+
+/// A track playing on a spotify device.
+#[derive(Clone)]
+pub struct NowPlaying {
+    // the active spotify device, e.g. a phone or speaker
+    pub device_name: Option<String>,
+    pub track: PlayingModel,
+}
+
+type SharedConnection = Arc<Mutex<Option<ElliConnection>>>;
+
+/// The socket of a running update, for painting pixels in between its frames, e.g. from the
+/// api. The worker paints over them with its next new frame.
+#[derive(Clone)]
+pub struct LiveSocket {
+    connection: SharedConnection,
+}
+
+impl LiveSocket {
+    /// Writes the pixels as they are, throttled like the frames of the worker. Returns false, if
+    /// the socket isn't authenticated right now.
+    pub async fn write_pixels(
+        &self,
+        pixels: Vec<PixelData>,
+        config: &ElliConfig,
+    ) -> Result<bool, Box<dyn Error>> {
+        let mut connection_guard = self.connection.lock().await;
+        match connection_guard
+            .as_mut()
+            .filter(|connection| connection.status() == ConnectionStatus::Authenticated)
+        {
+            Some(connection) => send_frame(connection, pixels, config).await.map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Renames the device. Returns false, if the socket isn't authenticated right now.
+    pub async fn set_name(&self, name: String) -> Result<bool, Box<dyn Error>> {
+        let mut connection_guard = self.connection.lock().await;
+        match connection_guard
+            .as_mut()
+            .filter(|connection| connection.status() == ConnectionStatus::Authenticated)
+        {
+            Some(connection) => connection.set_name(name).await.map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// The pixels the device reports. None, if the socket isn't authenticated right now.
+    pub async fn read_matrix(&self) -> Result<Option<Vec<PixelData>>, Box<dyn Error>> {
+        let mut connection_guard = self.connection.lock().await;
+        match connection_guard
+            .as_mut()
+            .filter(|connection| connection.status() == ConnectionStatus::Authenticated)
+        {
+            Some(connection) => connection.read_matrix().await.map(Some),
+            None => Ok(None),
+        }
+    }
 }
 
 impl ElliUpdate {
@@ -27,109 +188,1201 @@ impl ElliUpdate {
         app_state: web::Data<AppState>,
         spotify_client: web::Data<SpotifyClient>,
     ) -> Result<Self, Box<dyn Error>> {
+        let mut config = ElliConfig::from_ccc(&ccc)?;
+        app_state.settings(&ccc).apply(&mut config);
+        let frame_log = FrameLog::default();
+        // everything logged for the device carries its ccc, including the tasks of its socket
+        let span = info_span!("device", ccc = %ccc);
+        let connection = if config.dry_run {
+            None
+        } else {
+            // an offline lamp is reported right away, instead of a worker failing on every poll
+            let connecting = connect(&config, &frame_log).instrument(span.clone());
+            let connected = timeout(LAMP_CONNECT_TIMEOUT, connecting).await;
+            let mut connection = match connected {
+                Ok(Ok(connection)) => connection,
+                Ok(Err(e)) => return Err(LampUnreachable::new(&config, e.to_string()).into()),
+                Err(_) => {
+                    let reason = format!("No answer within {:?}", LAMP_CONNECT_TIMEOUT);
+                    return Err(LampUnreachable::new(&config, reason).into());
+                }
+            };
+            // the device knows its size better than a ccc without one. Cccs which have one
+            // don't wait for an answer devices might never give.
+            let (_, _, ccc_size) = ElliConfig::parse_ccc(&ccc)?;
+            if ccc_size.is_none() {
+                match connection.read_size().instrument(span.clone()).await {
+                    Ok(Some(size)) => {
+                        info!(parent: &span, "Device has a {}x{} matrix", size, size);
+                        config.set_size(size);
+                    }
+                    Ok(None) => {}
+                    Err(e) => warn!(
+                        parent: &span,
+                        "Failed to read the matrix size: {}. Using {}x{} from the ccc.",
+                        e,
+                        config.size,
+                        config.size
+                    ),
+                }
+            }
+            Some(connection)
+        };
+        let connection = Arc::new(Mutex::new(connection));
         let (close_tx, close_rx) = oneshot::channel();
-        let last_image_url = Arc::new(RwLock::new(String::new()));
-        let handle = Self::start_update(
+        // a single pending refresh is enough, as it covers all changes until it runs
+        let (refresh_tx, refresh_rx) = mpsc::channel(1);
+        let (status_tx, status_rx) = watch::channel(None);
+        let (now_playing_tx, now_playing_rx) = watch::channel(None);
+        let (config_tx, config_rx) = watch::channel(config);
+        let (frames_tx, _) = broadcast::channel(FRAME_BUFFER);
+        let redraw = Arc::new(AtomicBool::new(false));
+        let worker = Worker {
             ccc,
-            last_image_url.clone(),
+            config: config_rx,
+            last_image_url: Arc::new(RwLock::new(String::new())),
+            last_grid: Mutex::new(None),
+            animation: Mutex::new(None),
+            last_title: Mutex::new(String::new()),
+            last_pixels: Mutex::new(None),
+            idle_since: Mutex::new(None),
+            connection: connection.clone(),
             app_state,
             spotify_client,
-            close_rx,
-        )
-        .await?;
+            status_tx,
+            now_playing_tx,
+            frames_tx: frames_tx.clone(),
+            frame_log: frame_log.clone(),
+            redraw: redraw.clone(),
+            limiter: FrameLimiter::default(),
+        };
+        let handle = Self::start_update(worker, close_rx, refresh_rx, span);
         let update = Self {
             close_tx,
+            refresh_tx,
             task_handle: handle,
+            status_rx,
+            now_playing_rx,
+            connection,
+            config_tx,
+            frames_tx,
+            frame_log,
+            redraw,
         };
         Ok(update)
     }
 
+    pub fn status(&self) -> Option<ConnectionStatus> {
+        self.status_rx.borrow().clone()
+    }
+
+    pub fn now_playing(&self) -> Option<NowPlaying> {
+        self.now_playing_rx.borrow().clone()
+    }
+
+    /// The config the worker paints with, including the size reported by the device.
+    pub fn config(&self) -> ElliConfig {
+        self.config_tx.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<MatrixFrame> {
+        self.frames_tx.subscribe()
+    }
+
+    pub fn socket(&self) -> LiveSocket {
+        LiveSocket {
+            connection: self.connection.clone(),
+        }
+    }
+
+    /// Paints the matrix again with the changed settings. The worker repaints the frame on the
+    /// matrix right away and only renders the album art again, if the settings change that.
+    /// Returns immediately.
+    pub fn apply_settings(&self, settings: &DeviceSettings) {
+        self.config_tx.send_modify(|config| settings.apply(config));
+        if settings.change_rendering() {
+            // e.g. the test pattern is on the matrix, while the frame key still names the album
+            // art
+            self.redraw.store(true, Ordering::Relaxed);
+            self.refresh();
+        }
+    }
+
+    /// The last text frames sent to and received from the device, oldest first.
+    pub fn frames(&self) -> Vec<LoggedFrame> {
+        self.frame_log.frames()
+    }
+
+    /// Lets the worker update right away. Returns immediately, the update runs in the worker.
+    pub fn refresh(&self) {
+        // a full channel already has a refresh pending
+        let _ = self.refresh_tx.try_send(());
+    }
+
     pub async fn close(self) -> Result<(), Box<dyn Error>> {
+        self.stop(false).await
+    }
+
+    /// Like `close`, but switches the matrix off first if the config asks for it.
+    pub async fn disconnect(self) -> Result<(), Box<dyn Error>> {
+        let power_off = self.config_tx.borrow().power_off_on_disconnect;
+        self.stop(power_off).await
+    }
+
+    async fn stop(self, power_off: bool) -> Result<(), Box<dyn Error>> {
         let _ = self.close_tx.send(());
-        self.task_handle.await?;
+        let worker = self.task_handle.await?;
+        let (fade_out, limit) = {
+            let config = self.config_tx.borrow();
+            (config.fade_out, fade_out_timeout(&config))
+        };
+        if fade_out && timeout(limit, worker.fade_out()).await.is_err() {
+            // e.g. a stuck socket, which mustn't keep the device from being released
+            warn!("Fading out took longer than {:?}. Closing anyway.", limit);
+        }
+        if let Some(mut connection) = self.connection.lock().await.take() {
+            // the worker has stopped, so nothing paints after this
+            if power_off {
+                if let Err(e) = connection.set_power(false).await {
+                    warn!("Failed to switch off the matrix: {}", e);
+                }
+            }
+            connection.close().await?;
+        }
 
         Ok(())
     }
 
-    async fn start_update(
-        ccc: String,
-        last_image_url: Arc<RwLock<String>>,
-        app_state: web::Data<AppState>,
-        spotify_client: web::Data<SpotifyClient>,
+    fn start_update(
+        worker: Worker,
         mut rx_close: oneshot::Receiver<()>,
-    ) -> Result<JoinHandle<()>, Box<dyn Error>> {
-        let config = ElliConfig::from_ccc(&ccc)?;
-        let handle = tokio::spawn(async move {
-            let i = config.size * 3;
-            let mut update_interval = interval(Duration::from_secs(i as u64));
-            info!("Starting update worker for {} with interval {}s", ccc, i);
+        mut rx_refresh: mpsc::Receiver<()>,
+        span: Span,
+    ) -> JoinHandle<Worker> {
+        let task = async move {
+            info!(
+                "Starting update worker, polling at most every {:?}",
+                worker.config.borrow().poll_interval
+            );
+            // the first update happens almost right away. The jitter keeps workers started
+            // together, e.g. after a restart, from polling spotify all at once.
+            let first_poll = jitter(worker.config.borrow().poll_interval);
+            let mut next_update = Instant::now() + first_poll;
+            let mut failures = 0;
+            // when the first of the refresh requests waiting for the next update came in
+            let mut refresh_since = None;
+            // a receiver of its own, so that the worker's copies aren't marked as seen
+            let mut config_rx = worker.config.clone();
             loop {
+                let frame_delay = worker.frame_delay().await;
                 tokio::select! {
                     _ = &mut rx_close => {
-                        info!("received stop update signal for {}", ccc);
+                        info!("received stop update signal");
                         break;
                     }
-                    _ = update_interval.tick() => {
-                        info!("updating {}", ccc);
-                        do_update(ccc.clone(), last_image_url.clone(), app_state.clone(), spotify_client.clone()).await.unwrap();
+                    _ = sleep_until(next_update) => {
+                        info!("updating");
+                        refresh_since = None;
+                        let poll_interval = worker.config.borrow().poll_interval;
+                        let started = Instant::now();
+                        let result = worker.update().await;
+                        let metrics = worker.app_state.metrics();
+                        metrics.record_update(started.elapsed(), result.is_err());
+                        let wait = match result {
+                            Ok(remaining) => {
+                                failures = 0;
+                                next_poll(poll_interval, remaining)
+                            }
+                            Err(e) => {
+                                // keep the worker alive and try again on the next tick
+                                failures += 1;
+                                warn!("Update {} failed: {}", failures, e);
+                                if failures >= MAX_CONSECUTIVE_FAILURES {
+                                    worker.status_tx.send_replace(Some(ConnectionStatus::Error));
+                                }
+                                failure_wait(e.as_ref(), poll_interval)
+                            }
+                        };
+                        next_update = Instant::now() + wait + jitter(wait);
+                    }
+                    Ok(()) = config_rx.changed() => {
+                        // e.g. a new brightness, which doesn't need spotify
+                        if let Err(e) = worker.repaint().await {
+                            warn!("Repainting failed: {}", e);
+                        }
+                    }
+                    Some(()) = rx_refresh.recv() => {
+                        info!("refresh requested");
+                        // requests which came in meanwhile are covered by this one
+                        while rx_refresh.try_recv().is_ok() {}
+                        let now = Instant::now();
+                        let first = *refresh_since.get_or_insert(now);
+                        next_update = next_update.min(debounced_refresh(now, first));
+                    }
+                    // the animation is painted in between the polls
+                    _ = sleep(frame_delay.unwrap_or_default()), if frame_delay.is_some() => {
+                        // painting a frame takes a while on slow devices, which mustn't hold
+                        // up a close
+                        tokio::select! {
+                            _ = &mut rx_close => {
+                                info!("received stop update signal");
+                                break;
+                            }
+                            result = worker.animate() => {
+                                if let Err(e) = result {
+                                    warn!("Animating failed: {}", e);
+                                }
+                            }
+                        }
                     }
                 }
             }
-        });
-        Ok(handle)
+            // handed back, so that closing can still fade out what is on the matrix
+            worker
+        };
+        tokio::spawn(task.instrument(span))
+    }
+}
+
+// brightness of each frame of the fade to black, going down to 0
+fn fade_levels() -> impl Iterator<Item = u8> {
+    (0..FADE_OUT_STEPS)
+        .rev()
+        .map(|step| (255 * step / FADE_OUT_STEPS) as u8)
+}
+
+/// Time after which the fade on close is given up. Every step repaints the whole matrix at the
+/// pixel delay, so that the fade takes longer on larger matrices and slower devices.
+fn fade_out_timeout(config: &ElliConfig) -> Duration {
+    let step = FADE_OUT_STEP_DELAY
+        + (config.pixel_delay + UPDATE_TIMEOUT_PER_PIXEL) * config.size * config.size;
+    FADE_OUT_SLACK + step * FADE_OUT_STEPS
+}
+
+// when a refresh requested at `now` runs. Requests in quick succession push it back, so that
+// only the track of the last one is painted, but not further than REFRESH_DEBOUNCE_MAX after
+// the first one.
+fn debounced_refresh(now: Instant, first: Instant) -> Instant {
+    (now + REFRESH_DEBOUNCE).min(first + REFRESH_DEBOUNCE_MAX)
+}
+
+/// Opens a socket to the device and authenticates it.
+async fn connect(
+    config: &ElliConfig,
+    frame_log: &FrameLog,
+) -> Result<ElliConnection, Box<dyn Error>> {
+    let mut connection = ElliConnection::with_frame_log(
+        config.clone(),
+        ReconnectPolicy::default(),
+        frame_log.clone(),
+    )
+    .await
+    .inspect_err(|e| warn!("Failed to open the socket: {}", e))?;
+    connection.authenticate().await?;
+    // a refused authentication is answered, too, so only the status tells it
+    if connection.status() != ConnectionStatus::Authenticated {
+        let _ = connection.close().await;
+        return Err("The device refused the authentication".into());
     }
+    // the matrix might have been switched off on a disconnect
+    connection.set_power(true).await?;
+    Ok(connection)
 }
 
-async fn do_update(
+// everything the worker task needs to paint a device
+struct Worker {
     ccc: String,
+    config: watch::Receiver<ElliConfig>,
     last_image_url: Arc<RwLock<String>>,
+    // colors of the frame painted last, which a crossfade starts from
+    last_grid: Mutex<Option<Vec<[u8; 3]>>>,
+    // frames of the animated album art being looped. None for still images.
+    animation: Mutex<Option<Animation>>,
+    // title of the track seen last, which is scrolled again once another track plays
+    last_title: Mutex<String>,
+    // pixels of the last frame before dimming and whether playback was paused, so that a new
+    // brightness is painted without asking spotify or rendering the album art again
+    last_pixels: Mutex<Option<(Vec<PixelData>, bool)>>,
+    // since when nothing plays. None while something does.
+    idle_since: Mutex<Option<Instant>>,
+    connection: SharedConnection,
     app_state: web::Data<AppState>,
     spotify_client: web::Data<SpotifyClient>,
+    status_tx: watch::Sender<Option<ConnectionStatus>>,
+    now_playing_tx: watch::Sender<Option<NowPlaying>>,
+    frames_tx: broadcast::Sender<MatrixFrame>,
+    frame_log: FrameLog,
+    redraw: Arc<AtomicBool>,
+    // caps the frames painted on the matrix at the configured fps
+    limiter: FrameLimiter,
+}
+
+/// Frames of a scrolling title or animated album art, each with how long it is shown. They
+/// are adjusted to the config when painted, so that brightness changes apply right away.
+struct Animation {
+    frames: Vec<(Vec<PixelData>, Duration)>,
+    // the frame on the matrix right now
+    current: usize,
+    // frames before this one, e.g. the title, are only shown once
+    repeat_from: usize,
+}
+
+impl Animation {
+    // None once the animation has settled on its last frame
+    fn delay(&self) -> Option<Duration> {
+        let settled =
+            self.current + 1 == self.frames.len() && self.repeat_from + 1 >= self.frames.len();
+        (!settled).then(|| self.frames[self.current].1)
+    }
+
+    fn current(&self) -> Vec<PixelData> {
+        self.frames[self.current].0.clone()
+    }
+
+    // moves on to the next frame, starting over after the last one
+    fn advance(&mut self) -> Vec<PixelData> {
+        self.current += 1;
+        if self.current == self.frames.len() {
+            self.current = self.repeat_from;
+        }
+        self.frames[self.current].0.clone()
+    }
+}
+
+/// Spaces out frames to at most `max_fps` per second. Frames which come too early are either
+/// dropped, when a later one follows anyway, or wait for their turn.
+#[derive(Default)]
+pub struct FrameLimiter {
+    // when the last frame was let through
+    last: std::sync::Mutex<Option<Instant>>,
+}
+
+impl FrameLimiter {
+    /// Time between two frames at `max_fps`. 0 doesn't cap.
+    pub fn interval(max_fps: u32) -> Duration {
+        match max_fps {
+            0 => Duration::ZERO,
+            fps => Duration::from_secs(1) / fps,
+        }
+    }
+
+    /// Whether a frame may be shown right now. If so, it takes the slot.
+    pub fn admit(&self, max_fps: u32) -> bool {
+        let mut last = self.last.lock().unwrap();
+        let now = Instant::now();
+        match *last {
+            Some(at) if now < at + Self::interval(max_fps) => false,
+            _ => {
+                *last = Some(now);
+                true
+            }
+        }
+    }
+
+    /// Waits until a frame may be shown and takes the slot.
+    pub async fn wait(&self, max_fps: u32) {
+        let now = Instant::now();
+        let slot = {
+            let mut last = self.last.lock().unwrap();
+            let slot = last.map_or(now, |at| (at + Self::interval(max_fps)).max(now));
+            // taken right away, so that a frame waiting alongside gets the slot after
+            *last = Some(slot);
+            slot
+        };
+        sleep_until(slot).await;
+    }
+}
+
+impl Worker {
+    /// Runs `do_update`, but gives up once it takes longer than `update_timeout` allows, e.g.
+    /// because the socket connected but never authenticated.
+    async fn update(&self) -> Result<Option<Duration>, Box<dyn Error>> {
+        let limit = update_timeout(&self.config.borrow());
+        let elapsed = match timeout(limit, self.do_update()).await {
+            Ok(result) => return result,
+            Err(elapsed) => elapsed,
+        };
+        // the frame may not have made it, and the socket may be stuck
+        self.last_image_url.write().await.clear();
+        if let Some(connection) = self.connection.lock().await.take() {
+            let _ = connection.close().await;
+        }
+        Err(elapsed.into())
+    }
+
+    async fn do_update(&self) -> Result<Option<Duration>, Box<dyn Error>> {
+        let ccc = &self.ccc;
+        // a copy, so that config changes don't apply in the middle of painting a frame
+        let config = &self.config.borrow().clone();
+        if self.redraw.swap(false, Ordering::Relaxed) {
+            self.last_image_url.write().await.clear();
+        }
+
+        // fetch currently playing status from spotify
+        let playback = self
+            .spotify_client
+            .get_playback_state(ccc.as_str(), self.app_state.clone())
+            .await?;
+        let playing_model = if let Some(playback) = playback {
+            // stops the attract animation
+            *self.idle_since.lock().await = None;
+            let device_name = PlaybackModel::from(&playback).device_name;
+            let playing_model = PlayingModel::from(playback.current);
+            self.now_playing_tx.send_replace(Some(NowPlaying {
+                device_name,
+                track: playing_model.clone(),
+            }));
+            playing_model
+        } else {
+            info!("No track playing");
+            self.now_playing_tx.send_replace(None);
+            self.idle_since
+                .lock()
+                .await
+                .get_or_insert_with(Instant::now);
+            if self.attract_elapsed(config).await.is_some() {
+                // the animation is painted in between the polls
+                return Ok(None);
+            }
+            if let Some(path) = &config.idle_image {
+                self.show_idle(path, config).await?;
+            }
+            return Ok(None);
+        };
+        let remaining = playing_model.remaining();
+        let title = format!(
+            "{} - {}",
+            playing_model.name(),
+            playing_model.artists().join(", ")
+        );
+
+        // identifies what is painted on the matrix, so that we only repaint on changes
+        let paused = !playing_model.is_playing;
+        let art_key = if playing_model.has_image() {
+            playing_model.image_url.as_str()
+        } else {
+            NO_ART_FRAME_KEY
+        };
+        // the track is part of the key, so that another track of the same album repaints
+        let art_key = format!("{}#track{}", art_key, playing_model.uri());
+        let frame_key = match (paused, &config.paused_behavior) {
+            (true, PausedBehavior::Clear) => String::from(PAUSED_FRAME_KEY),
+            (true, PausedBehavior::Dim(_)) => format!("{}{}", art_key, PAUSED_FRAME_KEY),
+            _ => art_key,
+        };
+        let frame_key = if config.progress_bar {
+            let columns = render::progress_columns(config, playing_model.progress());
+            format!("{}#progress{}", frame_key, columns)
+        } else {
+            frame_key
+        };
+        let frame_key = if config.show_title {
+            format!("{}#title{}", frame_key, title)
+        } else {
+            frame_key
+        };
+        {
+            let read_guard = self.last_image_url.read().await;
+            if frame_key == read_guard.as_str() {
+                self.app_state.metrics().record_skipped_update();
+                return Ok(remaining); // No change needed
+            }
+        } // read_guard is dropped here before we acquire the write lock
+
+        let mut write_guard = self.last_image_url.write().await;
+        *write_guard = frame_key;
+        info!("Set last image url to: {}", write_guard.as_str());
+        // whatever was looped belongs to the previous frame
+        *self.animation.lock().await = None;
+
+        let new_track = {
+            let mut last_title = self.last_title.lock().await;
+            let new_track = *last_title != title;
+            last_title.clone_from(&title);
+            new_track
+        };
+
+        // the title is painted first instead of the album art, if it scrolls
+        let (pixels, grid, title_pixels) =
+            if paused && config.paused_behavior == PausedBehavior::Clear {
+                let grid = vec![[0, 0, 0]; (config.size * config.size) as usize];
+                (render::blank_pixels(config), grid, None)
+            } else {
+                // if something is playing, fetch the album art
+                let url = &playing_model.image_url;
+                let mut frames = if !playing_model.has_image() {
+                    vec![(render::placeholder(config)?, Duration::ZERO)]
+                } else {
+                    self.fetch_art(url, config, config.animate && !paused)
+                        .await?
+                };
+                let progress = playing_model.progress();
+                let (image, delay) = frames.remove(0);
+                let downsized_image = render::frame(&image, config, config.filter_type());
+                let grid = render::rgb_grid(&downsized_image, config, progress);
+                let pixels = art_pixels(&downsized_image, config, progress);
+                let first = (pixels.clone(), delay);
+                let rest = frames.into_iter().map(|(image, delay)| {
+                    let downsized_image = render::frame(&image, config, config.filter_type());
+                    let pixels = art_pixels(&downsized_image, config, progress);
+                    (pixels, delay)
+                });
+                let art: Vec<_> = std::iter::once(first).chain(rest).collect();
+
+                let mut frames: Vec<_> = if config.show_title && !paused && new_track {
+                    render::scroll_frames(&title, config, progress)
+                        .iter()
+                        .map(|grid| (render::grid_pixels(grid, config), TITLE_SCROLL_DELAY))
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+                let title_pixels = frames.first().map(|(pixels, _)| pixels.clone());
+                let repeat_from = frames.len();
+                frames.extend(art);
+                if frames.len() > 1 {
+                    *self.animation.lock().await = Some(Animation {
+                        frames,
+                        current: 0,
+                        repeat_from,
+                    });
+                }
+                (pixels, grid, title_pixels)
+            };
+        *self.last_pixels.lock().await = Some((pixels.clone(), paused));
+        // nobody might be watching the stream, which is fine
+        let _ = self.frames_tx.send(MatrixFrame {
+            colors: render::to_hex(&grid),
+            name: playing_model.name().to_string(),
+            artists: playing_model.artists().to_vec(),
+            album: playing_model.album().to_string(),
+            image_url: playing_model.image_url.clone(),
+        });
+        let previous_grid = self.last_grid.lock().await.replace(grid.clone());
+        let fade_frames = match previous_grid {
+            Some(previous) if previous.len() == grid.len() => {
+                render::crossfade(&previous, &grid, config.crossfade_steps)
+            }
+            _ => Vec::new(),
+        };
+
+        let frames = if let Some(title_pixels) = title_pixels {
+            // the album art follows once the title has scrolled by
+            vec![adjust(title_pixels, config, paused)]
+        } else {
+            fade_frames
+                .iter()
+                .map(|fade_frame| adjust(render::grid_pixels(fade_frame, config), config, paused))
+                .chain(std::iter::once(adjust(pixels, config, paused)))
+                .collect()
+        };
+        if !self.send_frames(frames, config).await? {
+            // paint again on the next tick
+            write_guard.clear();
+        }
+
+        Ok(remaining)
+    }
+
+    // the frames of the album art, or the placeholder for art which can't be decoded
+    async fn fetch_art(
+        &self,
+        url: &str,
+        config: &ElliConfig,
+        animate: bool,
+    ) -> Result<Vec<(DynamicImage, Duration)>, Box<dyn Error>> {
+        let fetched = if animate {
+            self.spotify_client.get_frames(url).await
+        } else {
+            let image = self.spotify_client.get_image(url).await;
+            image.map(|image| vec![(image, Duration::ZERO)])
+        };
+        match fetched {
+            Err(e) if ImageError::is_undecodable(e.as_ref()) => {
+                warn!("{}. Showing the placeholder for {} instead.", e, url);
+                Ok(vec![(render::placeholder(config)?, Duration::ZERO)])
+            }
+            fetched => fetched,
+        }
+    }
+
+    /// Paints the idle image, unless it is on the matrix already.
+    async fn show_idle(&self, path: &Path, config: &ElliConfig) -> Result<(), Box<dyn Error>> {
+        let mut last_image_url = self.last_image_url.write().await;
+        if last_image_url.as_str() == IDLE_FRAME_KEY {
+            return Ok(());
+        }
+        *last_image_url = String::from(IDLE_FRAME_KEY);
+        *self.animation.lock().await = None;
+        self.last_title.lock().await.clear();
+
+        let image = image::open(path)?;
+        let downsized_image = render::frame(&image, config, config.filter_type());
+        // the progress bar row stays dark
+        let pixels = art_pixels(&downsized_image, config, None);
+        *self.last_grid.lock().await = Some(render::rgb_grid(&downsized_image, config, None));
+        *self.last_pixels.lock().await = Some((pixels.clone(), false));
+
+        if !self
+            .send_frames(vec![adjust(pixels, config, false)], config)
+            .await?
+        {
+            last_image_url.clear();
+        }
+        Ok(())
+    }
+
+    // sends the frames one after the other, re-establishing the socket if it died. Returns
+    // false, if nothing was sent because the socket is reconnecting.
+    async fn send_frames(
+        &self,
+        frames: Vec<Vec<PixelData>>,
+        config: &ElliConfig,
+    ) -> Result<bool, Box<dyn Error>> {
+        if config.dry_run {
+            let pixels = frames.last().map_or(0, |frame| frame.len());
+            info!("Dry run. Not painting {} pixels.", pixels);
+            return Ok(true);
+        }
+
+        let mut connection_guard = self.connection.lock().await;
+        let connection = match connection_guard.take() {
+            Some(connection) if connection.status() != ConnectionStatus::Error => connection,
+            dead => {
+                // the socket gave up reconnecting or was never established
+                if let Some(connection) = dead {
+                    let _ = connection.close().await;
+                }
+                info!("Re-establishing socket");
+                connect(config, &self.frame_log).await?
+            }
+        };
+        let connection = connection_guard.insert(connection);
+
+        self.status_tx.send_replace(Some(connection.status()));
+        if connection.status() == ConnectionStatus::Reconnecting {
+            info!("Connection is reconnecting. Skipping update.");
+            return Ok(false);
+        }
+        let count = frames.len();
+        for (i, frame) in frames.into_iter().enumerate() {
+            // crossfades with more steps than fit into the frame rate skip some, but the final
+            // frame is always painted
+            if i + 1 < count {
+                if !self.limiter.admit(config.max_fps) {
+                    continue;
+                }
+            } else {
+                self.limiter.wait(config.max_fps).await;
+            }
+            let pixels = frame.len();
+            send_frame(connection, frame, config).await?;
+            self.app_state.metrics().record_pixels(pixels);
+        }
+        self.status_tx.send_replace(Some(connection.status()));
+        Ok(true)
+    }
+
+    // how long the animation frame on the matrix stays, if an animation is running. While
+    // nothing plays in RenderMode::Attract, this is the time until the attract animation starts.
+    async fn frame_delay(&self) -> Option<Duration> {
+        let (mode, attract_after, max_fps) = {
+            let config = self.config.borrow();
+            (config.mode, config.attract_after, config.max_fps)
+        };
+        if mode == RenderMode::Attract {
+            if let Some(since) = *self.idle_since.lock().await {
+                return Some(
+                    attract_after
+                        .saturating_sub(since.elapsed())
+                        .max(ATTRACT_FRAME_DELAY),
+                );
+            }
+        }
+        // gifs with shorter delays play slower, rather than painting faster than the cap
+        self.animation
+            .lock()
+            .await
+            .as_ref()
+            .and_then(Animation::delay)
+            .map(|delay| delay.max(FrameLimiter::interval(max_fps)))
+    }
+
+    // how long the attract animation has been running. None while it doesn't.
+    async fn attract_elapsed(&self, config: &ElliConfig) -> Option<Duration> {
+        if config.mode != RenderMode::Attract {
+            return None;
+        }
+        let idle = self.idle_since.lock().await.as_ref()?.elapsed();
+        idle.checked_sub(config.attract_after)
+    }
+
+    /// Paints the next frame of the running animation. Album art is animated while playing,
+    /// the attract animation while nothing plays.
+    async fn animate(&self) -> Result<(), Box<dyn Error>> {
+        let config = &self.config.borrow().clone();
+        // too soon after the last frame, e.g. of an update. Tried again after the next delay.
+        if !self.limiter.admit(config.max_fps) {
+            return Ok(());
+        }
+        if let Some(elapsed) = self.attract_elapsed(config).await {
+            // the next track is painted over the animation, even if it played before
+            self.last_image_url.write().await.clear();
+            *self.animation.lock().await = None;
+            *self.last_pixels.lock().await = None;
+            return self
+                .paint(render::attract_pixels(config, elapsed), false)
+                .await;
+        }
+        let Some(pixels) = self.animation.lock().await.as_mut().map(Animation::advance) else {
+            return Ok(());
+        };
+        self.paint(pixels, false).await
+    }
+
+    /// Paints what is on the matrix again with the current config, e.g. after the brightness
+    /// changed.
+    async fn repaint(&self) -> Result<(), Box<dyn Error>> {
+        let Some((pixels, paused)) = self.current_pixels().await else {
+            return Ok(());
+        };
+        let max_fps = self.config.borrow().max_fps;
+        self.limiter.wait(max_fps).await;
+        self.paint(pixels, paused).await
+    }
+
+    // pixels of the frame on the matrix before dimming and whether playback was paused. None
+    // before the first paint.
+    async fn current_pixels(&self) -> Option<(Vec<PixelData>, bool)> {
+        let current = self.animation.lock().await.as_ref().map(Animation::current);
+        match current {
+            Some(pixels) => Some((pixels, false)),
+            None => self.last_pixels.lock().await.clone(),
+        }
+    }
+
+    /// Dims what is on the matrix down to black in a few steps, before the socket is closed.
+    async fn fade_out(&self) {
+        let config = &self.config.borrow().clone();
+        let Some((pixels, paused)) = self.current_pixels().await else {
+            return;
+        };
+        let mut connection_guard = self.connection.lock().await;
+        let Some(connection) = connection_guard
+            .as_mut()
+            .filter(|connection| connection.status() == ConnectionStatus::Authenticated)
+        else {
+            return;
+        };
+        let pixels = adjust(pixels, config, paused);
+        let mut steps = interval(FADE_OUT_STEP_DELAY);
+        for level in fade_levels() {
+            steps.tick().await;
+            let frame = pixels.iter().cloned().map(|p| p.dimmed(level)).collect();
+            if let Err(e) = send_frame(connection, frame, config).await {
+                warn!("Fading out failed: {}", e);
+                return;
+            }
+        }
+    }
+
+    // paints on the open socket in between polls. Frames which can't be painted are skipped,
+    // the next poll re-establishes the socket.
+    async fn paint(&self, pixels: Vec<PixelData>, paused: bool) -> Result<(), Box<dyn Error>> {
+        let config = &self.config.borrow().clone();
+        if config.dry_run {
+            return Ok(());
+        }
+        // the socket is still busy with a frame painted from outside of the worker, e.g. a
+        // broadcast. The next frame follows soon, and should be the one shown instead of
+        // queueing up behind it.
+        let Ok(mut connection_guard) = self.connection.try_lock() else {
+            info!("Socket is busy. Dropping a frame.");
+            return Ok(());
+        };
+        let Some(connection) = connection_guard
+            .as_mut()
+            .filter(|connection| connection.status() == ConnectionStatus::Authenticated)
+        else {
+            return Ok(());
+        };
+        let count = pixels.len();
+        send_frame(connection, adjust(pixels, config, paused), config).await?;
+        self.app_state.metrics().record_pixels(count);
+        Ok(())
+    }
+}
+
+// the album art with the progress bar below it
+fn art_pixels(image: &DynamicImage, config: &ElliConfig, progress: Option<f32>) -> Vec<PixelData> {
+    let mut pixels = render::to_pixels(image, config);
+    if config.progress_bar {
+        pixels.extend(render::progress_bar_pixels(config, progress));
+    }
+    pixels
+}
+
+// dims the pixels while paused, applies the global brightness and orients them like the matrix
+fn adjust(pixels: Vec<PixelData>, config: &ElliConfig, paused: bool) -> Vec<PixelData> {
+    let max_val = match (paused, &config.paused_behavior) {
+        (true, PausedBehavior::Dim(max_val)) => *max_val,
+        _ => 255,
+    };
+    pixels
+        .into_iter()
+        .map(|p| render::orient(p.dimmed(max_val).dimmed(config.brightness), config))
+        .collect()
+}
+
+// whether frames are written with a single command. Either the frame goes out in a few socket
+// messages, or the device keeps up without a delay between the pixels anyway.
+fn batched(config: &ElliConfig) -> bool {
+    config.pixel_batch_size > 1 || config.pixel_delay.is_zero()
+}
+
+/// Paints the pixels as the config says: in batches, concurrently, or one after the other with
+/// the pixel delay in between.
+pub async fn send_frame(
+    connection: &mut ElliConnection,
+    pixels: Vec<PixelData>,
+    config: &ElliConfig,
 ) -> Result<(), Box<dyn Error>> {
-    let config = ElliConfig::from_ccc(&ccc)?;
-    let elli_size = config.size;
-    let mut connection = ElliConnection::new(config).await?;
-
-    // fetch currently playing status from spotify
-    let playing_model = if let Some(current_track) = spotify_client
-        .get_current_track(ccc.as_str(), app_state)
-        .await
-        .map_err(ErrorInternalServerError)?
-    {
-        PlayingModel::from(current_track)
+    if batched(config) {
+        connection.write_pixels(pixels).await?;
+    } else if config.pixel_concurrency > 1 {
+        connection
+            .write_pixels_concurrently(pixels, config.pixel_concurrency, config.pixel_delay)
+            .await?;
     } else {
-        info!("No track playing for device: {}", ccc);
-        return Ok(());
-    };
+        let mut throttle = interval(config.pixel_delay);
+        for data in pixels {
+            connection.write_pixel(data).await?;
+            throttle.tick().await;
+        }
+    }
+    Ok(())
+}
+
+/// Time until the next poll. Usually this is the regular interval, but if the current track
+/// ends before that, we poll shortly after its end to pick up the next one. The configured
+/// interval is the floor, so spotify is never polled more often than that.
+fn next_poll(poll_interval: Duration, remaining: Option<Duration>) -> Duration {
+    let until_track_end = remaining.map(|remaining| remaining + TRACK_END_MARGIN);
+    until_track_end
+        .map_or(REGULAR_POLL_INTERVAL, |wait| {
+            wait.min(REGULAR_POLL_INTERVAL)
+        })
+        .max(poll_interval)
+}
+
+/// Wait after a failed update. Spotify tells how long to back off, if it rate limits us.
+fn failure_wait(e: &(dyn Error + 'static), poll_interval: Duration) -> Duration {
+    match e.downcast_ref::<SpotifyError>() {
+        Some(SpotifyError::RateLimited(retry_after)) => poll_interval.max(*retry_after),
+        _ => poll_interval,
+    }
+}
+
+/// Random delay of up to `JITTER_FRACTION` of the given wait, to spread out the polls.
+fn jitter(wait: Duration) -> Duration {
+    wait.mul_f32(rand::thread_rng().gen_range(0.0..=JITTER_FRACTION))
+}
+
+/// Time after which an update is given up. It leaves time to paint every frame of a crossfade
+/// pixel by pixel at the pixel delay, so that larger matrices and slower devices get longer.
+fn update_timeout(config: &ElliConfig) -> Duration {
+    // the title is a single frame in the update, the rest of it scrolls in between the polls
+    let frames = u32::from(config.crossfade_steps.max(1));
+    let pixels = config.size * config.size * frames;
+    UPDATE_TIMEOUT_BASE + (config.pixel_delay + UPDATE_TIMEOUT_PER_PIXEL) * pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elli::mock_server::{MockBehavior, MockServer};
+    use crate::spotify::SpotifyAccess;
+    use crate::state::test_state;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_frame_event() {
+        let frame = MatrixFrame {
+            colors: vec![String::from("#ff0000")],
+            name: String::from("Song"),
+            artists: vec![String::from("Band")],
+            album: String::from("Record"),
+            image_url: String::from("https://i.scdn.co/image/1"),
+        };
+        assert_eq!(
+            frame.to_event(),
+            "data: {\"colors\":[\"#ff0000\"],\"name\":\"Song\",\"artists\":[\"Band\"],\"album\":\"Record\",\"image_url\":\"https://i.scdn.co/image/1\"}\n\n"
+        );
+    }
+
+    #[test]
+    fn test_animation_loops() {
+        let frame = |col| vec![PixelData::from_rgb(255, 0, 0, 0, col)];
+        let mut animation = Animation {
+            frames: vec![
+                (frame(0), Duration::from_millis(50)),
+                (frame(1), Duration::from_millis(80)),
+            ],
+            current: 0,
+            repeat_from: 0,
+        };
+        assert_eq!(animation.delay(), Some(Duration::from_millis(50)));
+        assert_eq!(animation.advance()[0].col, 1);
+        assert_eq!(animation.delay(), Some(Duration::from_millis(80)));
+        assert_eq!(animation.advance()[0].col, 0);
+    }
+
+    #[test]
+    fn test_animation_settles_after_title() {
+        let frame = |col| vec![PixelData::from_rgb(255, 0, 0, 0, col)];
+        let mut animation = Animation {
+            frames: vec![
+                (frame(0), TITLE_SCROLL_DELAY),
+                (frame(1), TITLE_SCROLL_DELAY),
+                (frame(2), Duration::ZERO),
+            ],
+            current: 0,
+            repeat_from: 2,
+        };
+        assert_eq!(animation.delay(), Some(TITLE_SCROLL_DELAY));
+        animation.advance();
+        assert_eq!(animation.advance()[0].col, 2);
+        // the still album art stays
+        assert_eq!(animation.delay(), None);
+    }
 
-    let current_url = playing_model.image_url.as_str();
-    {
-        let read_guard = last_image_url.read().await;
-        if current_url == read_guard.as_str() {
-            return Ok(()); // No change needed
+    #[test]
+    fn test_adjust() {
+        let mut config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").unwrap();
+        config.brightness = 128;
+        config.paused_behavior = PausedBehavior::Dim(64);
+        let pixels = || vec![PixelData::from_rgb(255, 0, 0, 0, 0)];
+        assert_eq!(adjust(pixels(), &config, false)[0].val, 128);
+        // pause dimming and brightness add up
+        assert_eq!(adjust(pixels(), &config, true)[0].val, 32);
+    }
+
+    #[test]
+    fn test_jitter() {
+        let wait = Duration::from_secs(3);
+        for _ in 0..100 {
+            assert!(jitter(wait) <= wait.mul_f32(JITTER_FRACTION));
         }
-    } // read_guard is dropped here before we acquire the write lock
+        assert_eq!(jitter(Duration::ZERO), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_update_timeout() {
+        let mut config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z16").unwrap();
+        // a frame of the default config, one pixel after the other
+        let painting = config.pixel_delay * 16 * 16;
+        assert!(update_timeout(&config) > UPDATE_TIMEOUT_BASE + painting);
+        config.crossfade_steps = 4;
+        assert!(update_timeout(&config) > UPDATE_TIMEOUT_BASE + painting * 4);
+        let large = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z32").unwrap();
+        assert!(update_timeout(&large) > update_timeout(&config));
+    }
 
-    let mut write_guard = last_image_url.write().await;
-    *write_guard = current_url.to_string(); // or playing_model.image_url.clone()
-    info!("Set last image url to: {}", write_guard.as_str());
+    #[test]
+    fn test_lamp_unreachable() {
+        let config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").unwrap();
+        let e: Box<dyn Error> =
+            LampUnreachable::new(&config, String::from("Connection refused")).into();
+        assert!(e.is::<LampUnreachable>());
+        assert_eq!(
+            e.to_string(),
+            "Lamp 3UPU4R9Z is unreachable: Connection refused"
+        );
+    }
 
-    // only take the future and fetch the spotify data while the socket connection is established.
-    let auth_future = connection.authenticate();
+    #[test]
+    fn test_next_poll() {
+        let poll_interval = Duration::from_secs(1);
+        assert_eq!(next_poll(poll_interval, None), REGULAR_POLL_INTERVAL);
+        assert_eq!(
+            next_poll(poll_interval, Some(Duration::from_secs(60))),
+            REGULAR_POLL_INTERVAL
+        );
+        assert_eq!(
+            next_poll(poll_interval, Some(Duration::from_millis(500))),
+            Duration::from_millis(500) + TRACK_END_MARGIN
+        );
+        // the configured interval is a floor, for track ends and regular polls alike
+        let poll_interval = Duration::from_secs(10);
+        assert_eq!(next_poll(poll_interval, None), poll_interval);
+        assert_eq!(
+            next_poll(poll_interval, Some(Duration::from_millis(500))),
+            poll_interval
+        );
+    }
 
-    // if something is playing, fetch the album art
-    let image = spotify_client.get_image(&playing_model.image_url).await?;
-    let downsized_image = image.resize(elli_size, elli_size, FilterType::Nearest);
+    #[tokio::test(start_paused = true)]
+    async fn test_frame_limiter() {
+        let limiter = FrameLimiter::default();
+        assert!(limiter.admit(10));
+        assert!(!limiter.admit(10));
+        // without a cap, every frame goes through
+        assert!(limiter.admit(0));
 
-    // await the authentication process of the lamp before we send pixels
-    auth_future.await?;
-    let mut throttle = interval(Duration::from_millis(5 * elli_size as u64));
-    for (x, y, rgba) in downsized_image.pixels() {
-        let data = PixelData::from_rgb(rgba[0], rgba[1], rgba[2], y as usize, x as usize);
-        connection.write_pixel(data).await?;
-        throttle.tick().await;
+        let start = Instant::now();
+        limiter.wait(10).await;
+        limiter.wait(10).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+        assert!(!limiter.admit(10));
+        sleep(Duration::from_millis(100)).await;
+        assert!(limiter.admit(10));
     }
-    connection.close().await?;
 
-    Ok(())
+    #[test]
+    fn test_debounced_refresh() {
+        let first = Instant::now();
+        assert_eq!(debounced_refresh(first, first), first + REFRESH_DEBOUNCE);
+        let later = first + Duration::from_millis(200);
+        assert_eq!(debounced_refresh(later, first), later + REFRESH_DEBOUNCE);
+        // a burst doesn't hold the update off for good
+        let much_later = first + REFRESH_DEBOUNCE_MAX;
+        assert_eq!(
+            debounced_refresh(much_later, first),
+            first + REFRESH_DEBOUNCE_MAX
+        );
+    }
+
+    #[test]
+    fn test_fade_levels() {
+        let levels: Vec<_> = fade_levels().collect();
+        assert_eq!(levels.len(), FADE_OUT_STEPS as usize);
+        assert!(levels.windows(2).all(|pair| pair[0] > pair[1]));
+        assert!(levels[0] < 255);
+        assert_eq!(levels.last(), Some(&0));
+    }
+
+    fn app_state() -> web::Data<AppState> {
+        web::Data::new(test_state())
+    }
+
+    // a worker as `ElliUpdate::new` starts it, without a task around it
+    fn worker(
+        config: ElliConfig,
+        connection: Option<ElliConnection>,
+        app_state: web::Data<AppState>,
+        spotify_client: SpotifyClient,
+    ) -> Worker {
+        Worker {
+            ccc: String::from("0FBL3E2B3UPU4R9Z"),
+            config: watch::channel(config).1,
+            last_image_url: Arc::new(RwLock::new(String::new())),
+            last_grid: Mutex::new(None),
+            animation: Mutex::new(None),
+            last_title: Mutex::new(String::new()),
+            last_pixels: Mutex::new(None),
+            idle_since: Mutex::new(None),
+            connection: Arc::new(Mutex::new(connection)),
+            app_state,
+            spotify_client: web::Data::new(spotify_client),
+            status_tx: watch::channel(None).0,
+            now_playing_tx: watch::channel(None).0,
+            frames_tx: broadcast::channel(FRAME_BUFFER).0,
+            frame_log: FrameLog::default(),
+            redraw: Arc::new(AtomicBool::new(false)),
+            limiter: FrameLimiter::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_backs_off() {
+        // answers every request like a rate limiting spotify
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request).await;
+                let response = "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 12\r\n\
+                                Content-Length: 0\r\n\r\n";
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        let app_state = app_state();
+        let access = SpotifyAccess::restore(String::from("access"), None, 3600);
+        app_state.insert_access("0FBL3E2B3UPU4R9Z", access).await;
+        let config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z").unwrap();
+        let poll_interval = config.poll_interval;
+        let spotify_client = SpotifyClient::new().with_api_url(api_url);
+        let worker = worker(config, None, app_state, spotify_client);
+
+        let e = worker.update().await.unwrap_err();
+        assert_eq!(
+            failure_wait(e.as_ref(), poll_interval),
+            Duration::from_secs(12)
+        );
+        let other: Box<dyn Error> = "socket closed".into();
+        assert_eq!(failure_wait(other.as_ref(), poll_interval), poll_interval);
+    }
+
+    #[tokio::test]
+    async fn test_connect_fails_when_the_device_refuses() {
+        let server = MockServer::start(MockBehavior::RejectAuth).await;
+        let config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z03")
+            .unwrap()
+            .with_host(server.host.clone());
+        assert!(connect(&config, &FrameLog::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fade_out_ends_black() {
+        let server = MockServer::start(MockBehavior::Accept).await;
+        let config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z03")
+            .unwrap()
+            .with_host(server.host.clone());
+        let connection = connect(&config, &FrameLog::default()).await.unwrap();
+        let limit = fade_out_timeout(&config);
+        let worker = worker(config, Some(connection), app_state(), SpotifyClient::new());
+        let pixels = (0..9)
+            .map(|i| PixelData::from_rgb(255, 255, 255, i / 3, i % 3))
+            .collect();
+        *worker.last_pixels.lock().await = Some((pixels, false));
+
+        // the default pixel delay paints every step one pixel after the other
+        timeout(limit, worker.fade_out()).await.unwrap();
+        let received = server.received();
+        let writes: Vec<_> = received
+            .iter()
+            .filter(|message| message["param"] == "pixel")
+            .collect();
+        assert_eq!(writes.len(), 9 * FADE_OUT_STEPS as usize);
+        assert!(writes[writes.len() - 9..]
+            .iter()
+            .all(|message| message["val"] == 0));
+    }
+
+    #[tokio::test]
+    async fn test_paint_drops_frames_while_the_socket_is_busy() {
+        let server = MockServer::start(MockBehavior::Accept).await;
+        let config = ElliConfig::from_ccc("0FBL3E2B3UPU4R9Z03")
+            .unwrap()
+            .with_host(server.host.clone());
+        let connection = connect(&config, &FrameLog::default()).await.unwrap();
+        let worker = worker(config, Some(connection), app_state(), SpotifyClient::new());
+        let pixels: Vec<_> = (0..9)
+            .map(|i| PixelData::from_rgb(255, 0, 0, i / 3, i % 3))
+            .collect();
+        let writes = |server: &MockServer| {
+            let received = server.received();
+            received.iter().filter(|m| m["param"] == "pixel").count()
+        };
+
+        // e.g. a broadcast painting on the socket of the update
+        let busy = worker.connection.lock().await;
+        worker.paint(pixels.clone(), false).await.unwrap();
+        drop(busy);
+        assert_eq!(writes(&server), 0);
+        worker.paint(pixels, false).await.unwrap();
+        assert_eq!(writes(&server), 9);
+    }
 }